@@ -1,7 +1,7 @@
 use crate::client::{BaseClient, BlockingClient, NonBlockingClient, RequestHandle};
-use crate::{GI_B, MI_B, KI_B};
+use crate::{GI_B, KI_B, MI_B};
 use bytes::BytesMut;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::time::{Duration, Instant};
 use std::{io, net, str};
 use tracing::Level;
@@ -10,6 +10,9 @@ use tracing::Level;
 pub struct BaseCLI {
     pub addr: net::IpAddr,
 
+    #[arg(long)]
+    pub device: Option<String>,
+
     #[arg(long, default_value_t = false)]
     pub skip_validation: bool,
 
@@ -161,7 +164,7 @@ where
     F: FnMut(BaseClient, usize) -> io::Result<C>,
 {
     let size = cli.size;
-    let base = BaseClient::new(cli.addr)?;
+    let base = BaseClient::new(cli.addr, cli.device.as_deref())?;
     let remote = base.remotes[0].slice(0..size);
     let mut client = f(base, size)?;
     println!("size: {size}, config: {:?}", client.config());
@@ -219,7 +222,7 @@ where
     F: FnMut(BaseClient, usize) -> io::Result<C>,
 {
     let size = cli.size;
-    let base = BaseClient::new(cli.addr)?;
+    let base = BaseClient::new(cli.addr, cli.device.as_deref())?;
     let remote = base.remotes[0].slice(0..size);
     let client = f(base, size)?;
     println!("size: {size}, config: {:?}", client.config());
@@ -251,20 +254,23 @@ where
         }
 
         let mut latencies = Vec::new();
+        let mut phases = HashMap::new();
         let start = Instant::now();
         while start.elapsed() < cli.measure() {
             if let Some(b) = bytes.pop_front() {
                 let start = Instant::now();
                 let handle = client.prefetch(b, &remote)?;
-                handle.wait_available();
+                let phase = handle.wait_available();
                 let end = Instant::now();
                 bytes.push_back(handle.acquire()?);
                 latencies.push(end - start);
+                *phases.entry(phase).or_insert(0usize) += 1;
             }
         }
 
         let stats = LatencyStats::from_latencies(&mut latencies);
-        println!("{stats:?}")
+        println!("{stats:?}");
+        println!("wait phases: {phases:?}")
     }
 
     if !cli.skip_throughput {
@@ -8,6 +8,9 @@ use std::io;
 pub struct CLI {
     #[arg(long, default_value_t = 4 * GI_B)]
     size: usize,
+
+    #[arg(long)]
+    device: Option<String>,
 }
 
 fn main() -> io::Result<()> {
@@ -21,6 +24,9 @@ fn main() -> io::Result<()> {
 
 impl From<CLI> for Config {
     fn from(value: CLI) -> Self {
-        Self { size: value.size }
+        Self {
+            size: value.size,
+            device: value.device,
+        }
     }
 }
@@ -35,7 +35,7 @@ impl client::BlockingClient for Client {
                         break;
                     }
                     Err(e) if e.kind() == io::ErrorKind::OutOfMemory => continue,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 }
             }
         }
@@ -76,7 +76,7 @@ impl client::BlockingClient for Client {
                             Err(e) if e.kind() == io::ErrorKind::OutOfMemory => {
                                 hint::spin_loop();
                             }
-                            Err(e) => return Err(e),
+                            Err(e) => return Err(e.into()),
                         }
                     }
                     if !posted {
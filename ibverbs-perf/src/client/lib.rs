@@ -1,5 +1,5 @@
 use crate::client::BaseClient;
-use crate::{BINCODE_CONFIG, PORT};
+use crate::{BINCODE_CONFIG, PORT, open_device};
 use bincode::serde::{decode_from_std_read, encode_into_std_write};
 use ibverbs::RemoteMemorySlice;
 use ibverbs::ibv_qp_type::IBV_QPT_RC;
@@ -10,12 +10,8 @@ use tracing::trace;
 const QP_COUNT: usize = 3;
 
 impl BaseClient {
-    pub fn new(addr: IpAddr) -> io::Result<Self> {
-        let ctx = ibverbs::devices()?
-            .iter()
-            .next()
-            .ok_or(io::ErrorKind::NotFound)?
-            .open()?;
+    pub fn new(addr: IpAddr, device: Option<&str>) -> io::Result<Self> {
+        let ctx = open_device(device)?;
 
         let pd = ctx.alloc_pd()?;
         let cq = ctx.create_cq(1024, 0)?;
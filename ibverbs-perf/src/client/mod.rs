@@ -5,13 +5,35 @@ pub mod naive;
 pub mod pipeline;
 
 use bytes::BytesMut;
-use ibverbs::{CompletionQueue, ProtectionDomain, QueuePair, RemoteMemorySlice};
+use ibverbs::{
+    CompletionQueue, ProtectionDomain, QueuePair, RemoteMemorySlice, WaitPhase, WaitStrategy,
+};
 use std::fmt::Debug;
-use std::{hint, io};
+use std::time::Duration;
+use std::{io, thread};
 
 #[cfg(feature = "hwlocality")]
 pub(crate) const NUMA_NODE: usize = 1;
 
+/// How `RequestHandle`s wait for their request, before falling back to sleeping.
+const WAIT_STRATEGY: WaitStrategy = WaitStrategy::new()
+    .spin_for(Duration::from_micros(100))
+    .yield_for(Duration::from_millis(1));
+
+/// Waits until `ready` returns `true`, following `WAIT_STRATEGY`.
+///
+/// The handles are completed by other threads rather than through a completion channel, so
+/// instead of blocking, this sleeps between checks once the strategy ran out.
+fn wait_until(mut ready: impl FnMut() -> bool) -> WaitPhase {
+    if let Some(((), phase)) = WAIT_STRATEGY.spin_then_yield(|| ready().then_some(())) {
+        return phase;
+    }
+    while !ready() {
+        thread::sleep(Duration::from_micros(50));
+    }
+    WaitPhase::Sleep
+}
+
 pub struct BaseClient {
     pub(crate) pd: ProtectionDomain,
     pub(crate) cq: CompletionQueue,
@@ -32,16 +54,12 @@ pub trait BlockingClient: Client + Sized {
 
 pub trait RequestHandle {
     fn is_available(&self) -> bool;
-    fn wait_available(&self) {
-        while !self.is_available() {
-            hint::spin_loop();
-        }
+    fn wait_available(&self) -> WaitPhase {
+        wait_until(|| self.is_available())
     }
     fn is_acquirable(&self) -> bool;
-    fn wait_acquirable(&self) {
-        while !self.is_acquirable() {
-            hint::spin_loop();
-        }
+    fn wait_acquirable(&self) -> WaitPhase {
+        wait_until(|| self.is_acquirable())
     }
     fn acquire(self) -> io::Result<BytesMut>;
 }
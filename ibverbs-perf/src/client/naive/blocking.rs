@@ -43,6 +43,6 @@ impl client::BlockingClient for Client {
             }
         }
 
-        mr.deregister()
+        Ok(mr.deregister()?)
     }
 }
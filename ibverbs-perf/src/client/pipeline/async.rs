@@ -1,4 +1,6 @@
-use super::lib::{DeregistrationMessage, Handle, Pending, PostMessage, RegistrationMessage};
+use super::lib::{
+    DeregistrationMessage, Handle, Pending, PostMessage, RegistrationMessage, post_reads,
+};
 use crate::client::lib::{decode_wr_id, encode_wr_id};
 use crate::{chunks_mut_exact, client};
 use bytes::BytesMut;
@@ -72,44 +74,24 @@ impl client::NonBlockingClient for Client {
 
         task::spawn_blocking(move || {
             let mut pending = HashMap::new();
-            let mut waiting = VecDeque::new();
+            let mut waiting = VecDeque::<PostMessage>::new();
             let mut completions = [ibv_wc::default(); 16];
 
             loop {
-                if let Some(PostMessage {
+                let posted = post_reads(&client.qps, &mut waiting, |msg| {
+                    (&msg.mr, msg.remote, encode_wr_id(msg.id, msg.chunk))
+                })
+                .unwrap_or_else(|e| panic!("{:?}", e));
+                for PostMessage {
                     id,
                     chunk,
                     state,
                     mr,
-                    remote,
-                }) = waiting.pop_front()
+                    ..
+                } in posted
                 {
-                    let local = mr.slice_local(..).collect::<Vec<_>>();
-                    let wr_id = encode_wr_id(id, chunk);
-
-                    let mut posted = false;
-                    for qp in &client.qps {
-                        match unsafe { qp.post_read(&local, remote, wr_id) } {
-                            Ok(_) => {
-                                posted = true;
-                                break;
-                            }
-                            Err(e) if e.kind() == io::ErrorKind::OutOfMemory => continue,
-                            Err(e) => panic!("{:?}", e),
-                        }
-                    }
-                    if posted {
-                        state.posted.fetch_add(1, Ordering::Relaxed);
-                        pending.insert(wr_id, Pending { state, mr });
-                    } else {
-                        waiting.push_front(PostMessage {
-                            id,
-                            chunk,
-                            state,
-                            mr,
-                            remote,
-                        });
-                    }
+                    state.posted.fetch_add(1, Ordering::Relaxed);
+                    pending.insert(encode_wr_id(id, chunk), Pending { state, mr });
                 }
 
                 match post_rx.try_recv() {
@@ -1,3 +1,4 @@
+use super::lib::post_reads;
 use crate::{chunks_mut_exact, chunks_unsplit, client};
 use bytes::BytesMut;
 use ibverbs::{RemoteMemorySlice, ibv_wc};
@@ -54,26 +55,11 @@ impl client::BlockingClient for Client {
                 allocated.push_back((chunk, mr, remote));
             }
 
-            if let Some((chunk, mr, remote)) = allocated.pop_front() {
-                let local = mr.slice_local(..).collect::<Vec<_>>();
-
-                let mut posted = false;
-                for qp in &self.base.qps {
-                    match unsafe { qp.post_read(&local, remote, chunk as u64) } {
-                        Ok(_) => {
-                            posted = true;
-                            break;
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::OutOfMemory => continue,
-                        Err(e) => return Err(e),
-                    }
-                }
-
-                if posted {
-                    pending.insert(chunk, mr);
-                } else {
-                    allocated.push_front((chunk, mr, remote));
-                }
+            let posted = post_reads(&self.base.qps, &mut allocated, |(chunk, mr, remote)| {
+                (mr, *remote, *chunk as u64)
+            })?;
+            for (chunk, mr, _) in posted {
+                pending.insert(chunk, mr);
             }
 
             for completion in self.base.cq.poll(&mut completions)? {
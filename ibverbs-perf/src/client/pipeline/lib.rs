@@ -2,7 +2,8 @@ use crate::chunks_unsplit;
 use crate::client::RequestHandle;
 use bytes::BytesMut;
 use dashmap::DashMap;
-use ibverbs::{MemoryRegion, RemoteMemorySlice};
+use ibverbs::{MemoryRegion, QueuePair, RemoteMemorySlice, SendBatch};
+use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -58,6 +59,50 @@ impl RequestHandle for Handle {
     }
 }
 
+/// Posts reads of all `waiting` chunks, given by their memory region, remote slice and `wr_id`.
+///
+/// The reads are posted as one batch to the first queue pair, and whatever it has no room for to
+/// the next one. Returns the chunks that were posted, and leaves the rest in `waiting`.
+pub(crate) fn post_reads<T>(
+    qps: &[QueuePair],
+    waiting: &mut VecDeque<T>,
+    read: impl Fn(&T) -> (&MemoryRegion, RemoteMemorySlice, u64),
+) -> Result<Vec<T>, ibverbs::Error> {
+    if waiting.is_empty() {
+        return Ok(Vec::new());
+    }
+    // the local slices of all chunks, one after the other
+    let mut locals = Vec::with_capacity(waiting.len());
+    for chunk in waiting.iter() {
+        locals.extend(read(chunk).0.slice_local(..));
+    }
+
+    let mut batch = SendBatch::with_capacity(waiting.len());
+    let mut posted = 0;
+    for qp in qps {
+        // add the reads that are not posted yet
+        batch.clear();
+        let mut start = 0;
+        for (i, chunk) in waiting.iter().enumerate() {
+            let (mr, remote, wr_id) = read(chunk);
+            let end = start + mr.slice_local(..).count();
+            if i >= posted {
+                batch.read(&locals[start..end], remote, wr_id);
+            }
+            start = end;
+        }
+        match unsafe { qp.post_send_batch(&mut batch) } {
+            Ok(_) => {
+                posted = waiting.len();
+                break;
+            }
+            Err(e) if e.error.kind() == io::ErrorKind::OutOfMemory => posted += e.index,
+            Err(e) => return Err(e.error),
+        }
+    }
+    Ok(waiting.drain(..posted).collect())
+}
+
 pub(crate) struct Pending {
     pub(crate) state: Arc<State>,
     pub(crate) mr: MemoryRegion,
@@ -1,5 +1,6 @@
 use bincode::config::{Configuration, standard};
 use bytes::BytesMut;
+use ibverbs::Context;
 use std::{io, iter};
 
 pub mod bench;
@@ -17,6 +18,16 @@ pub static GB: usize = 1000 * MB;
 
 pub(crate) const BINCODE_CONFIG: Configuration = standard();
 
+/// Opens the RDMA device with the given name, or the first device if no name is given.
+pub fn open_device(name: Option<&str>) -> io::Result<Context> {
+    let devices = ibverbs::devices()?;
+    let device = match name {
+        Some(name) => devices.find_by_name(name),
+        None => devices.get(0),
+    };
+    Ok(device.ok_or(io::ErrorKind::NotFound)?.open()?)
+}
+
 #[cfg(feature = "hwlocality")]
 pub mod hwlocality {
     use hwlocality::Topology;
@@ -1,6 +1,6 @@
 #[cfg(feature = "hwlocality")]
 use crate::hwlocality::pin_thread_to_node;
-use crate::{BINCODE_CONFIG, PORT};
+use crate::{BINCODE_CONFIG, PORT, open_device};
 use bincode::serde::{decode_from_std_read, encode_into_std_write};
 use ibverbs::ibv_qp_type::IBV_QPT_RC;
 use ibverbs::{CompletionQueue, MemoryRegion, ProtectionDomain, QueuePair};
@@ -12,6 +12,7 @@ pub const NUMA_NODE: usize = 0;
 
 pub struct Config {
     pub size: usize,
+    pub device: Option<String>,
 }
 
 pub struct Server {
@@ -26,11 +27,7 @@ impl Server {
         #[cfg(feature = "hwlocality")]
         pin_thread_to_node::<NUMA_NODE>()?;
 
-        let ctx = ibverbs::devices()?
-            .iter()
-            .next()
-            .ok_or(io::ErrorKind::NotFound)?
-            .open()?;
+        let ctx = open_device(config.device.as_deref())?;
 
         let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, PORT))?;
         let pd = ctx.alloc_pd()?;
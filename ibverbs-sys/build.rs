@@ -54,6 +54,8 @@ fn main() {
         .allowlist_function("ibv_.*")
         .allowlist_function("_ibv_.*")
         .allowlist_type("ibv_.*")
+        // needed to reach the extended verbs that verbs.h only exposes through static inline functions
+        .allowlist_type("verbs_context")
         .allowlist_var("IBV_LINK_LAYER_.*")
         .bitfield_enum("ibv_access_flags")
         .bitfield_enum("ibv_create_cq_wc_flags")
@@ -7,13 +7,12 @@ pub fn benchmark(c: &mut Criterion) {
     static MB: usize = 1024 * KB;
     static GB: usize = 1024 * MB;
 
-    let ctx = ibverbs::devices()
-        .unwrap()
-        .iter()
-        .next()
-        .unwrap()
-        .open()
-        .unwrap();
+    let devices = ibverbs::devices().unwrap();
+    let device = match std::env::var("IBVERBS_DEVICE") {
+        Ok(name) => devices.find_by_name(&name),
+        Err(_) => devices.get(0),
+    };
+    let ctx = device.expect("no RDMA device found").open().unwrap();
     let pd = ctx.alloc_pd().unwrap();
 
     let mut group = c.benchmark_group("MemoryRegion");
@@ -0,0 +1,234 @@
+//! Waiting for work completions from async code running on tokio.
+
+use crate::{CompletionQueue, Error};
+use ffi::ibv_wc;
+use std::future::Future;
+use std::os::fd::{AsRawFd, RawFd};
+use tokio::io::unix::AsyncFd;
+
+/// The number of work completions that `AsyncCompletionQueue::next` polls at once.
+const BATCH_SIZE: usize = 16;
+
+/// A `CompletionQueue` that can be waited on from async code running on a tokio runtime.
+///
+/// Instead of blocking a thread like `CompletionQueue::wait`, waiting yields to the runtime until
+/// the CQ's completion channel becomes readable. Arming the CQ and acknowledging its completion
+/// events is handled internally.
+///
+/// The CQ must have a completion channel of its own: a channel that is shared with other CQs (see
+/// `CompletionQueueBuilder::set_channel`) can only be registered with the runtime once.
+pub struct AsyncCompletionQueue {
+    cq: AsyncFd<ChannelFd>,
+    batch: Batch,
+}
+
+/// A batch of work completions, along with the number of them that were filled in.
+type Polled = Result<([ibv_wc; BATCH_SIZE], usize), Error>;
+
+/// Work completions that were polled at once, and are handed out one by one.
+#[derive(Default)]
+struct Batch {
+    completions: [ibv_wc; BATCH_SIZE],
+    len: usize,
+    next: usize,
+}
+
+impl Batch {
+    /// Returns the next work completion of the batch, or waits for a new batch with `wait_batch`
+    /// if all were handed out.
+    ///
+    /// The batch is only replaced once `wait_batch` succeeded, so that neither an error nor
+    /// dropping the returned future leaves entries behind that were never polled.
+    async fn next<W, F>(&mut self, wait_batch: W) -> Result<ibv_wc, Error>
+    where
+        W: FnOnce([ibv_wc; BATCH_SIZE]) -> F,
+        F: Future<Output = Polled>,
+    {
+        if self.next == self.len {
+            let (completions, len) = wait_batch([ibv_wc::default(); BATCH_SIZE]).await?;
+            self.completions = completions;
+            self.len = len;
+            self.next = 0;
+        }
+        let wc = self.completions[self.next];
+        self.next += 1;
+        Ok(wc)
+    }
+}
+
+/// A `CompletionQueue` along with the file descriptor of its completion channel.
+struct ChannelFd {
+    cq: CompletionQueue,
+    fd: RawFd,
+}
+
+impl AsRawFd for ChannelFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsyncCompletionQueue {
+    /// Wraps `cq`, and registers its completion channel with the current tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called from within a tokio runtime.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::NoCompletionChannel`: The CQ was created without a completion channel.
+    ///  - System errors: From registering the completion channel with the runtime.
+    pub fn new(cq: CompletionQueue) -> Result<Self, Error> {
+        let fd = cq.channel().ok_or(Error::NoCompletionChannel)?.as_raw_fd();
+        Ok(AsyncCompletionQueue {
+            cq: AsyncFd::new(ChannelFd { cq, fd })?,
+            batch: Batch::default(),
+        })
+    }
+
+    /// Returns the wrapped `CompletionQueue`.
+    pub fn get_ref(&self) -> &CompletionQueue {
+        &self.cq.get_ref().cq
+    }
+
+    /// Deregisters the completion channel from the runtime, and returns the wrapped
+    /// `CompletionQueue`.
+    ///
+    /// Work completions that were already polled by `next`, but not returned yet, are lost.
+    pub fn into_inner(self) -> CompletionQueue {
+        self.cq.into_inner().cq
+    }
+
+    /// Waits for one or more work completions.
+    ///
+    /// This is the async counterpart of `CompletionQueue::wait`. It returns the non-empty subset
+    /// of `completions` that was filled in.
+    ///
+    /// # Errors
+    ///
+    ///  - System errors: From underlying calls like `req_notify_cq`, `poll`, or
+    ///    `ibv_get_cq_event`.
+    pub async fn wait<'c>(&self, completions: &'c mut [ibv_wc]) -> Result<&'c mut [ibv_wc], Error> {
+        let n = wait(&self.cq, completions).await?;
+        Ok(&mut completions[..n])
+    }
+
+    /// Waits for the next work completion.
+    ///
+    /// Work completions are polled in batches, and returned one by one. The returned future may
+    /// be dropped before it completes, e.g. in `tokio::select!`, without losing completions.
+    ///
+    /// # Errors
+    ///
+    ///  - System errors: From underlying calls like `req_notify_cq`, `poll`, or
+    ///    `ibv_get_cq_event`.
+    pub async fn next(&mut self) -> Result<ibv_wc, Error> {
+        let cq = &self.cq;
+        self.batch
+            .next(|mut completions| async move {
+                let n = wait(cq, &mut completions).await?;
+                Ok((completions, n))
+            })
+            .await
+    }
+}
+
+/// Waits until `cq` has work completions, and returns how many of them were polled into
+/// `completions`.
+async fn wait(cq: &AsyncFd<ChannelFd>, completions: &mut [ibv_wc]) -> Result<usize, Error> {
+    loop {
+        let n = cq.get_ref().cq.poll(completions)?.len();
+        if n > 0 {
+            return Ok(n);
+        }
+
+        cq.get_ref().cq.req_notify()?;
+
+        // We poll again to avoid a race when Work Completions arrive between the first `poll()`
+        // and `req_notify_cq()`.
+        let n = cq.get_ref().cq.poll(completions)?.len();
+        if n > 0 {
+            return Ok(n);
+        }
+
+        let mut guard = cq.readable().await?;
+        if !cq.get_ref().cq.consume_event()? {
+            // the readiness was stale, wait for the runtime to report it again
+            guard.clear_ready();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::pin::pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use std::{future, ptr};
+
+    fn poll_once<F: Future>(f: F) -> Poll<F::Output> {
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| {}, |_| {}, |_| {});
+        const RAW: RawWaker = RawWaker::new(ptr::null(), &VTABLE);
+        // SAFETY: the waker does nothing, and has no data.
+        let waker = unsafe { Waker::from_raw(RAW) };
+        pin!(f).poll(&mut Context::from_waker(&waker))
+    }
+
+    fn wc(wr_id: u64) -> ibv_wc {
+        let mut wc = ibv_wc::default();
+        // SAFETY: `ibv_wc` mirrors the C struct, which starts with the `wr_id`.
+        unsafe { (&mut wc as *mut ibv_wc).cast::<u64>().write(wr_id) };
+        wc
+    }
+
+    /// Returns a `wait_batch` that polls the completions of `wr_ids`.
+    fn polled(wr_ids: &[u64]) -> impl FnOnce([ibv_wc; BATCH_SIZE]) -> future::Ready<Polled> + '_ {
+        move |mut completions| {
+            for (wc_slot, &wr_id) in completions.iter_mut().zip(wr_ids) {
+                *wc_slot = wc(wr_id);
+            }
+            future::ready(Ok((completions, wr_ids.len())))
+        }
+    }
+
+    fn next_wr_id(batch: &mut Batch, wr_ids: &[u64]) -> u64 {
+        match poll_once(batch.next(polled(wr_ids))) {
+            Poll::Ready(Ok(wc)) => wc.wr_id(),
+            _ => panic!("expected a completion"),
+        }
+    }
+
+    #[test]
+    fn completions_are_handed_out_in_order() {
+        let mut batch = Batch::default();
+        assert_eq!(next_wr_id(&mut batch, &[1, 2]), 1);
+        // the batch is not refilled while completions are left
+        assert_eq!(next_wr_id(&mut batch, &[9]), 2);
+        assert_eq!(next_wr_id(&mut batch, &[3]), 3);
+    }
+
+    #[test]
+    fn cancelled_wait_leaves_no_completions() {
+        let mut batch = Batch::default();
+        let waiting = batch.next(|_| future::pending());
+        assert!(poll_once(waiting).is_pending());
+        assert_eq!(next_wr_id(&mut batch, &[4]), 4);
+
+        // cancelled once the first batch was handed out
+        let waiting = batch.next(|_| future::pending());
+        assert!(poll_once(waiting).is_pending());
+        assert_eq!(next_wr_id(&mut batch, &[5]), 5);
+    }
+
+    #[test]
+    fn failed_wait_leaves_no_completions() {
+        let mut batch = Batch::default();
+        assert_eq!(next_wr_id(&mut batch, &[1]), 1);
+
+        let failing = batch.next(|_| future::ready(Err(Error::verb_without_errno("ibv_poll_cq"))));
+        assert!(matches!(poll_once(failing), Poll::Ready(Err(_))));
+        assert_eq!(next_wr_id(&mut batch, &[2, 3]), 2);
+        assert_eq!(next_wr_id(&mut batch, &[9]), 3);
+    }
+}
@@ -0,0 +1,378 @@
+//! Batches of work requests that are posted to a `QueuePair` with a single call.
+
+use crate::{ibv_send_flags, Error, LocalMemorySlice, RemoteMemorySlice};
+use std::marker::PhantomData;
+use std::{error, fmt, ptr};
+
+/// A batch of send requests (sends, RDMA writes and RDMA reads), posted with
+/// `QueuePair::post_send_batch`.
+///
+/// The requests are chained into a single linked list, so the device is only notified once for
+/// the whole batch. The batch borrows the `LocalMemorySlice`s of its requests until it is dropped
+/// or cleared. Requests are signaled, and generate a work completion with their `wr_id`, unless
+/// other flags are set with `SendBatch::set_flags`.
+pub struct SendBatch<'a> {
+    wrs: Vec<ffi::ibv_send_wr>,
+    flags: ibv_send_flags,
+    _local: PhantomData<&'a [LocalMemorySlice]>,
+}
+
+impl Default for SendBatch<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> SendBatch<'a> {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Creates an empty batch with room for `capacity` requests.
+    pub fn with_capacity(capacity: usize) -> Self {
+        SendBatch {
+            wrs: Vec::with_capacity(capacity),
+            flags: ibv_send_flags::IBV_SEND_SIGNALED,
+            _local: PhantomData,
+        }
+    }
+
+    /// Returns the number of requests in the batch.
+    pub fn len(&self) -> usize {
+        self.wrs.len()
+    }
+
+    /// Returns `true` if the batch has no requests.
+    pub fn is_empty(&self) -> bool {
+        self.wrs.is_empty()
+    }
+
+    /// Removes all requests from the batch, so that it can be reused.
+    pub fn clear(&mut self) {
+        self.wrs.clear();
+    }
+
+    /// Sets the flags of the requests that are added after this call, as for
+    /// `QueuePair::post_send_with_flags`.
+    ///
+    /// A common pattern is to leave all but the last request of a batch unsignaled, so that the
+    /// batch generates a single work completion.
+    pub fn set_flags(&mut self, flags: ibv_send_flags) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Adds a send of `local`, like `QueuePair::post_send`.
+    pub fn send(
+        &mut self,
+        local: &'a [LocalMemorySlice],
+        wr_id: u64,
+        imm_data: Option<u32>,
+    ) -> &mut Self {
+        let (opcode, imm_data) = match imm_data {
+            Some(imm) => (ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM, imm),
+            None => (ffi::ibv_wr_opcode::IBV_WR_SEND, 0),
+        };
+        self.push(local, wr_id, opcode, imm_data, Default::default())
+    }
+
+    /// Adds an RDMA write of `local` to `remote`, like `QueuePair::post_write`.
+    pub fn write(
+        &mut self,
+        local: &'a [LocalMemorySlice],
+        remote: RemoteMemorySlice,
+        wr_id: u64,
+        imm_data: Option<u32>,
+    ) -> &mut Self {
+        let (opcode, imm_data) = match imm_data {
+            Some(imm) => (ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM, imm.to_be()),
+            None => (ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE, 0),
+        };
+        self.push(local, wr_id, opcode, imm_data, rdma(remote))
+    }
+
+    /// Adds an RDMA read of `remote` into `local`, like `QueuePair::post_read`.
+    pub fn read(
+        &mut self,
+        local: &'a [LocalMemorySlice],
+        remote: RemoteMemorySlice,
+        wr_id: u64,
+    ) -> &mut Self {
+        let opcode = ffi::ibv_wr_opcode::IBV_WR_RDMA_READ;
+        self.push(local, wr_id, opcode, 0, rdma(remote))
+    }
+
+    fn push(
+        &mut self,
+        local: &'a [LocalMemorySlice],
+        wr_id: u64,
+        opcode: ffi::ibv_wr_opcode,
+        imm_data: u32,
+        wr: ffi::ibv_send_wr__bindgen_ty_2,
+    ) -> &mut Self {
+        self.wrs.push(ffi::ibv_send_wr {
+            wr_id,
+            next: ptr::null_mut(),
+            sg_list: local.as_ptr() as *mut ffi::ibv_sge,
+            num_sge: local.len() as i32,
+            opcode,
+            send_flags: self.flags.0,
+            wr,
+            qp_type: Default::default(),
+            __bindgen_anon_1: ffi::ibv_send_wr__bindgen_ty_1 { imm_data },
+            __bindgen_anon_2: Default::default(),
+        });
+        self
+    }
+
+    /// Chains the requests into a linked list, and returns them.
+    ///
+    /// The list is only valid until the batch is modified.
+    pub(crate) fn link(&mut self) -> &mut [ffi::ibv_send_wr] {
+        link(&mut self.wrs, |wr, next| wr.next = next);
+        &mut self.wrs
+    }
+}
+
+/// A batch of receive requests, posted with `QueuePair::post_receive_batch`.
+///
+/// The requests are chained into a single linked list, so the device is only notified once for
+/// the whole batch. The batch borrows the `LocalMemorySlice`s of its requests until it is dropped
+/// or cleared.
+pub struct RecvBatch<'a> {
+    wrs: Vec<ffi::ibv_recv_wr>,
+    _local: PhantomData<&'a [LocalMemorySlice]>,
+}
+
+impl Default for RecvBatch<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> RecvBatch<'a> {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Creates an empty batch with room for `capacity` requests.
+    pub fn with_capacity(capacity: usize) -> Self {
+        RecvBatch {
+            wrs: Vec::with_capacity(capacity),
+            _local: PhantomData,
+        }
+    }
+
+    /// Returns the number of requests in the batch.
+    pub fn len(&self) -> usize {
+        self.wrs.len()
+    }
+
+    /// Returns `true` if the batch has no requests.
+    pub fn is_empty(&self) -> bool {
+        self.wrs.is_empty()
+    }
+
+    /// Removes all requests from the batch, so that it can be reused.
+    pub fn clear(&mut self) {
+        self.wrs.clear();
+    }
+
+    /// Adds a receive into `local`, like `QueuePair::post_receive`.
+    pub fn receive(&mut self, local: &'a [LocalMemorySlice], wr_id: u64) -> &mut Self {
+        self.wrs.push(ffi::ibv_recv_wr {
+            wr_id,
+            next: ptr::null_mut(),
+            sg_list: local.as_ptr() as *mut ffi::ibv_sge,
+            num_sge: local.len() as i32,
+        });
+        self
+    }
+
+    /// Chains the requests into a linked list, and returns its head.
+    ///
+    /// The list is only valid until the batch is modified.
+    pub(crate) fn link(&mut self) -> *mut ffi::ibv_recv_wr {
+        link(&mut self.wrs, |wr, next| wr.next = next)
+    }
+
+    /// Returns the index of `wr`, which must point into the batch.
+    pub(crate) fn index_of(&self, wr: *const ffi::ibv_recv_wr) -> usize {
+        index_of(&self.wrs, wr)
+    }
+}
+
+fn rdma(remote: RemoteMemorySlice) -> ffi::ibv_send_wr__bindgen_ty_2 {
+    ffi::ibv_send_wr__bindgen_ty_2 {
+        rdma: ffi::ibv_send_wr__bindgen_ty_2__bindgen_ty_1 {
+            remote_addr: remote.addr,
+            rkey: remote.rkey,
+        },
+    }
+}
+
+fn link<T>(wrs: &mut [T], mut set_next: impl FnMut(&mut T, *mut T)) -> *mut T {
+    // the requests are linked after they were added, since adding may move them
+    let head = wrs.as_mut_ptr();
+    let n = wrs.len();
+    for i in 0..n {
+        let next = if i + 1 < n {
+            head.wrapping_add(i + 1)
+        } else {
+            ptr::null_mut()
+        };
+        // SAFETY: `i` is in bounds, and `head` is the only pointer into `wrs` in use.
+        set_next(unsafe { &mut *head.add(i) }, next);
+    }
+    head
+}
+
+/// Returns the index of `wr`, which must point into `wrs`.
+pub(crate) fn index_of<T>(wrs: &[T], wr: *const T) -> usize {
+    (wr as usize - wrs.as_ptr() as usize) / std::mem::size_of::<T>()
+}
+
+/// The error returned when posting a `SendBatch` or `RecvBatch` fails.
+///
+/// The requests before `index` were posted, and will generate work completions. The request at
+/// `index` and all requests after it were not posted.
+#[derive(Debug)]
+pub struct BatchError {
+    /// The index of the first request that was rejected.
+    pub index: usize,
+    /// The reason the request was rejected.
+    pub error: Error,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "work request {} of the batch: {}",
+            self.index, self.error
+        )
+    }
+}
+
+impl error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<BatchError> for Error {
+    fn from(e: BatchError) -> Self {
+        e.error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn send_wrs(n: u64) -> Vec<ffi::ibv_send_wr> {
+        (0..n)
+            .map(|wr_id| ffi::ibv_send_wr {
+                wr_id,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn link_chains_in_order() {
+        let mut wrs = send_wrs(3);
+        let head = link(&mut wrs, |wr, next| wr.next = next);
+        assert_eq!(head, wrs.as_mut_ptr());
+
+        let mut wr = head;
+        let mut wr_ids = Vec::new();
+        while !wr.is_null() {
+            // SAFETY: the list only points into `wrs`.
+            let current = unsafe { &*wr };
+            wr_ids.push(current.wr_id);
+            assert_eq!(index_of(&wrs, wr), current.wr_id as usize);
+            wr = current.next;
+        }
+        assert_eq!(wr_ids, [0, 1, 2]);
+    }
+
+    #[test]
+    fn link_of_one_request_ends_the_list() {
+        let mut wrs = send_wrs(1);
+        wrs[0].next = ptr::NonNull::dangling().as_ptr();
+        link(&mut wrs, |wr, next| wr.next = next);
+        assert!(wrs[0].next.is_null());
+    }
+
+    #[test]
+    fn send_batch_applies_flags_to_later_requests() {
+        let remote = RemoteMemorySlice {
+            addr: 0x1000,
+            length: 8,
+            rkey: 7,
+        };
+        let mut batch = SendBatch::new();
+        batch
+            .set_flags(ibv_send_flags(0))
+            .write(&[], remote, 1, Some(0x0102_0304))
+            .read(&[], remote, 2)
+            .set_flags(ibv_send_flags::IBV_SEND_SIGNALED)
+            .send(&[], 3, None);
+        assert_eq!(batch.len(), 3);
+
+        let wrs = batch.link();
+        let flags = wrs.iter().map(|wr| wr.send_flags).collect::<Vec<_>>();
+        assert_eq!(flags, [0, 0, ibv_send_flags::IBV_SEND_SIGNALED.0]);
+        let opcodes = wrs.iter().map(|wr| wr.opcode).collect::<Vec<_>>();
+        assert_eq!(
+            opcodes,
+            [
+                ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM,
+                ffi::ibv_wr_opcode::IBV_WR_RDMA_READ,
+                ffi::ibv_wr_opcode::IBV_WR_SEND,
+            ]
+        );
+        // immediate data goes out in network byte order
+        assert_eq!(
+            unsafe { wrs[0].__bindgen_anon_1.imm_data },
+            0x0102_0304_u32.to_be()
+        );
+        assert_eq!(unsafe { wrs[1].wr.rdma.remote_addr }, 0x1000);
+        assert_eq!(index_of(wrs, wrs[0].next), 1);
+        assert!(wrs[2].next.is_null());
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn recv_batch_reports_the_index_of_a_request() {
+        let mut batch = RecvBatch::with_capacity(4);
+        for wr_id in 0..4 {
+            batch.receive(&[], wr_id);
+        }
+        let head = batch.link();
+        // SAFETY: the list only points into the batch.
+        let third = unsafe { (*(*head).next).next };
+        assert_eq!(unsafe { (*third).wr_id }, 2);
+        assert_eq!(batch.index_of(third), 2);
+        assert_eq!(batch.index_of(head), 0);
+    }
+
+    #[test]
+    fn batch_error_reports_index() {
+        let error = Error::verb("ibv_post_send", nix::libc::ENOMEM);
+        let e = BatchError { index: 3, error };
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "work request 3 of the batch: {}",
+                Error::verb("ibv_post_send", nix::libc::ENOMEM)
+            )
+        );
+        assert!(Error::from(e).is_out_of_resources());
+    }
+}
@@ -0,0 +1,234 @@
+//! Typed views of the work completions polled from a `CompletionQueue`.
+
+use ffi::{ibv_wc, ibv_wc_flags, ibv_wc_opcode, ibv_wc_status};
+use std::ffi::CStr;
+use std::fmt;
+
+/// A work completion, split by whether and how the work request completed.
+///
+/// Unlike `ffi::ibv_wc`, each variant only carries the attributes that are valid for it. Use
+/// `CompletionQueue::poll_completions` to poll them, or convert an `ffi::ibv_wc` with `From`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WorkCompletion {
+    /// A send, posted with `QueuePair::post_send` or `QueuePair::post_send_ud`, completed.
+    Send {
+        /// The `wr_id` the work request was posted with.
+        wr_id: u64,
+    },
+    /// An RDMA write completed.
+    RdmaWrite {
+        /// The `wr_id` the work request was posted with.
+        wr_id: u64,
+    },
+    /// An RDMA read completed.
+    RdmaRead {
+        /// The `wr_id` the work request was posted with.
+        wr_id: u64,
+        /// The number of bytes that were read.
+        byte_len: u32,
+    },
+    /// An atomic compare-and-swap completed.
+    CompareAndSwap {
+        /// The `wr_id` the work request was posted with.
+        wr_id: u64,
+    },
+    /// An atomic fetch-and-add completed.
+    FetchAndAdd {
+        /// The `wr_id` the work request was posted with.
+        wr_id: u64,
+    },
+    /// A receive request consumed an incoming send.
+    Recv {
+        /// The `wr_id` the receive request was posted with.
+        wr_id: u64,
+        /// The number of bytes received. For UD QPs, this includes the 40 bytes reserved for the
+        /// GRH.
+        byte_len: u32,
+        /// The immediate data that was sent along with the message, if any.
+        imm_data: Option<u32>,
+        /// The number of the local QP that received the message. This tells the QPs of a
+        /// `SharedReceiveQueue` apart.
+        qp_num: u32,
+        /// The number of the QP that sent the message. Only valid for UD QPs.
+        src_qp: u32,
+        /// Whether the receive buffer starts with a valid GRH. Only set for UD QPs; see
+        /// `Grh::parse`.
+        grh: bool,
+    },
+    /// A receive request consumed an incoming RDMA write with immediate data.
+    RecvRdmaWithImm {
+        /// The `wr_id` the receive request was posted with.
+        wr_id: u64,
+        /// The number of bytes the remote side wrote.
+        byte_len: u32,
+        /// The immediate data that was sent along with the write.
+        imm_data: u32,
+        /// The number of the local QP that received the write.
+        qp_num: u32,
+    },
+    /// A work request with an opcode this crate does not post completed, e.g. a memory window
+    /// bind.
+    Other {
+        /// The `wr_id` the work request was posted with.
+        wr_id: u64,
+        /// The operation the work request performed.
+        opcode: ibv_wc_opcode,
+    },
+    /// The work request failed.
+    Failed(WcError),
+}
+
+impl WorkCompletion {
+    /// Returns the `wr_id` the work request was posted with.
+    pub fn wr_id(&self) -> u64 {
+        match *self {
+            WorkCompletion::Send { wr_id }
+            | WorkCompletion::RdmaWrite { wr_id }
+            | WorkCompletion::RdmaRead { wr_id, .. }
+            | WorkCompletion::CompareAndSwap { wr_id }
+            | WorkCompletion::FetchAndAdd { wr_id }
+            | WorkCompletion::Recv { wr_id, .. }
+            | WorkCompletion::RecvRdmaWithImm { wr_id, .. }
+            | WorkCompletion::Other { wr_id, .. } => wr_id,
+            WorkCompletion::Failed(e) => e.wr_id,
+        }
+    }
+
+    /// Returns `true` if the work request completed successfully.
+    pub fn is_success(&self) -> bool {
+        !matches!(self, WorkCompletion::Failed(_))
+    }
+
+    /// Turns a failed work completion into an `Err`, so it can be propagated with `?`.
+    pub fn into_result(self) -> Result<Self, WcError> {
+        match self {
+            WorkCompletion::Failed(e) => Err(e),
+            wc => Ok(wc),
+        }
+    }
+}
+
+impl From<&ibv_wc> for WorkCompletion {
+    fn from(wc: &ibv_wc) -> Self {
+        if let Some((status, vendor_err)) = wc.error() {
+            return WorkCompletion::Failed(WcError {
+                wr_id: wc.wr_id(),
+                status,
+                vendor_err,
+                qp_num: wc.qp_num,
+            });
+        }
+
+        let wr_id = wc.wr_id();
+        let byte_len = wc.len() as u32;
+        match wc.opcode() {
+            ibv_wc_opcode::IBV_WC_SEND => WorkCompletion::Send { wr_id },
+            ibv_wc_opcode::IBV_WC_RDMA_WRITE => WorkCompletion::RdmaWrite { wr_id },
+            ibv_wc_opcode::IBV_WC_RDMA_READ => WorkCompletion::RdmaRead { wr_id, byte_len },
+            ibv_wc_opcode::IBV_WC_COMP_SWAP => WorkCompletion::CompareAndSwap { wr_id },
+            ibv_wc_opcode::IBV_WC_FETCH_ADD => WorkCompletion::FetchAndAdd { wr_id },
+            ibv_wc_opcode::IBV_WC_RECV => WorkCompletion::Recv {
+                wr_id,
+                byte_len,
+                imm_data: wc.imm_data(),
+                qp_num: wc.qp_num,
+                src_qp: wc.src_qp,
+                grh: (wc.wc_flags & ibv_wc_flags::IBV_WC_GRH).0 != 0,
+            },
+            ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM => WorkCompletion::RecvRdmaWithImm {
+                wr_id,
+                byte_len,
+                imm_data: wc.imm_data,
+                qp_num: wc.qp_num,
+            },
+            opcode => WorkCompletion::Other { wr_id, opcode },
+        }
+    }
+}
+
+/// A work request that completed with an error status.
+///
+/// On a reliable connection, an error completion moves the `QueuePair` into the error state, and
+/// all of its other outstanding work requests are flushed with `IBV_WC_WR_FLUSH_ERR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WcError {
+    /// The `wr_id` the work request was posted with.
+    pub wr_id: u64,
+    /// The status the work request completed with. Never `IBV_WC_SUCCESS`.
+    pub status: ibv_wc_status,
+    /// The vendor specific error syndrome.
+    pub vendor_err: u32,
+    /// The number of the local QP the work request was posted to.
+    pub qp_num: u32,
+}
+
+impl WcError {
+    /// Returns `true` if the work request may succeed when posted again, once the connection is
+    /// re-established.
+    ///
+    /// This is the case when the request was flushed because of another failure, or when the
+    /// remote side did not respond in time, e.g. because it had no receive requests posted or
+    /// went away. Other statuses report errors in the request itself, like protection or length
+    /// violations, that will make it fail again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.status,
+            ibv_wc_status::IBV_WC_WR_FLUSH_ERR
+                | ibv_wc_status::IBV_WC_RETRY_EXC_ERR
+                | ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR
+                | ibv_wc_status::IBV_WC_RESP_TIMEOUT_ERR
+                | ibv_wc_status::IBV_WC_REM_ABORT_ERR
+        )
+    }
+
+    /// Returns `true` if posting the work request again will fail the same way.
+    pub fn is_fatal(&self) -> bool {
+        !self.is_retryable()
+    }
+
+    /// Returns `true` if the work request did not fail itself, but was flushed because its
+    /// `QueuePair` entered the error state.
+    pub fn is_flushed(&self) -> bool {
+        self.status == ibv_wc_status::IBV_WC_WR_FLUSH_ERR
+    }
+}
+
+impl fmt::Display for WcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = unsafe { CStr::from_ptr(ffi::ibv_wc_status_str(self.status)) };
+        write!(
+            f,
+            "work request {} on qp {:#x} failed: {} (vendor error {:#x})",
+            self.wr_id,
+            self.qp_num,
+            status.to_string_lossy(),
+            self.vendor_err
+        )
+    }
+}
+
+impl std::error::Error for WcError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failed_completion_is_classified() {
+        // the default work completion has `IBV_WC_GENERAL_ERR` status
+        let wc = WorkCompletion::from(&ibv_wc::default());
+        assert!(!wc.is_success());
+        assert_eq!(wc.wr_id(), 0);
+        let e = wc.into_result().unwrap_err();
+        assert_eq!(e.status, ibv_wc_status::IBV_WC_GENERAL_ERR);
+        assert!(e.is_fatal());
+        assert!(!e.is_flushed());
+
+        let e = WcError {
+            status: ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR,
+            ..e
+        };
+        assert!(e.is_retryable());
+    }
+}
@@ -0,0 +1,374 @@
+//! The error type returned by the verbs of this crate.
+
+use std::{fmt, io};
+
+/// Describes the resource that a failed verb operated on.
+///
+/// All fields are optional, since not every verb operates on every kind of resource.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The number of the `QueuePair` the verb operated on.
+    pub qp_num: Option<u32>,
+    /// The state a `QueuePair` was being moved to.
+    pub qp_state: Option<ffi::ibv_qp_state>,
+    /// The `id` of the `CompletionQueue` the verb operated on.
+    pub cq_id: Option<isize>,
+    /// The port the verb operated on.
+    pub port_num: Option<u8>,
+}
+
+/// An error returned by this crate.
+///
+/// `Error` converts into `io::Error`, so it can be propagated with `?` from functions that return
+/// `io::Result`. The `io::ErrorKind` of the converted error is the same as `Error::kind`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A verb failed.
+    Verb {
+        /// The name of the verb that failed, e.g. `ibv_modify_qp`.
+        verb: &'static str,
+        /// The error number reported by the verb, if it reported one.
+        errno: Option<i32>,
+        /// The resource the verb operated on.
+        context: ErrorContext,
+    },
+    /// A port is not in `ACTIVE` or `ARMED` state, and can therefore not be used.
+    PortNotActive {
+        /// The number of the port.
+        port_num: u8,
+        /// The state the port is in.
+        state: ffi::ibv_port_state,
+    },
+    /// The device does not have any ports.
+    NoPorts,
+    /// The remote `QueuePairEndpoint` has a GID, but no local GID index was set with
+    /// `QueuePairBuilder::set_gid_index`.
+    MissingGidIndex,
+    /// A blocking wait timed out.
+    TimedOut,
+    /// A resource could not be closed, because it is still used by other handles or resources,
+    /// e.g. a `ProtectionDomain` by its `MemoryRegion`s.
+    InUse,
+    /// A `CompletionQueue` that was created without a completion channel was asked to notify
+    /// about completions.
+    NoCompletionChannel,
+    /// Posting a work request could overrun a `CompletionQueue` that has credit accounting
+    /// enabled (see `CompletionQueueBuilder::set_credit_accounting`).
+    ///
+    /// The request was not posted. It can be posted again once completions were polled from the
+    /// CQ.
+    CompletionQueueFull {
+        /// The `id` of the `CompletionQueue`.
+        cq_id: isize,
+    },
+    /// A `RequestToken` was passed to a `CompletionRouter` that did not issue it.
+    ForeignRequestToken,
+    /// The payload of an inline send is larger than the `QueuePair` can carry inline (see
+    /// `QueuePairBuilder::set_max_inline_data`).
+    InlineTooLarge {
+        /// The length of the payload.
+        len: usize,
+        /// The maximum inline payload the `QueuePair` was created with.
+        max_inline_data: u32,
+    },
+    /// Any other I/O error, e.g. while waiting on a file descriptor.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Creates an error for `verb`, which failed with `errno`.
+    pub(crate) fn verb(verb: &'static str, errno: i32) -> Self {
+        Error::Verb {
+            verb,
+            errno: Some(errno),
+            context: ErrorContext::default(),
+        }
+    }
+
+    /// Creates an error for `verb`, which failed without reporting an error number.
+    pub(crate) fn verb_without_errno(verb: &'static str) -> Self {
+        Error::Verb {
+            verb,
+            errno: None,
+            context: ErrorContext::default(),
+        }
+    }
+
+    /// Creates an error for `verb`, which reported its error number through `errno`.
+    pub(crate) fn last_os_error(verb: &'static str) -> Self {
+        Error::Verb {
+            verb,
+            errno: io::Error::last_os_error().raw_os_error(),
+            context: ErrorContext::default(),
+        }
+    }
+
+    fn map_context(mut self, f: impl FnOnce(&mut ErrorContext)) -> Self {
+        if let Error::Verb { context, .. } = &mut self {
+            f(context);
+        }
+        self
+    }
+
+    pub(crate) fn with_qp(self, qp_num: u32) -> Self {
+        self.map_context(|c| c.qp_num = Some(qp_num))
+    }
+
+    pub(crate) fn with_qp_state(self, qp_state: ffi::ibv_qp_state) -> Self {
+        self.map_context(|c| c.qp_state = Some(qp_state))
+    }
+
+    pub(crate) fn with_cq(self, cq_id: isize) -> Self {
+        self.map_context(|c| c.cq_id = Some(cq_id))
+    }
+
+    pub(crate) fn with_port(self, port_num: u8) -> Self {
+        self.map_context(|c| c.port_num = Some(port_num))
+    }
+
+    /// Returns the error number reported by the failed verb or system call, if any.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Verb { errno, .. } => *errno,
+            Error::Io(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Returns the resource that the failed verb operated on, if the error came from a verb.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::Verb { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Returns the corresponding `io::ErrorKind` for this error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Verb {
+                errno: Some(errno), ..
+            } => io::Error::from_raw_os_error(*errno).kind(),
+            Error::Verb { errno: None, .. } => io::ErrorKind::Other,
+            Error::PortNotActive { .. } => io::ErrorKind::NotConnected,
+            Error::NoPorts => io::ErrorKind::NotFound,
+            Error::MissingGidIndex => io::ErrorKind::InvalidInput,
+            Error::TimedOut => io::ErrorKind::TimedOut,
+            Error::InUse => io::Error::from_raw_os_error(nix::libc::EBUSY).kind(),
+            Error::NoCompletionChannel => io::ErrorKind::InvalidInput,
+            Error::CompletionQueueFull { .. } => io::ErrorKind::WouldBlock,
+            Error::ForeignRequestToken => io::ErrorKind::InvalidInput,
+            Error::InlineTooLarge { .. } => io::ErrorKind::InvalidInput,
+            Error::Io(e) => e.kind(),
+        }
+    }
+
+    /// Returns `true` if the error was caused by a port that is not `ACTIVE` or `ARMED`.
+    pub fn is_port_down(&self) -> bool {
+        matches!(self, Error::PortNotActive { .. })
+    }
+
+    /// Returns `true` if a verb failed because the device or a queue ran out of resources, e.g.
+    /// because a send queue is full. Such operations may succeed when retried later.
+    pub fn is_out_of_resources(&self) -> bool {
+        matches!(
+            self,
+            Error::Verb {
+                errno: Some(nix::libc::ENOMEM | nix::libc::ENOSPC),
+                ..
+            }
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Verb {
+                verb,
+                errno,
+                context,
+            } => {
+                write!(f, "{verb} failed")?;
+                if let Some(qp_num) = context.qp_num {
+                    write!(f, " for qp {qp_num:#x}")?;
+                }
+                if let Some(qp_state) = context.qp_state {
+                    write!(f, " moving to {qp_state:?}")?;
+                }
+                if let Some(cq_id) = context.cq_id {
+                    write!(f, " for cq {cq_id}")?;
+                }
+                if let Some(port_num) = context.port_num {
+                    write!(f, " on port {port_num}")?;
+                }
+                if let Some(errno) = errno {
+                    write!(f, ": {}", io::Error::from_raw_os_error(*errno))?;
+                }
+                Ok(())
+            }
+            Error::PortNotActive { port_num, state } => {
+                write!(
+                    f,
+                    "port {port_num} is not ACTIVE or ARMED (state: {state:?})"
+                )
+            }
+            Error::NoPorts => write!(f, "device has no ports"),
+            Error::MissingGidIndex => write!(f, "gid was set for remote but not local"),
+            Error::TimedOut => write!(f, "timed out"),
+            Error::InUse => write!(f, "resource is still in use"),
+            Error::NoCompletionChannel => write!(f, "completion queue has no completion channel"),
+            Error::CompletionQueueFull { cq_id } => write!(f, "completion queue {cq_id} is full"),
+            Error::ForeignRequestToken => {
+                write!(f, "request token was issued by another completion router")
+            }
+            Error::InlineTooLarge {
+                len,
+                max_inline_data,
+            } => write!(
+                f,
+                "inline payload of {len} bytes exceeds the maximum of {max_inline_data} bytes"
+            ),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<nix::errno::Errno> for Error {
+    fn from(e: nix::errno::Errno) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+/// An error from a call that consumed a resource, e.g. a `QueuePair` changing its state, which
+/// hands the resource back as it was before the call.
+///
+/// Converts into `Error`, dropping the resource, so it can be propagated with `?` when the
+/// resource is of no further use.
+pub struct ResourceError<T> {
+    resource: Box<T>,
+    error: Error,
+}
+
+impl<T> ResourceError<T> {
+    pub(crate) fn new(resource: T, error: Error) -> Self {
+        ResourceError {
+            resource: Box::new(resource),
+            error,
+        }
+    }
+
+    /// Returns the reason the call failed.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Returns the resource that was passed in.
+    pub fn into_inner(self) -> T {
+        *self.resource
+    }
+
+    /// Returns the resource that was passed in, and the reason the call failed.
+    pub fn into_parts(self) -> (T, Error) {
+        (*self.resource, self.error)
+    }
+}
+
+impl<T> fmt::Debug for ResourceError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResourceError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for ResourceError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<T> std::error::Error for ResourceError<T> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<T> From<ResourceError<T>> for Error {
+    fn from(e: ResourceError<T>) -> Self {
+        e.error
+    }
+}
+
+impl<T> From<ResourceError<T>> for io::Error {
+    fn from(e: ResourceError<T>) -> Self {
+        e.error.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn into_io_error_keeps_kind() {
+        let e = Error::verb("ibv_post_send", nix::libc::ENOMEM).with_qp(0x42);
+        assert!(e.is_out_of_resources());
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "ibv_post_send failed for qp 0x42: {}",
+                io::Error::from_raw_os_error(nix::libc::ENOMEM)
+            )
+        );
+
+        let e = io::Error::from(e);
+        assert_eq!(e.kind(), io::ErrorKind::OutOfMemory);
+        let inner = e.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert_eq!(inner.context().unwrap().qp_num, Some(0x42));
+
+        let e = Error::PortNotActive {
+            port_num: 2,
+            state: ffi::ibv_port_state::IBV_PORT_DOWN,
+        };
+        assert!(e.is_port_down());
+        assert!(!e.is_out_of_resources());
+    }
+
+    #[test]
+    fn resource_errors_hand_back_the_resource() {
+        let e = ResourceError::new(vec![1, 2], Error::verb("ibv_modify_qp", nix::libc::EINVAL));
+        assert_eq!(e.to_string(), e.error().to_string());
+        let (resource, error) = e.into_parts();
+        assert_eq!(resource, [1, 2]);
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let e = ResourceError::new((), Error::InUse);
+        assert!(matches!(Error::from(e), Error::InUse));
+    }
+}
@@ -64,33 +64,67 @@
 // avoid warnings about RDMAmojo, iWARP, InfiniBand, etc. not being in backticks
 #![allow(clippy::doc_markdown)]
 
+#[cfg(feature = "tokio")]
+mod async_cq;
+mod batch;
+mod completion;
+mod error;
+mod qp_state;
+mod router;
+mod wait;
+
 use bytes::BytesMut;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
 use std::ffi::CStr;
 use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
 use std::mem::ManuallyDrop;
 use std::ops::{Bound, Deref, DerefMut, RangeBounds};
-use std::os::fd::BorrowedFd;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::os::raw::c_void;
-use std::sync::Arc;
-use std::time::Duration;
-use std::{fmt, io, iter, mem, ptr};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::{Duration, Instant};
+use std::{fmt, io, iter, mem, ptr, slice};
 
-const PORT_NUM: u8 = 1;
+/// The port used when no other port is chosen explicitly.
+const DEFAULT_PORT_NUM: u8 = 1;
 
 /// Direct access to low-level libverbs FFI.
+pub use ffi::ibv_atomic_cap;
+pub use ffi::ibv_device_cap_flags;
 pub use ffi::ibv_gid_type;
 pub use ffi::ibv_mtu;
+pub use ffi::ibv_node_type;
+pub use ffi::ibv_odp_transport_cap_bits;
+pub use ffi::ibv_port_state;
+pub use ffi::ibv_qp_state;
 pub use ffi::ibv_qp_type;
+pub use ffi::ibv_transport_type;
 pub use ffi::ibv_wc;
 pub use ffi::ibv_wc_opcode;
 pub use ffi::ibv_wc_status;
 
 /// Access flags for use with `QueuePair` and `MemoryRegion`.
 pub use ffi::ibv_access_flags;
+/// Flags for the send requests posted with `QueuePair::post_send_with_flags` and its siblings.
+pub use ffi::ibv_send_flags;
 use ffi::ibv_sge;
+
+#[cfg(feature = "tokio")]
+pub use async_cq::AsyncCompletionQueue;
+pub use batch::{BatchError, RecvBatch, SendBatch};
+pub use completion::{WcError, WorkCompletion};
+pub use error::{Error, ErrorContext, ResourceError};
+pub use qp_state::{Drained, Init, Receiving, Reset, Rtr, Rts};
+pub use router::{CompletionRouter, RequestToken, RoutedCompletion, TokenError};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+pub use wait::{WaitPhase, WaitStrategy};
+
+/// Default Q_Key of UD `QueuePair`s.
+pub const DEFAULT_QKEY: u32 = 0x1111_1111;
 
 /// Default access flags.
 pub const DEFAULT_ACCESS_FLAGS: ffi::ibv_access_flags = ffi::ibv_access_flags(
@@ -108,12 +142,12 @@ pub const DEFAULT_ACCESS_FLAGS: ffi::ibv_access_flags = ffi::ibv_access_flags(
 ///  - `EPERM`: Permission denied.
 ///  - `ENOMEM`: Insufficient memory to complete the operation.
 ///  - `ENOSYS`: No kernel support for RDMA.
-pub fn devices() -> io::Result<DeviceList> {
+pub fn devices() -> Result<DeviceList, Error> {
     let mut n = 0i32;
     let devices = unsafe { ffi::ibv_get_device_list(&mut n as *mut _) };
 
     if devices.is_null() {
-        return Err(io::Error::last_os_error());
+        return Err(Error::last_os_error("ibv_get_device_list"));
     }
 
     let devices = unsafe {
@@ -155,6 +189,29 @@ impl DeviceList {
     pub fn get(&self, index: usize) -> Option<Device<'_>> {
         self.0.get(index).map(|d| d.into())
     }
+
+    /// Returns the device with the given name (e.g. `mlx5_0`), or `None` if there is none.
+    ///
+    /// See `Device::name`.
+    pub fn find_by_name(&self, name: &str) -> Option<Device<'_>> {
+        self.iter()
+            .find(|d| d.name().is_some_and(|n| n.to_bytes() == name.as_bytes()))
+    }
+
+    /// Returns the device with the given GUID, or `None` if there is none.
+    ///
+    /// See `Device::guid`.
+    pub fn find_by_guid(&self, guid: Guid) -> Option<Device<'_>> {
+        self.iter().find(|d| d.guid().is_ok_and(|g| g == guid))
+    }
+
+    /// Returns the device with the given stable kernel index, or `None` if there is none.
+    ///
+    /// Note that this is the index assigned by the kernel, which is unrelated to the position of
+    /// the device in this list as used by `DeviceList::get`. See `Device::index`.
+    pub fn find_by_index(&self, index: i32) -> Option<Device<'_>> {
+        self.iter().find(|d| d.index().is_ok_and(|i| i == index))
+    }
 }
 
 impl<'a> IntoIterator for &'a DeviceList {
@@ -249,14 +306,42 @@ impl<'devlist> Device<'devlist> {
     /// opened by the kernel low-level driver and may be used by other user/kernel level code. This
     /// verb only opens a context to allow user level applications to use it.
     ///
+    /// This is equivalent to `open_with_options(&OpenOptions::new())`, which checks that at least
+    /// one port of the device is in `ACTIVE` or `ARMED` state.
+    ///
     /// # Errors
     ///
-    ///  - `EINVAL`: `PORT_NUM` is invalid (from `ibv_query_port_attr`).
+    ///  - `EINVAL`: The device could not be queried (from `ibv_query_device`).
     ///  - `ENOMEM`: Out of memory (from `ibv_query_port_attr`).
     ///  - `EMFILE`: Too many files are opened by this process (from `ibv_query_gid`).
-    ///  - Other: the device is not in `ACTIVE` or `ARMED` state.
-    pub fn open(&self) -> io::Result<Context> {
-        Context::with_device(*self.0)
+    ///  - Other: none of the device's ports is in `ACTIVE` or `ARMED` state.
+    pub fn open(&self) -> Result<Context, Error> {
+        self.open_with_options(&OpenOptions::new())
+    }
+
+    /// Opens an RMDA device with the given options and creates a context for further use.
+    ///
+    /// See `Device::open` and `OpenOptions`.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: The device or the port could not be queried.
+    ///  - `ENOMEM`: Out of memory (from `ibv_query_port_attr`).
+    ///  - Other: the ports required by `options` are not in `ACTIVE` or `ARMED` state.
+    pub fn open_with_options(&self, options: &OpenOptions) -> Result<Context, Error> {
+        Context::with_device(*self.0, options)
+    }
+
+    /// Returns the node type of this RDMA device, e.g. whether it is a channel adapter (CA) or an
+    /// RDMA-enabled NIC (RNIC).
+    pub fn node_type(&self) -> ibv_node_type {
+        unsafe { (**self.0).node_type }
+    }
+
+    /// Returns the transport type of this RDMA device, e.g. InfiniBand (which includes RoCE) or
+    /// iWARP.
+    pub fn transport_type(&self) -> ibv_transport_type {
+        unsafe { (**self.0).transport_type }
     }
 
     /// Returns a string of the name, which is associated with this RDMA device.
@@ -302,11 +387,11 @@ impl<'devlist> Device<'devlist> {
     /// # Errors
     ///
     ///  - `EMFILE`: Too many files are opened by this process.
-    pub fn guid(&self) -> io::Result<Guid> {
+    pub fn guid(&self) -> Result<Guid, Error> {
         let guid_int = unsafe { ffi::ibv_get_device_guid(*self.0) };
         let guid: Guid = guid_int.into();
         if guid.is_reserved() {
-            Err(io::Error::last_os_error())
+            Err(Error::last_os_error("ibv_get_device_guid"))
         } else {
             Ok(guid)
         }
@@ -316,25 +401,258 @@ impl<'devlist> Device<'devlist> {
     /// # Errors
     ///
     ///  - `ENOTSUP`: Stable index is not supported
-    pub fn index(&self) -> io::Result<i32> {
+    pub fn index(&self) -> Result<i32, Error> {
         let idx = unsafe { ffi::ibv_get_device_index(*self.0) };
         if idx == -1 {
-            Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "device index not known",
-            ))
+            Err(Error::verb("ibv_get_device_index", nix::libc::EOPNOTSUPP))
         } else {
             Ok(idx)
         }
     }
 }
 
+/// Looks up an extended verb of a context, like the `verbs_get_ctx_op` macro from `verbs.h`.
+///
+/// Many of the extended verbs are `static inline` functions in `verbs.h`, and are therefore not
+/// part of the generated bindings. They dispatch through the `verbs_context` that surrounds every
+/// `ibv_context` opened by a provider that supports the extended ABI. Evaluates to `None` if the
+/// context or its provider does not implement the operation.
+macro_rules! verbs_get_ctx_op {
+    ($ctx:expr, $op:ident) => {{
+        let ctx: *mut ffi::ibv_context = $ctx;
+        // `__VERBS_ABI_IS_EXTENDED` in verbs.h
+        if unsafe { (*ctx).abi_compat } != usize::MAX as *mut c_void {
+            None
+        } else {
+            let vctx = unsafe {
+                &*ctx
+                    .byte_sub(mem::offset_of!(ffi::verbs_context, context))
+                    .cast::<ffi::verbs_context>()
+            };
+            if vctx.sz
+                < mem::size_of::<ffi::verbs_context>() - mem::offset_of!(ffi::verbs_context, $op)
+            {
+                None
+            } else {
+                vctx.$op
+            }
+        }
+    }};
+}
+
+/// Sets the `O_NONBLOCK` flag on the file descriptor `fd`.
+fn set_nonblocking(fd: RawFd) -> Result<(), Error> {
+    let flags = nix::fcntl::fcntl(fd, nix::fcntl::F_GETFL)?;
+    let arg = nix::fcntl::FcntlArg::F_SETFL(
+        nix::fcntl::OFlag::from_bits_retain(flags) | nix::fcntl::OFlag::O_NONBLOCK,
+    );
+    nix::fcntl::fcntl(fd, arg)?;
+    Ok(())
+}
+
+/// Options for opening an RDMA device with `Device::open_with_options`.
+///
+/// By default, opening a device fails unless at least one of its ports is in `ACTIVE` or `ARMED`
+/// state.
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    port_check: PortCheck,
+    report_leaks: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum PortCheck {
+    AnyPort,
+    Port(u8),
+    Skip,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        OpenOptions {
+            port_check: PortCheck::AnyPort,
+            report_leaks: false,
+        }
+    }
+
+    /// Requires the given port to be in `ACTIVE` or `ARMED` state, instead of any port.
+    pub fn require_port(&mut self, port_num: u8) -> &mut Self {
+        self.port_check = PortCheck::Port(port_num);
+        self
+    }
+
+    /// Sets whether the state of the ports is checked when opening the device.
+    ///
+    /// Disabling the check allows opening devices whose ports are all down, e.g. to inspect them
+    /// with `Context::port_attributes`.
+    ///
+    /// Defaults to `true`.
+    pub fn check_ports(&mut self, check: bool) -> &mut Self {
+        self.port_check = if check {
+            PortCheck::AnyPort
+        } else {
+            PortCheck::Skip
+        };
+        self
+    }
+
+    /// Sets whether a warning is logged when the `Context` is dropped while resources created from
+    /// it are still alive, and when resources were leaked because they could not be destroyed.
+    ///
+    /// The number of live resources can also be inspected with `Context::live_resources`.
+    ///
+    /// Defaults to `false`.
+    pub fn report_leaks(&mut self, report: bool) -> &mut Self {
+        self.report_leaks = report;
+        self
+    }
+}
+
+/// The number of resources of a `Context` that have been created, but not destroyed.
+///
+/// This includes resources that were leaked because destroying them failed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct LiveResources {
+    /// The number of live `ProtectionDomain`s.
+    pub protection_domains: usize,
+    /// The number of live `CompletionQueue`s.
+    pub completion_queues: usize,
+    /// The number of live `QueuePair`s.
+    pub queue_pairs: usize,
+    /// The number of live `MemoryRegion`s.
+    pub memory_regions: usize,
+    /// The number of live `SharedReceiveQueue`s.
+    pub shared_receive_queues: usize,
+    /// The number of live `AddressHandle`s.
+    pub address_handles: usize,
+    /// The number of live `CompletionChannel`s.
+    pub completion_channels: usize,
+}
+
+impl LiveResources {
+    /// Returns `true` if there are no live resources.
+    pub fn is_empty(&self) -> bool {
+        *self == LiveResources::default()
+    }
+}
+
+impl fmt::Display for LiveResources {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} protection domains, {} completion queues, {} queue pairs, {} memory regions, {} \
+             shared receive queues, {} address handles, {} completion channels",
+            self.protection_domains,
+            self.completion_queues,
+            self.queue_pairs,
+            self.memory_regions,
+            self.shared_receive_queues,
+            self.address_handles,
+            self.completion_channels
+        )
+    }
+}
+
+#[derive(Default)]
+struct ResourceCounters {
+    pds: AtomicUsize,
+    cqs: AtomicUsize,
+    qps: AtomicUsize,
+    mrs: AtomicUsize,
+    srqs: AtomicUsize,
+    ahs: AtomicUsize,
+    ccs: AtomicUsize,
+}
+
+impl ResourceCounters {
+    fn created(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn destroyed(counter: &AtomicUsize) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LiveResources {
+        LiveResources {
+            protection_domains: self.pds.load(Ordering::Relaxed),
+            completion_queues: self.cqs.load(Ordering::Relaxed),
+            queue_pairs: self.qps.load(Ordering::Relaxed),
+            memory_regions: self.mrs.load(Ordering::Relaxed),
+            shared_receive_queues: self.srqs.load(Ordering::Relaxed),
+            address_handles: self.ahs.load(Ordering::Relaxed),
+            completion_channels: self.ccs.load(Ordering::Relaxed),
+        }
+    }
+}
+
 struct ContextInner {
     ctx: *mut ffi::ibv_context,
+    live: ResourceCounters,
+    report_leaks: bool,
 }
 
 impl ContextInner {
-    fn query_port(&self) -> io::Result<ffi::ibv_port_attr> {
+    /// Closes the device context, unless that already happened.
+    fn close(&mut self) -> Result<(), Error> {
+        if self.ctx.is_null() {
+            return Ok(());
+        }
+        let errno = unsafe { ffi::ibv_close_device(self.ctx) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_close_device", errno));
+        }
+        self.ctx = ptr::null_mut();
+        Ok(())
+    }
+
+    fn query_device(&self) -> Result<ffi::ibv_device_attr, Error> {
+        let mut device_attr = ffi::ibv_device_attr::default();
+        let errno = unsafe { ffi::ibv_query_device(self.ctx, &mut device_attr as *mut _) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_query_device", errno));
+        }
+        Ok(device_attr)
+    }
+
+    fn query_device_ex(&self) -> Result<ffi::ibv_device_attr_ex, Error> {
+        let mut attr = ffi::ibv_device_attr_ex::default();
+        if let Some(query_device_ex) = verbs_get_ctx_op!(self.ctx, query_device_ex) {
+            let errno = unsafe {
+                query_device_ex(
+                    self.ctx,
+                    ptr::null(),
+                    &mut attr as *mut _,
+                    size_of::<ffi::ibv_device_attr_ex>(),
+                )
+            };
+            match errno {
+                0 => return Ok(attr),
+                // the provider does not implement the extended query
+                nix::libc::EOPNOTSUPP | nix::libc::ENOSYS => {}
+                errno => return Err(Error::verb("ibv_query_device_ex", errno)),
+            }
+        }
+
+        // legacy fallback for providers without the extended query
+        attr = ffi::ibv_device_attr_ex::default();
+        attr.orig_attr = self.query_device()?;
+        Ok(attr)
+    }
+
+    fn ports(&self) -> Result<Vec<u8>, Error> {
+        let phys_port_cnt = self.query_device()?.phys_port_cnt;
+        Ok((1..=phys_port_cnt).collect())
+    }
+
+    fn query_port_attr(&self, port_num: u8) -> Result<ffi::ibv_port_attr, Error> {
         // TODO: from http://www.rdmamojo.com/2012/07/21/ibv_query_port/
         //
         //   Most of the port attributes, returned by ibv_query_port(), aren't constant and may be
@@ -343,16 +661,33 @@ impl ContextInner {
         //   (re)configures the subnet.
         //
         let mut port_attr = ffi::ibv_port_attr::default();
-        let errno = unsafe {
-            ffi::ibv_query_port(
-                self.ctx,
-                PORT_NUM,
-                &mut port_attr as *mut ffi::ibv_port_attr as *mut _,
-            )
+        // the exported `ibv_query_port` only fills in the fields of the legacy port attributes
+        let errno = if let Some(query_port) = verbs_get_ctx_op!(self.ctx, query_port) {
+            unsafe {
+                query_port(
+                    self.ctx,
+                    port_num,
+                    &mut port_attr as *mut _,
+                    size_of::<ffi::ibv_port_attr>(),
+                )
+            }
+        } else {
+            unsafe {
+                ffi::ibv_query_port(
+                    self.ctx,
+                    port_num,
+                    &mut port_attr as *mut ffi::ibv_port_attr as *mut _,
+                )
+            }
         };
         if errno != 0 {
-            return Err(io::Error::from_raw_os_error(errno));
+            return Err(Error::verb("ibv_query_port", errno).with_port(port_num));
         }
+        Ok(port_attr)
+    }
+
+    fn query_port(&self, port_num: u8) -> Result<ffi::ibv_port_attr, Error> {
+        let port_attr = self.query_port_attr(port_num)?;
 
         // From http://www.rdmamojo.com/2012/08/02/ibv_query_gid/:
         //
@@ -362,8 +697,8 @@ impl ContextInner {
         //
         match port_attr.state {
             ffi::ibv_port_state::IBV_PORT_ACTIVE | ffi::ibv_port_state::IBV_PORT_ARMED => {}
-            _ => {
-                return Err(io::Error::other("port is not ACTIVE or ARMED"));
+            state => {
+                return Err(Error::PortNotActive { port_num, state });
             }
         }
         Ok(port_attr)
@@ -372,8 +707,14 @@ impl ContextInner {
 
 impl Drop for ContextInner {
     fn drop(&mut self) {
-        let ok = unsafe { ffi::ibv_close_device(self.ctx) };
-        assert_eq!(ok, 0);
+        // all resources hold on to the context, so any that are still counted were leaked
+        let live = self.live.snapshot();
+        if self.report_leaks && !live.is_empty() {
+            log::warn!("closing device context with leaked resources: {live}");
+        }
+        if let Err(e) = self.close() {
+            log::error!("{e}; leaking the device context");
+        }
     }
 }
 
@@ -386,20 +727,154 @@ pub struct Context {
 }
 
 impl Context {
-    /// Opens a context for the given device, and queries its port and gid.
-    fn with_device(dev: *mut ffi::ibv_device) -> io::Result<Context> {
+    /// Opens a context for the given device, and checks its ports as requested by `options`.
+    fn with_device(dev: *mut ffi::ibv_device, options: &OpenOptions) -> Result<Context, Error> {
         assert!(!dev.is_null());
 
         let ctx = unsafe { ffi::ibv_open_device(dev) };
         if ctx.is_null() {
-            return Err(io::Error::other("failed to open device"));
+            return Err(Error::last_os_error("ibv_open_device"));
         }
-        let inner = Arc::new(ContextInner { ctx });
+        let inner = Arc::new(ContextInner {
+            ctx,
+            live: ResourceCounters::default(),
+            report_leaks: options.report_leaks,
+        });
 
         let ctx = Context { inner };
-        // checks that the port is active/armed.
-        ctx.inner.query_port()?;
-        Ok(ctx)
+        match options.port_check {
+            PortCheck::AnyPort => {
+                // checks that at least one port is active/armed.
+                let mut last_err = None;
+                for port_num in ctx.inner.ports()? {
+                    match ctx.inner.query_port(port_num) {
+                        Ok(_) => return Ok(ctx),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err.unwrap_or(Error::NoPorts))
+            }
+            PortCheck::Port(port_num) => {
+                ctx.inner.query_port(port_num)?;
+                Ok(ctx)
+            }
+            PortCheck::Skip => Ok(ctx),
+        }
+    }
+
+    /// Queries the capabilities and limits of the device.
+    ///
+    /// The returned limits are the upper bounds for, among others, `create_cq`'s
+    /// `min_cq_entries` (`max_cqe`), `QueuePairBuilder::set_max_send_wr` (`max_qp_wr`) and
+    /// `QueuePairBuilder::set_max_send_sge` (`max_sge`). Note that some devices support less than
+    /// the reported maximum for specific transport types.
+    ///
+    /// Uses `ibv_query_device_ex` if the device supports it, and falls back to `ibv_query_device`
+    /// otherwise. In the latter case, the extended attributes (ODP capabilities, clock and CQ
+    /// moderation limits) are reported as zero.
+    ///
+    /// See also [RDMAmojo's `ibv_query_device` documentation][1].
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: The device could not be queried.
+    ///  - `ENOMEM`: Out of memory.
+    ///
+    /// [1]: http://www.rdmamojo.com/2012/07/13/ibv_query_device/
+    pub fn query_device(&self) -> Result<DeviceAttributes, Error> {
+        self.inner.query_device_ex().map(DeviceAttributes::from)
+    }
+
+    /// Queries the attributes of the physical port `port_num`.
+    ///
+    /// Unlike building a `QueuePair`, this also succeeds for ports that are not `ACTIVE` or
+    /// `ARMED`, so it can be used to find out why a port is unusable. Most of the attributes are
+    /// not constant, and may be changed by the subnet manager or the hardware at any time.
+    ///
+    /// See also [RDMAmojo's `ibv_query_port` documentation][1].
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `port_num` is invalid.
+    ///  - `ENOMEM`: Out of memory.
+    ///
+    /// [1]: http://www.rdmamojo.com/2012/07/21/ibv_query_port/
+    pub fn port_attributes(&self, port_num: u8) -> Result<PortAttributes, Error> {
+        self.inner
+            .query_port_attr(port_num)
+            .map(PortAttributes::from)
+    }
+
+    /// Returns a handle for reading the asynchronous events of this device.
+    ///
+    /// Asynchronous events report errors and state changes that are not tied to a work request,
+    /// such as a `QueuePair` moving to the error state, a `CompletionQueue` overrun, or a port
+    /// going up or down. Every event is acknowledged as soon as it has been read.
+    ///
+    /// All handles of a context read from the same event queue, so each event is only returned
+    /// to one of them.
+    ///
+    /// See also [RDMAmojo's `ibv_get_async_event` documentation][1].
+    ///
+    /// [1]: http://www.rdmamojo.com/2012/08/11/ibv_get_async_event/
+    pub fn async_events(&self) -> Result<AsyncEvents, Error> {
+        // the file descriptor needs to be set to non-blocking because `ibv_get_async_event()`
+        // would block otherwise.
+        set_nonblocking(unsafe { *self.inner.ctx }.async_fd)?;
+
+        Ok(AsyncEvents {
+            ctx: self.inner.clone(),
+        })
+    }
+
+    /// Samples the HCA clock, for converting the completion timestamps of
+    /// `ExtendedCompletionQueue`s.
+    ///
+    /// # Errors
+    ///
+    ///  - `EOPNOTSUPP`: The device does not report its clock frequency, or does not support
+    ///    reading its clock with `ibv_query_rt_values_ex`.
+    pub fn hca_clock(&self) -> Result<HcaClock, Error> {
+        let attr = self.inner.query_device_ex()?;
+        if attr.hca_core_clock == 0 {
+            return Err(Error::verb("ibv_query_device_ex", nix::libc::EOPNOTSUPP));
+        }
+        let Some(query_rt_values) = verbs_get_ctx_op!(self.inner.ctx, query_rt_values) else {
+            return Err(Error::verb("ibv_query_rt_values_ex", nix::libc::EOPNOTSUPP));
+        };
+
+        let mut values = ffi::ibv_values_ex {
+            comp_mask: ffi::ibv_values_mask::IBV_VALUES_MASK_RAW_CLOCK as u32,
+            ..Default::default()
+        };
+        let errno = unsafe { query_rt_values(self.inner.ctx, &mut values as *mut _) };
+        let reference = Instant::now();
+        if errno != 0 {
+            return Err(Error::verb("ibv_query_rt_values_ex", errno));
+        }
+
+        // providers report the raw clock in ticks, spread over the fields of the timespec
+        let ticks = (values.raw_clock.tv_sec as u64)
+            .wrapping_mul(1_000_000_000)
+            .wrapping_add(values.raw_clock.tv_nsec as u64);
+        Ok(HcaClock {
+            khz: attr.hca_core_clock,
+            mask: match attr.completion_timestamp_mask {
+                0 => u64::MAX,
+                mask => mask,
+            },
+            ticks,
+            reference,
+        })
+    }
+
+    /// Returns the numbers of the physical ports of this device.
+    ///
+    /// Ports are numbered starting at 1. Any of them can be passed to
+    /// `QueuePairBuilder::set_port`, although only ports in `ACTIVE` or `ARMED` state can be used
+    /// to build a `QueuePair`.
+    pub fn ports(&self) -> Result<Vec<u8>, Error> {
+        self.inner.ports()
     }
 
     /// Create a completion queue (CQ).
@@ -414,49 +889,54 @@ impl Context {
     /// that it came from.
     ///
     /// `min_cq_entries` defines the minimum size of the CQ. The actual created size can be equal
-    /// or higher than this value. `id` is an opaque identifier that is echoed by
-    /// `CompletionQueue::poll`.
+    /// or higher than this value. `id` is an opaque identifier that is returned by
+    /// `CompletionQueue::id`.
+    ///
+    /// The CQ gets its own completion channel and uses completion vector 0. Use
+    /// `Context::cq_builder` to configure these.
     ///
     /// # Errors
     ///
     ///  - `EINVAL`: Invalid `min_cq_entries` (must be `1 <= cqe <= dev_cap.max_cqe`).
     ///  - `ENOMEM`: Not enough resources to complete this operation.
-    pub fn create_cq(&self, min_cq_entries: i32, id: isize) -> io::Result<CompletionQueue> {
-        let cc = unsafe { ffi::ibv_create_comp_channel(self.inner.ctx) };
-        if cc.is_null() {
-            return Err(io::Error::last_os_error());
-        }
+    pub fn create_cq(&self, min_cq_entries: i32, id: isize) -> Result<CompletionQueue, Error> {
+        self.cq_builder(min_cq_entries).set_id(id).build()
+    }
 
-        let cc_fd = unsafe { *cc }.fd;
-        let flags = nix::fcntl::fcntl(cc_fd, nix::fcntl::F_GETFL)?;
-        // the file descriptor needs to be set to non-blocking because `ibv_get_cq_event()`
-        // would block otherwise.
-        let arg = nix::fcntl::FcntlArg::F_SETFL(
-            nix::fcntl::OFlag::from_bits_retain(flags) | nix::fcntl::OFlag::O_NONBLOCK,
-        );
-        nix::fcntl::fcntl(cc_fd, arg)?;
+    /// Creates a builder for a completion queue (CQ) with at least `min_cq_entries` entries.
+    ///
+    /// See `Context::create_cq` for details on CQs.
+    pub fn cq_builder(&self, min_cq_entries: i32) -> CompletionQueueBuilder {
+        CompletionQueueBuilder {
+            ctx: self.inner.clone(),
+            min_cq_entries,
+            id: 0,
+            comp_vector: 0,
+            channel: ChannelMode::Own,
+            credit_accounting: false,
+            wc_flags: STANDARD_WC_FLAGS,
+        }
+    }
 
-        let cq = unsafe {
-            ffi::ibv_create_cq(
-                self.inner.ctx,
-                min_cq_entries,
-                ptr::null::<c_void>().offset(id) as *mut _,
-                cc,
-                0,
-            )
-        };
+    /// Returns the number of completion vectors of this device.
+    ///
+    /// Completion vectors are usually backed by separate interrupts, so spreading the CQs over
+    /// them (see `CompletionQueueBuilder::set_comp_vector`) spreads the completion events over
+    /// several cores.
+    pub fn num_comp_vectors(&self) -> u32 {
+        unsafe { *self.inner.ctx }.num_comp_vectors as u32
+    }
 
-        if cq.is_null() {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(CompletionQueue {
-                inner: Arc::new(CompletionQueueInner {
-                    _ctx: self.inner.clone(),
-                    cc,
-                    cq,
-                }),
-            })
-        }
+    /// Creates a completion channel, which can be shared by several `CompletionQueue`s through
+    /// `CompletionQueueBuilder::set_channel`.
+    ///
+    /// # Errors
+    ///
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn create_comp_channel(&self) -> Result<CompletionChannel, Error> {
+        Ok(CompletionChannel {
+            inner: Arc::new(CompletionChannelInner::new(self.inner.clone())?),
+        })
     }
 
     /// Allocate a protection domain (PDs) for the device's context.
@@ -466,11 +946,12 @@ impl Context {
     /// A protection domain is a means of protection, and helps you create a group of object that
     /// can work together. If several objects were created using PD1, and others were created using
     /// PD2, working with objects from group1 together with objects from group2 will not work.
-    pub fn alloc_pd(&self) -> io::Result<ProtectionDomain> {
+    pub fn alloc_pd(&self) -> Result<ProtectionDomain, Error> {
         let pd = unsafe { ffi::ibv_alloc_pd(self.inner.ctx) };
         if pd.is_null() {
-            Err(io::Error::other("obv_alloc_pd returned null"))
+            Err(Error::last_os_error("ibv_alloc_pd"))
         } else {
+            ResourceCounters::created(&self.inner.live.pds);
             Ok(ProtectionDomain {
                 inner: Arc::new(ProtectionDomainInner {
                     ctx: self.inner.clone(),
@@ -481,8 +962,13 @@ impl Context {
     }
 
     /// Returns the valid GID table entries of this RDMA device context.
-    pub fn gid_table(&self) -> io::Result<Vec<GidEntry>> {
-        let max_entries = self.inner.query_port()?.gid_tbl_len as usize;
+    ///
+    /// The table covers all ports of the device; use `GidEntry::port_num` to tell them apart.
+    pub fn gid_table(&self) -> Result<Vec<GidEntry>, Error> {
+        let mut max_entries = 0;
+        for port_num in self.inner.ports()? {
+            max_entries += self.inner.query_port_attr(port_num)?.gid_tbl_len as usize;
+        }
         let mut gid_table = vec![ffi::ibv_gid_entry::default(); max_entries];
         let num_entries = unsafe {
             ffi::_ibv_query_gid_table(
@@ -494,1334 +980,4488 @@ impl Context {
             )
         };
         if num_entries < 0 {
-            return Err(io::Error::other(format!(
-                "failed to query gid table, error={}",
-                -num_entries
-            )));
+            return Err(Error::verb("ibv_query_gid_table", -num_entries as i32));
         }
         gid_table.truncate(num_entries as usize);
         let gid_table = gid_table.into_iter().map(GidEntry::from).collect();
         Ok(gid_table)
     }
-}
 
-struct CompletionQueueInner {
-    _ctx: Arc<ContextInner>,
-    cq: *mut ffi::ibv_cq,
-    cc: *mut ffi::ibv_comp_channel,
+    /// Returns the index of the partition key `pkey` in the P_Key table of port `port_num`.
+    ///
+    /// The index is what `QueuePairBuilder::set_pkey_index` expects. Only needed on fabrics with
+    /// partitions other than the default one, whose P_Key is `0xffff` at index 0.
+    ///
+    /// # Errors
+    ///
+    ///  - `ENOENT`: `pkey` is not in the P_Key table of the port.
+    ///  - `EINVAL`: Invalid `port_num`.
+    pub fn pkey_index(&self, port_num: u8, pkey: u16) -> Result<u16, Error> {
+        let index = unsafe { ffi::ibv_get_pkey_index(self.inner.ctx, port_num, pkey.to_be()) };
+        if index < 0 {
+            return Err(Error::last_os_error("ibv_get_pkey_index").with_port(port_num));
+        }
+        Ok(index as u16)
+    }
+
+    /// Returns the number of resources created from this context that are still alive.
+    pub fn live_resources(&self) -> LiveResources {
+        self.inner.live.snapshot()
+    }
+
+    /// Closes the device context.
+    ///
+    /// Unlike dropping the `Context`, this reports whether closing succeeded.
+    ///
+    /// # Errors
+    ///
+    /// The `Context` is handed back in the `ResourceError`, so that closing can be retried,
+    /// together with:
+    ///
+    ///  - `Error::InUse`: Resources created from this context are still alive. Dropping the
+    ///    `Context` instead closes it when the last of them is dropped.
+    ///  - System errors: From `ibv_close_device`.
+    pub fn close(self) -> Result<(), ResourceError<Self>> {
+        // `Context` implements `Drop`, so `inner` can't be moved out of it directly
+        let this = ManuallyDrop::new(self);
+        let inner = unsafe { ptr::read(&this.inner) };
+        match Arc::try_unwrap(inner) {
+            Ok(mut inner) => inner.close().map_err(|e| {
+                let inner = Arc::new(inner);
+                ResourceError::new(Context { inner }, e)
+            }),
+            Err(inner) => Err(ResourceError::new(Context { inner }, Error::InUse)),
+        }
+    }
 }
 
-impl Drop for CompletionQueueInner {
+impl Drop for Context {
     fn drop(&mut self) {
-        let errno = unsafe { ffi::ibv_destroy_cq(self.cq) };
-        if errno != 0 {
-            let e = io::Error::from_raw_os_error(errno);
-            panic!("destroy_cq failed: {e}");
+        // other handles to the context, e.g. held by `AsyncEvents`, are not resources
+        let live = self.inner.live.snapshot();
+        if self.inner.report_leaks && !live.is_empty() {
+            log::warn!("device context dropped while resources are still alive: {live}");
         }
+    }
+}
 
-        let errno = unsafe { ffi::ibv_destroy_comp_channel(self.cc) };
-        if errno != 0 {
-            let e = io::Error::from_raw_os_error(errno);
-            panic!("destroy_comp_channel failed: {e}");
+/// The capabilities and limits of an RDMA device, as returned by `Context::query_device`.
+#[derive(Debug, Clone)]
+pub struct DeviceAttributes {
+    /// The firmware version of the device.
+    pub fw_ver: String,
+    /// The GUID of the device, as also returned by `Device::guid`.
+    pub node_guid: Guid,
+    /// The system image GUID of the device.
+    pub sys_image_guid: Guid,
+    /// The largest contiguous block of memory that can be registered.
+    pub max_mr_size: u64,
+    /// The supported memory page sizes, as a bitmask.
+    pub page_size_cap: u64,
+    /// The vendor ID, per IEEE.
+    pub vendor_id: u32,
+    /// The vendor-supplied part ID.
+    pub vendor_part_id: u32,
+    /// The hardware version.
+    pub hw_ver: u32,
+    /// The maximum number of queue pairs.
+    pub max_qp: u32,
+    /// The maximum number of outstanding work requests on any send or receive queue.
+    pub max_qp_wr: u32,
+    /// The capabilities supported by the device.
+    pub device_cap_flags: ibv_device_cap_flags,
+    /// The maximum number of scatter/gather elements per work request for non-RDMA-read
+    /// operations.
+    pub max_sge: u32,
+    /// The maximum number of scatter/gather elements per RDMA read work request.
+    pub max_sge_rd: u32,
+    /// The maximum number of completion queues.
+    pub max_cq: u32,
+    /// The maximum number of entries of a completion queue.
+    pub max_cqe: u32,
+    /// The maximum number of memory regions.
+    pub max_mr: u32,
+    /// The maximum number of protection domains.
+    pub max_pd: u32,
+    /// The maximum number of RDMA reads & atomic operations that can be outstanding per queue pair
+    /// as the target.
+    pub max_qp_rd_atom: u32,
+    /// The maximum number of RDMA reads & atomic operations that can be outstanding per queue pair
+    /// as the initiator.
+    pub max_qp_init_rd_atom: u32,
+    /// The level of support for atomic operations.
+    pub atomic_cap: ibv_atomic_cap,
+    /// The maximum number of shared receive queues.
+    pub max_srq: u32,
+    /// The maximum number of outstanding work requests on any shared receive queue.
+    pub max_srq_wr: u32,
+    /// The maximum number of scatter/gather elements per shared receive queue work request.
+    pub max_srq_sge: u32,
+    /// The size of the P_Key table of each port.
+    pub max_pkeys: u16,
+    /// The number of physical ports.
+    pub phys_port_cnt: u8,
+    /// The On-Demand Paging capabilities.
+    pub odp_caps: OdpCapabilities,
+    /// The bits of the completion timestamp that the device implements.
+    pub completion_timestamp_mask: u64,
+    /// The frequency of the device clock in kHz, used for completion timestamps.
+    pub hca_core_clock: u64,
+    /// The maximum number of completions that can be coalesced into one completion event.
+    pub max_cq_moderation_count: u16,
+    /// The maximum completion event coalescing period, in microseconds.
+    pub max_cq_moderation_period: u16,
+}
+
+impl From<ffi::ibv_device_attr_ex> for DeviceAttributes {
+    fn from(attr: ffi::ibv_device_attr_ex) -> Self {
+        let orig = &attr.orig_attr;
+        Self {
+            fw_ver: unsafe { CStr::from_ptr(orig.fw_ver.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+            node_guid: orig.node_guid.into(),
+            sys_image_guid: orig.sys_image_guid.into(),
+            max_mr_size: orig.max_mr_size,
+            page_size_cap: orig.page_size_cap,
+            vendor_id: orig.vendor_id,
+            vendor_part_id: orig.vendor_part_id,
+            hw_ver: orig.hw_ver,
+            max_qp: orig.max_qp as u32,
+            max_qp_wr: orig.max_qp_wr as u32,
+            device_cap_flags: ibv_device_cap_flags(orig.device_cap_flags),
+            max_sge: orig.max_sge as u32,
+            max_sge_rd: orig.max_sge_rd as u32,
+            max_cq: orig.max_cq as u32,
+            max_cqe: orig.max_cqe as u32,
+            max_mr: orig.max_mr as u32,
+            max_pd: orig.max_pd as u32,
+            max_qp_rd_atom: orig.max_qp_rd_atom as u32,
+            max_qp_init_rd_atom: orig.max_qp_init_rd_atom as u32,
+            atomic_cap: orig.atomic_cap,
+            max_srq: orig.max_srq as u32,
+            max_srq_wr: orig.max_srq_wr as u32,
+            max_srq_sge: orig.max_srq_sge as u32,
+            max_pkeys: orig.max_pkeys,
+            phys_port_cnt: orig.phys_port_cnt,
+            odp_caps: attr.odp_caps.into(),
+            completion_timestamp_mask: attr.completion_timestamp_mask,
+            hca_core_clock: attr.hca_core_clock,
+            max_cq_moderation_count: attr.cq_mod_caps.max_cq_count,
+            max_cq_moderation_period: attr.cq_mod_caps.max_cq_period,
         }
     }
 }
 
-unsafe impl Send for CompletionQueueInner {}
-unsafe impl Sync for CompletionQueueInner {}
+/// The On-Demand Paging (ODP) capabilities of an RDMA device.
+///
+/// With ODP, memory regions can be registered without pinning their pages, which are instead
+/// faulted in by the device when they are accessed.
+#[derive(Debug, Copy, Clone)]
+pub struct OdpCapabilities {
+    /// Whether the device supports On-Demand Paging at all.
+    pub supported: bool,
+    /// Whether the device supports implicit On-Demand Paging (registering the whole address
+    /// space).
+    pub implicit: bool,
+    /// The operations that support On-Demand Paging on RC queue pairs.
+    pub rc: ibv_odp_transport_cap_bits,
+    /// The operations that support On-Demand Paging on UC queue pairs.
+    pub uc: ibv_odp_transport_cap_bits,
+    /// The operations that support On-Demand Paging on UD queue pairs.
+    pub ud: ibv_odp_transport_cap_bits,
+}
 
-/// A completion queue that allows subscribing to the completion of queued sends and receives.
-#[derive(Clone)]
-pub struct CompletionQueue {
-    inner: Arc<CompletionQueueInner>,
+impl From<ffi::ibv_odp_caps> for OdpCapabilities {
+    fn from(caps: ffi::ibv_odp_caps) -> Self {
+        let general = caps.general_caps;
+        Self {
+            supported: general & ffi::ibv_odp_general_caps::IBV_ODP_SUPPORT as u64 != 0,
+            implicit: general & ffi::ibv_odp_general_caps::IBV_ODP_SUPPORT_IMPLICIT as u64 != 0,
+            rc: ibv_odp_transport_cap_bits(caps.per_transport_caps.rc_odp_caps),
+            uc: ibv_odp_transport_cap_bits(caps.per_transport_caps.uc_odp_caps),
+            ud: ibv_odp_transport_cap_bits(caps.per_transport_caps.ud_odp_caps),
+        }
+    }
 }
 
-impl CompletionQueue {
-    /// Poll for (possibly multiple) work completions.
-    ///
-    /// A Work Completion indicates that a Work Request in a Work Queue, and all of the outstanding
-    /// unsignaled Work Requests that posted to that Work Queue, associated with this CQ have
-    /// completed. Any Receive Requests, signaled Send Requests and Send Requests that ended with
-    /// an error will generate Work Completions.
-    ///
-    /// When a Work Request ends, a Work Completion is added to the tail of the CQ that this Work
-    /// Queue is associated with. `poll` checks if Work Completions are present in a CQ, and pop
-    /// them from the head of the CQ in the order they entered it (FIFO) into `completions`. After
-    /// a Work Completion was popped from a CQ, it cannot be returned to it. `poll` returns the
-    /// subset of `completions` that successfully completed. If the returned slice has fewer
-    /// elements than the provided `completions` slice, the CQ was emptied.
-    ///
-    /// Not all attributes of the completed `ibv_wc`'s are always valid. If the completion status
-    /// is not `IBV_WC_SUCCESS`, only the following attributes are valid: `wr_id`, `status`,
-    /// `qp_num`, and `vendor_err`.
-    ///
-    /// Callers must ensure the CQ does not overrun (exceed its capacity), as this triggers an
-    ///  `IBV_EVENT_CQ_ERR` async event, rendering the CQ unusable. You can do this by limiting
-    /// the number of inflight Work Requests.
-    ///
-    /// Note that `poll` does not block or cause a context switch. This is why RDMA technologies
-    /// can achieve very low latency (below 1 µs).
-    #[inline]
-    pub fn poll<'c>(
-        &self,
-        completions: &'c mut [ffi::ibv_wc],
-    ) -> io::Result<&'c mut [ffi::ibv_wc]> {
-        // TODO: from http://www.rdmamojo.com/2013/02/15/ibv_poll_cq/
-        //
-        //   One should consume Work Completions at a rate that prevents the CQ from being overrun
-        //   (hold more Work Completions than the CQ size). In case of an CQ overrun, the async
-        //   event `IBV_EVENT_CQ_ERR` will be triggered, and the CQ cannot be used anymore.
-        //
-        let ctx: *mut ffi::ibv_context = unsafe { &*self.inner.cq }.context;
-        let ops = &mut unsafe { &mut *ctx }.ops;
-        let n = unsafe {
-            ops.poll_cq.as_mut().unwrap()(
-                self.inner.cq,
-                completions.len() as i32,
-                completions.as_mut_ptr(),
-            )
-        };
+/// The attributes of a physical port, as returned by `Context::port_attributes`.
+#[derive(Debug, Clone)]
+pub struct PortAttributes {
+    /// The logical state of the port. Only `ACTIVE` and `ARMED` ports can be used.
+    pub state: ibv_port_state,
+    /// The physical state of the port, as defined by the InfiniBand specification: 1 is sleep,
+    /// 2 is polling, 3 is disabled, 4 is port configuration training, 5 is link up, 6 is link
+    /// error recovery and 7 is phy test.
+    pub phys_state: u8,
+    /// The maximum MTU supported by the port.
+    pub max_mtu: ibv_mtu,
+    /// The MTU currently configured on the port.
+    pub active_mtu: ibv_mtu,
+    /// The base LID of the port. Only meaningful for InfiniBand ports.
+    pub lid: u16,
+    /// The LID of the subnet manager that manages the port.
+    pub sm_lid: u16,
+    /// The LID mask control, i.e. the number of low bits of `lid` that can be used as path bits.
+    pub lmc: u8,
+    /// The link layer protocol used by the port.
+    pub link_layer: LinkLayer,
+    /// The currently active speed of each lane.
+    pub active_speed: PortSpeed,
+    /// The currently active number of lanes.
+    pub active_width: PortWidth,
+    /// The length of the GID table of the port.
+    pub gid_tbl_len: u32,
+    /// The length of the P_Key table of the port.
+    pub pkey_tbl_len: u16,
+}
 
-        if n < 0 {
-            Err(io::Error::other("ibv_poll_cq failed"))
-        } else {
-            Ok(&mut completions[0..n as usize])
+impl From<ffi::ibv_port_attr> for PortAttributes {
+    fn from(port_attr: ffi::ibv_port_attr) -> Self {
+        Self {
+            state: port_attr.state,
+            phys_state: port_attr.phys_state,
+            max_mtu: port_attr.max_mtu,
+            active_mtu: port_attr.active_mtu,
+            lid: port_attr.lid,
+            sm_lid: port_attr.sm_lid,
+            lmc: port_attr.lmc,
+            link_layer: port_attr.link_layer.into(),
+            active_speed: if port_attr.active_speed_ex != 0 {
+                port_attr.active_speed_ex.into()
+            } else {
+                u32::from(port_attr.active_speed).into()
+            },
+            active_width: port_attr.active_width.into(),
+            gid_tbl_len: port_attr.gid_tbl_len as u32,
+            pkey_tbl_len: port_attr.pkey_tbl_len,
         }
     }
+}
 
-    /// Waits for one or more work completions in a Completion Queue (CQ).
-    ///
-    /// Unlike `poll`, this method blocks until at least one work completion is available or the
-    /// optional timeout expires. It is designed to wait efficiently for completions when polling
-    /// alone is insufficient, such as in low-traffic scenarios.
-    ///
-    /// The returned slice reflects completed work requests (e.g., sends, receives) from the
-    /// associated Work Queue. Not all fields in `ibv_wc` are valid unless the status is
-    /// `IBV_WC_SUCCESS`.
+/// The link layer protocol of a port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LinkLayer {
+    /// The device did not report a link layer. This is the case for older InfiniBand devices.
+    Unspecified,
+    /// InfiniBand.
+    InfiniBand,
+    /// Ethernet, i.e. RoCE or iWARP.
+    Ethernet,
+    /// A link layer that is not known to this crate.
+    Unknown(u8),
+}
+
+impl From<u8> for LinkLayer {
+    fn from(link_layer: u8) -> Self {
+        match link_layer {
+            0 => LinkLayer::Unspecified,
+            1 => LinkLayer::InfiniBand,
+            2 => LinkLayer::Ethernet,
+            x => LinkLayer::Unknown(x),
+        }
+    }
+}
+
+/// The signalling rate of each lane of a port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PortSpeed {
+    /// Single Data Rate, 2.5 Gb/s.
+    Sdr,
+    /// Double Data Rate, 5 Gb/s.
+    Ddr,
+    /// Quad Data Rate, 10 Gb/s.
+    Qdr,
+    /// FDR10, 10.3125 Gb/s.
+    Fdr10,
+    /// Fourteen Data Rate, 14.0625 Gb/s.
+    Fdr,
+    /// Enhanced Data Rate, 25.78125 Gb/s.
+    Edr,
+    /// High Data Rate, 50 Gb/s.
+    Hdr,
+    /// Next Data Rate, 100 Gb/s.
+    Ndr,
+    /// eXtended Data Rate, 200 Gb/s.
+    Xdr,
+    /// A speed that is not known to this crate.
+    Unknown(u32),
+}
+
+impl PortSpeed {
+    /// Returns the nominal rate of a single lane in Gb/s, or `None` if the speed is unknown.
+    pub fn lane_gbps(&self) -> Option<f64> {
+        match self {
+            PortSpeed::Sdr => Some(2.5),
+            PortSpeed::Ddr => Some(5.0),
+            PortSpeed::Qdr => Some(10.0),
+            PortSpeed::Fdr10 => Some(10.3125),
+            PortSpeed::Fdr => Some(14.0625),
+            PortSpeed::Edr => Some(25.78125),
+            PortSpeed::Hdr => Some(50.0),
+            PortSpeed::Ndr => Some(100.0),
+            PortSpeed::Xdr => Some(200.0),
+            PortSpeed::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u32> for PortSpeed {
+    fn from(speed: u32) -> Self {
+        match speed {
+            1 => PortSpeed::Sdr,
+            2 => PortSpeed::Ddr,
+            4 => PortSpeed::Qdr,
+            8 => PortSpeed::Fdr10,
+            16 => PortSpeed::Fdr,
+            32 => PortSpeed::Edr,
+            64 => PortSpeed::Hdr,
+            128 => PortSpeed::Ndr,
+            256 => PortSpeed::Xdr,
+            x => PortSpeed::Unknown(x),
+        }
+    }
+}
+
+/// The number of lanes of a port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PortWidth {
+    /// One lane.
+    X1,
+    /// Two lanes.
+    X2,
+    /// Four lanes.
+    X4,
+    /// Eight lanes.
+    X8,
+    /// Twelve lanes.
+    X12,
+    /// A width that is not known to this crate.
+    Unknown(u8),
+}
+
+impl PortWidth {
+    /// Returns the number of lanes, or `None` if the width is unknown.
+    pub fn lanes(&self) -> Option<u8> {
+        match self {
+            PortWidth::X1 => Some(1),
+            PortWidth::X2 => Some(2),
+            PortWidth::X4 => Some(4),
+            PortWidth::X8 => Some(8),
+            PortWidth::X12 => Some(12),
+            PortWidth::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u8> for PortWidth {
+    fn from(width: u8) -> Self {
+        match width {
+            1 => PortWidth::X1,
+            2 => PortWidth::X4,
+            4 => PortWidth::X8,
+            8 => PortWidth::X12,
+            16 => PortWidth::X2,
+            x => PortWidth::Unknown(x),
+        }
+    }
+}
+
+/// A handle for reading the asynchronous events of a device, created by `Context::async_events`.
+///
+/// The handle exposes the file descriptor of the event queue through `AsRawFd` and `AsFd`, so it
+/// can be registered with an event loop. The descriptor is non-blocking and becomes readable when
+/// an event is available.
+pub struct AsyncEvents {
+    ctx: Arc<ContextInner>,
+}
+
+impl AsyncEvents {
+    /// Returns the next asynchronous event, or `None` if no event is pending.
     ///
     /// # Errors
-    /// - `TimedOut`: If the timeout expires before any completions are available.
-    /// - System errors: From underlying calls like `req_notify_cq`, `poll`, or `ibv_get_cq_event`.
-    pub fn wait<'c>(
-        &self,
-        completions: &'c mut [ffi::ibv_wc],
-        timeout: Option<Duration>,
-    ) -> io::Result<&'c mut [ffi::ibv_wc]> {
-        let c = completions as *mut [ffi::ibv_wc];
-
-        loop {
-            let polled_completions = self.poll(unsafe { &mut *c })?;
-            if !polled_completions.is_empty() {
-                return Ok(polled_completions);
+    ///
+    ///  - System errors: From the underlying `ibv_get_async_event` call.
+    pub fn try_next_event(&self) -> Result<Option<AsyncEvent>, Error> {
+        let mut event = ffi::ibv_async_event::default();
+        let rc = unsafe { ffi::ibv_get_async_event(self.ctx.ctx, &mut event) };
+        if rc < 0 {
+            let e = Error::last_os_error("ibv_get_async_event");
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
             }
+            return Err(e);
+        }
 
-            // SAFETY: dereferencing completion queue context, which is guaranteed to not have
-            // been destroyed yet because we don't destroy it until in Drop, and given we have
-            // self, Drop has not been called. The context is guaranteed to not have been destroyed
-            // because the `CompletionQueue` holds a reference to the `Context` and we only destroy
-            // the context in Drop implementation of the `Context`.
-            let ctx = unsafe { *self.inner.cq }.context;
-            let errno = unsafe {
-                let ops = &mut { &mut *ctx }.ops;
-                ops.req_notify_cq.as_mut().unwrap()(self.inner.cq, 0)
-            };
-            if errno != 0 {
-                return Err(io::Error::from_raw_os_error(errno));
-            }
+        // SAFETY: the event was just filled in by `ibv_get_async_event`, and the resource it
+        // refers to cannot be destroyed until the event is acknowledged below.
+        let async_event = unsafe { AsyncEvent::from_raw(&event) };
+        // All events returned by ibv_get_async_event() must be acknowledged with
+        // ibv_ack_async_event(), otherwise destroying the affected resource blocks forever.
+        unsafe { ffi::ibv_ack_async_event(&mut event) };
+        Ok(Some(async_event))
+    }
 
-            // We poll again to avoid a race when Work Completions arrive between the first `poll()` and `req_notify_cq()`.
-            let polled_completions = self.poll(unsafe { &mut *c })?;
-            if !polled_completions.is_empty() {
-                return Ok(polled_completions);
+    /// Waits for the next asynchronous event.
+    ///
+    /// Blocks until an event is available or the optional timeout expires.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::TimedOut`: If the timeout expires before an event is available.
+    /// - System errors: From underlying calls like `poll` or `ibv_get_async_event`.
+    pub fn next_event(&self, timeout: Option<Duration>) -> Result<AsyncEvent, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(event) = self.try_next_event()? {
+                return Ok(event);
             }
 
-            let pollfd = nix::poll::PollFd::new(
-                // SAFETY: dereferencing completion queue context, which is guaranteed to not have
-                // been destroyed yet because we don't destroy it until in Drop, and given we have
-                // self, Drop has not been called. `fd` is guaranteed to not have been destroyed
-                // because only destroy it in the Drop implementation of this `CompletionQueue` and
-                // we still hold `self` here.
-                unsafe { BorrowedFd::borrow_raw({ *self.inner.cc }.fd) },
-                nix::poll::PollFlags::POLLIN,
-            );
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let pollfd = nix::poll::PollFd::new(self.as_fd(), nix::poll::PollFlags::POLLIN);
             let ret = nix::poll::poll(
                 &mut [pollfd],
-                timeout
+                remaining
                     .map(nix::poll::PollTimeout::try_from)
                     .transpose()
-                    .map_err(|_| io::Error::other("failed to convert timeout to PollTimeout"))?,
+                    .map_err(|_| {
+                        Error::Io(io::Error::other("failed to convert timeout to PollTimeout"))
+                    })?,
             )?;
             match ret {
                 0 => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::TimedOut,
-                        "Timed out during completion queue wait",
-                    ));
+                    return Err(Error::TimedOut);
                 }
                 1 => {}
                 _ => unreachable!("we passed 1 fd to poll, but it returned {ret}"),
             }
+        }
+    }
+}
 
-            let mut out_cq = std::ptr::null_mut();
-            let mut out_cq_context = std::ptr::null_mut();
-            // The Completion Notification must be read using ibv_get_cq_event(). The file descriptor of
-            // `cq_context` was put into non-blocking mode to make `ibv_get_cq_event()` non-blocking.
-            // SAFETY: c ffi call
-            let rc =
-                unsafe { ffi::ibv_get_cq_event(self.inner.cc, &mut out_cq, &mut out_cq_context) };
-            if rc < 0 {
-                let e = io::Error::last_os_error();
-                if e.kind() == io::ErrorKind::WouldBlock {
-                    continue;
-                }
-                return Err(e);
-            }
-
-            assert_eq!(self.inner.cq, out_cq);
-            // cq_context is the opaque user defined identifier passed to `ibv_create_cq()`.
-            assert!(out_cq_context.is_null());
+impl AsRawFd for AsyncEvents {
+    fn as_raw_fd(&self) -> RawFd {
+        unsafe { *self.ctx.ctx }.async_fd
+    }
+}
 
-            // All completion events returned by ibv_get_cq_event() must eventually be acknowledged with ibv_ack_cq_events().
-            // SAFETY: c ffi call
-            unsafe { ffi::ibv_ack_cq_events(self.inner.cq, 1) };
-        }
+impl AsFd for AsyncEvents {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // SAFETY: the descriptor is only closed when the context is closed, and we hold a
+        // reference to the context.
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
     }
 }
 
-/// An unconfigured `QueuePair`.
+/// An asynchronous event of a device, as returned by `AsyncEvents::next_event`.
 ///
-/// A `QueuePairBuilder` is used to configure a `QueuePair` before it is allocated and initialized.
-/// To construct one, use `ProtectionDomain::create_qp`. See also [RDMAmojo] for many more details.
+/// Events about a `QueuePair` carry its `qp_num` (see `QueuePair::qp_num`), and events about a
+/// `CompletionQueue` carry the `id` it was created with (see `CompletionQueue::id`).
 ///
-/// [RDMAmojo]: http://www.rdmamojo.com/2013/01/12/ibv_modify_qp/
-pub struct QueuePairBuilder {
-    ctx: isize,
-    pd: Arc<ProtectionDomainInner>,
-    port_attr: ffi::ibv_port_attr,
-
-    send: Arc<CompletionQueueInner>,
-    max_send_wr: u32,
-    recv: Arc<CompletionQueueInner>,
-    max_recv_wr: u32,
-
-    gid_index: Option<u32>,
-    max_send_sge: u32,
-    max_recv_sge: u32,
-    max_inline_data: u32,
-
-    qp_type: ffi::ibv_qp_type,
-
-    // carried along to handshake phase
-    /// traffic class set in Global Routing Headers, only used if `gid_index` is set.
-    traffic_class: u8,
-    /// only valid for RC and UC
-    access: Option<ffi::ibv_access_flags>,
-    /// only valid for RC
-    timeout: Option<u8>,
-    /// only valid for RC
-    retry_count: Option<u8>,
-    /// only valid for RC
-    rnr_retry: Option<u8>,
-    /// only valid for RC
-    min_rnr_timer: Option<u8>,
-    /// only valid for RC
-    max_rd_atomic: Option<u8>,
-    /// only valid for RC
-    max_dest_rd_atomic: Option<u8>,
-    /// only valid for RC and UC
-    path_mtu: Option<ibv_mtu>,
-    /// only valid for RC and UC
-    rq_psn: Option<u32>,
-    /// service level (0-15). Higher value means higher priority.
-    service_level: u8,
+/// See also [RDMAmojo's `ibv_get_async_event` documentation][1] for when each event is raised.
+///
+/// [1]: http://www.rdmamojo.com/2012/08/11/ibv_get_async_event/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AsyncEvent {
+    /// A `QueuePair` hit an error that prevents it from reporting completions, and was moved to
+    /// the error state.
+    QpFatal {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// A `QueuePair` received a request that violates the transport protocol, and was moved to
+    /// the error state.
+    QpRequestError {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// A `QueuePair` received a request that violates its access permissions, and was moved to the
+    /// error state.
+    QpAccessError {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// A `QueuePair` in the `RTR` state received its first packet.
+    CommunicationEstablished {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// The send queue of a `QueuePair` in the `SQD` state has been drained.
+    SqDrained {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// A `QueuePair` migrated to its alternate path.
+    PathMigrated {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// A `QueuePair` failed to migrate to its alternate path.
+    PathMigrationError {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// A `QueuePair` that is attached to a shared receive queue will not consume any more receive
+    /// requests.
+    QpLastWqeReached {
+        /// The number of the affected `QueuePair`.
+        qp_num: u32,
+    },
+    /// A `CompletionQueue` was overrun, and cannot be used anymore.
+    CqError {
+        /// The `id` of the affected `CompletionQueue`.
+        cq_id: isize,
+    },
+    /// A `SharedReceiveQueue` hit an error, and cannot be used anymore.
+    SrqError {
+        /// The `SharedReceiveQueue::handle` of the affected shared receive queue.
+        srq_handle: u32,
+    },
+    /// The number of outstanding receive requests of a `SharedReceiveQueue` dropped below the
+    /// limit set with `SharedReceiveQueue::modify`.
+    SrqLimitReached {
+        /// The `SharedReceiveQueue::handle` of the affected shared receive queue.
+        srq_handle: u32,
+    },
+    /// A port became active.
+    PortActive {
+        /// The number of the affected port.
+        port_num: u8,
+    },
+    /// A port is no longer active.
+    PortError {
+        /// The number of the affected port.
+        port_num: u8,
+    },
+    /// The LID of a port changed.
+    LidChange {
+        /// The number of the affected port.
+        port_num: u8,
+    },
+    /// The P_Key table of a port changed.
+    PkeyChange {
+        /// The number of the affected port.
+        port_num: u8,
+    },
+    /// The subnet manager of a port changed.
+    SmChange {
+        /// The number of the affected port.
+        port_num: u8,
+    },
+    /// The subnet manager asked for the subscriptions of a port to be registered again.
+    ClientReregister {
+        /// The number of the affected port.
+        port_num: u8,
+    },
+    /// The GID table of a port changed.
+    GidChange {
+        /// The number of the affected port.
+        port_num: u8,
+    },
+    /// A work queue hit an error, and was moved to the error state.
+    WqFatal,
+    /// The device hit an unrecoverable error, and the context cannot be used anymore.
+    DeviceFatal,
 }
 
-impl QueuePairBuilder {
-    /// Prepare a new `QueuePair` builder.
+impl AsyncEvent {
+    /// Converts a raw event into an `AsyncEvent`.
     ///
-    /// `max_send_wr` is the maximum number of outstanding Work Requests that can be posted to the
-    /// Send Queue in that Queue Pair. Value must be in `[0..dev_cap.max_qp_wr]`. There may be RDMA
-    /// devices that for specific transport types may support less outstanding Work Requests than
-    /// the maximum reported value.
+    /// # Safety
     ///
-    /// Similarly, `max_recv_wr` is the maximum number of outstanding Work Requests that can be
-    /// posted to the Receive Queue in that Queue Pair. Value must be in `[0..dev_cap.max_qp_wr]`.
-    /// There may be RDMA devices that for specific transport types may support less outstanding
-    /// Work Requests than the maximum reported value. This value is ignored if the Queue Pair is
-    /// associated with an SRQ
-    #[allow(clippy::too_many_arguments)]
-    fn new(
-        pd: Arc<ProtectionDomainInner>,
-        port_attr: ffi::ibv_port_attr,
-        send: Arc<CompletionQueueInner>,
-        max_send_wr: u32,
-        recv: Arc<CompletionQueueInner>,
-        max_recv_wr: u32,
-        qp_type: ffi::ibv_qp_type,
-        max_send_sge: u32,
-        max_recv_sge: u32,
-    ) -> QueuePairBuilder {
-        let port_active_mtu = port_attr.active_mtu;
-        QueuePairBuilder {
-            ctx: 0,
-            pd,
-            port_attr,
-
-            gid_index: None,
-            traffic_class: 0,
-            send,
-            max_send_wr,
-            recv,
-            max_recv_wr,
+    /// `event` must have been returned by `ibv_get_async_event`, and must not have been
+    /// acknowledged yet.
+    unsafe fn from_raw(event: &ffi::ibv_async_event) -> Self {
+        use ffi::ibv_event_type::*;
+
+        let qp_num = || unsafe { (*event.element.qp).qp_num };
+        let cq_id = || unsafe { (*event.element.cq).cq_context as isize };
+        let srq_handle = || unsafe { (*event.element.srq).handle };
+        let port_num = || unsafe { event.element.port_num as u8 };
+        match event.event_type {
+            IBV_EVENT_QP_FATAL => AsyncEvent::QpFatal { qp_num: qp_num() },
+            IBV_EVENT_QP_REQ_ERR => AsyncEvent::QpRequestError { qp_num: qp_num() },
+            IBV_EVENT_QP_ACCESS_ERR => AsyncEvent::QpAccessError { qp_num: qp_num() },
+            IBV_EVENT_COMM_EST => AsyncEvent::CommunicationEstablished { qp_num: qp_num() },
+            IBV_EVENT_SQ_DRAINED => AsyncEvent::SqDrained { qp_num: qp_num() },
+            IBV_EVENT_PATH_MIG => AsyncEvent::PathMigrated { qp_num: qp_num() },
+            IBV_EVENT_PATH_MIG_ERR => AsyncEvent::PathMigrationError { qp_num: qp_num() },
+            IBV_EVENT_QP_LAST_WQE_REACHED => AsyncEvent::QpLastWqeReached { qp_num: qp_num() },
+            IBV_EVENT_CQ_ERR => AsyncEvent::CqError { cq_id: cq_id() },
+            IBV_EVENT_SRQ_ERR => AsyncEvent::SrqError {
+                srq_handle: srq_handle(),
+            },
+            IBV_EVENT_SRQ_LIMIT_REACHED => AsyncEvent::SrqLimitReached {
+                srq_handle: srq_handle(),
+            },
+            IBV_EVENT_PORT_ACTIVE => AsyncEvent::PortActive {
+                port_num: port_num(),
+            },
+            IBV_EVENT_PORT_ERR => AsyncEvent::PortError {
+                port_num: port_num(),
+            },
+            IBV_EVENT_LID_CHANGE => AsyncEvent::LidChange {
+                port_num: port_num(),
+            },
+            IBV_EVENT_PKEY_CHANGE => AsyncEvent::PkeyChange {
+                port_num: port_num(),
+            },
+            IBV_EVENT_SM_CHANGE => AsyncEvent::SmChange {
+                port_num: port_num(),
+            },
+            IBV_EVENT_CLIENT_REREGISTER => AsyncEvent::ClientReregister {
+                port_num: port_num(),
+            },
+            IBV_EVENT_GID_CHANGE => AsyncEvent::GidChange {
+                port_num: port_num(),
+            },
+            IBV_EVENT_WQ_FATAL => AsyncEvent::WqFatal,
+            IBV_EVENT_DEVICE_FATAL => AsyncEvent::DeviceFatal,
+        }
+    }
+}
 
-            max_send_sge,
-            max_recv_sge,
-            max_inline_data: 0,
+/// Creates `CompletionQueue`s with a non-default completion vector or completion channel.
+///
+/// To construct one, use `Context::cq_builder`.
+pub struct CompletionQueueBuilder {
+    ctx: Arc<ContextInner>,
+    min_cq_entries: i32,
+    id: isize,
+    comp_vector: u32,
+    channel: ChannelMode,
+    credit_accounting: bool,
+    /// only used by `build_ex`
+    wc_flags: ffi::ibv_create_cq_wc_flags,
+}
 
-            qp_type,
+enum ChannelMode {
+    Own,
+    Shared(Arc<CompletionChannelInner>),
+    PollingOnly,
+}
 
-            access: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC
-                || qp_type == ffi::ibv_qp_type::IBV_QPT_UC)
-                .then_some(ffi::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE),
-            min_rnr_timer: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(16),
-            retry_count: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(6),
-            rnr_retry: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(6),
-            timeout: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(4),
-            max_rd_atomic: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(1),
-            max_dest_rd_atomic: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(1),
-            path_mtu: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC
-                || qp_type == ffi::ibv_qp_type::IBV_QPT_UC)
-                .then_some(port_active_mtu),
-            rq_psn: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC
-                || qp_type == ffi::ibv_qp_type::IBV_QPT_UC)
-                .then_some(0),
-            service_level: 0,
-        }
+impl CompletionQueueBuilder {
+    /// Set the opaque identifier of the CQ, which is returned by `CompletionQueue::id` and
+    /// reported by `CompletionChannel::next_event` and `AsyncEvent::CqError`.
+    ///
+    /// Defaults to 0.
+    pub fn set_id(&mut self, id: isize) -> &mut Self {
+        self.id = id;
+        self
     }
 
-    /// Set the access flags for the new `QueuePair`.
+    /// Set the completion vector that completion events of the CQ are signaled on.
     ///
-    /// Valid only for RC and UC QPs.
+    /// Must be less than `Context::num_comp_vectors`.
     ///
-    /// Defaults to `IBV_ACCESS_LOCAL_WRITE`.
-    pub fn set_access(&mut self, access: ffi::ibv_access_flags) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
-            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
-        {
-            self.access = Some(access);
-        }
+    /// Defaults to 0.
+    pub fn set_comp_vector(&mut self, comp_vector: u32) -> &mut Self {
+        self.comp_vector = comp_vector;
         self
     }
 
-    /// Set the access flags of the new `QueuePair` such that it allows remote reads and writes.
+    /// Report the completion events of the CQ on `channel`, which may be shared with other CQs.
     ///
-    /// Valid only for RC and UC QPs.
-    pub fn allow_remote_rw(&mut self) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
-            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
-        {
-            self.access = Some(
-                self.access.expect("always set to Some in new")
-                    | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_WRITE
-                    | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_READ,
-            );
-        }
+    /// By default, each CQ gets a completion channel of its own.
+    pub fn set_channel(&mut self, channel: &CompletionChannel) -> &mut Self {
+        self.channel = ChannelMode::Shared(channel.inner.clone());
         self
     }
 
-    /// Set the service level of the new `QueuePair`.
-    /// service level (0-15). Higher value means higher priority.
-    /// Defaults to 0.
-    pub fn set_service_level(&mut self, service_level: u8) -> &mut Self {
-        self.service_level = service_level;
+    /// Create the CQ without a completion channel.
+    ///
+    /// Such a CQ can only be polled, and `CompletionQueue::wait` fails with
+    /// `Error::NoCompletionChannel`. This saves a file descriptor per CQ.
+    pub fn set_polling_only(&mut self) -> &mut Self {
+        self.channel = ChannelMode::PollingOnly;
         self
     }
 
-    /// Sets the GID table index that should be used for the new `QueuePair`.
-    /// The entry corresponds to the index in `Context::gid_table()`. This is only used if the
-    /// `QueuePairEndpoint` that is passed to `QueuePair::handshake()` has a `gid`.
+    /// Track how many work completions may still be added to the CQ without overrunning it.
     ///
-    /// Defaults to unset.
-    pub fn set_gid_index(&mut self, gid_index: u32) -> &mut Self {
-        self.gid_index = Some(gid_index);
+    /// With credit accounting, every posted work request that generates a work completion in the
+    /// CQ takes up one of its entries, until that completion is polled. Posting a request to a
+    /// `QueuePair` using the CQ then fails with `Error::CompletionQueueFull`, which has the
+    /// `io::ErrorKind::WouldBlock` kind, instead of risking an overrun and the
+    /// `AsyncEvent::CqError` that follows it. The accounting is shared by all `QueuePair`s that
+    /// use the CQ, and follows `CompletionQueue::resize`.
+    ///
+    /// Receives posted to a `SharedReceiveQueue` are not accounted for, since it is not known
+    /// which CQ they complete on, so the CQ must be created with room for them. Polling their
+    /// completions never makes more entries available than posted work requests reserved. The CQ
+    /// must only be polled through this crate, e.g. not through `ibv_poll_cq` on its raw handle.
+    ///
+    /// Defaults to `false`.
+    pub fn set_credit_accounting(&mut self, enabled: bool) -> &mut Self {
+        self.credit_accounting = enabled;
         self
     }
 
-    /// Sets the traffic class of the Global Routing Headers (GRH).
+    /// Create a new `CompletionQueue` from this builder template.
     ///
-    /// This value is only used if a `gid_index` was specified. Using this value, the originator
-    /// of the packets specifies the required delivery priority for handling them by the routers.
+    /// # Errors
     ///
-    /// Defaults to 0.
-    pub fn set_traffic_class(&mut self, traffic_class: u8) -> &mut Self {
-        self.traffic_class = traffic_class;
-        self
-    }
+    ///  - `EINVAL`: Invalid `min_cq_entries` (must be `1 <= cqe <= dev_cap.max_cqe`), or invalid
+    ///    completion vector.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn build(&self) -> Result<CompletionQueue, Error> {
+        let cc = self.channel()?;
+        let cq = unsafe {
+            ffi::ibv_create_cq(
+                self.ctx.ctx,
+                self.min_cq_entries,
+                ptr::null::<c_void>().offset(self.id) as *mut _,
+                cc.as_ref().map_or(ptr::null_mut(), |cc| cc.cc),
+                self.comp_vector as i32,
+            )
+        };
 
-    /// Sets the minimum RNR NAK Timer Field Value for the new `QueuePair`.
-    ///
-    /// Defaults to 16 (2.56 ms delay).
-    /// Valid only for RC QPs.
-    ///
-    /// When an incoming message to this QP should consume a Work Request from the Receive Queue,
-    /// but no Work Request is outstanding on that Queue, the QP will send an RNR NAK packet to
-    /// the initiator. It does not affect RNR NAKs sent for other reasons. The value must be one of
-    /// the following values:
-    ///
-    ///  - 0 - 655.36 ms delay
-    ///  - 1 - 0.01 ms delay
-    ///  - 2 - 0.02 ms delay
-    ///  - 3 - 0.03 ms delay
-    ///  - 4 - 0.04 ms delay
-    ///  - 5 - 0.06 ms delay
-    ///  - 6 - 0.08 ms delay
-    ///  - 7 - 0.12 ms delay
-    ///  - 8 - 0.16 ms delay
-    ///  - 9 - 0.24 ms delay
-    ///  - 10 - 0.32 ms delay
-    ///  - 11 - 0.48 ms delay
-    ///  - 12 - 0.64 ms delay
-    ///  - 13 - 0.96 ms delay
-    ///  - 14 - 1.28 ms delay
-    ///  - 15 - 1.92 ms delay
-    ///  - 16 - 2.56 ms delay
-    ///  - 17 - 3.84 ms delay
-    ///  - 18 - 5.12 ms delay
-    ///  - 19 - 7.68 ms delay
-    ///  - 20 - 10.24 ms delay
-    ///  - 21 - 15.36 ms delay
-    ///  - 22 - 20.48 ms delay
-    ///  - 23 - 30.72 ms delay
-    ///  - 24 - 40.96 ms delay
-    ///  - 25 - 61.44 ms delay
-    ///  - 26 - 81.92 ms delay
-    ///  - 27 - 122.88 ms delay
-    ///  - 28 - 163.84 ms delay
-    ///  - 29 - 245.76 ms delay
-    ///  - 30 - 327.68 ms delay
-    ///  - 31 - 491.52 ms delay
-    pub fn set_min_rnr_timer(&mut self, timer: u8) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
-            self.min_rnr_timer = Some(timer);
+        if cq.is_null() {
+            Err(Error::last_os_error("ibv_create_cq").with_cq(self.id))
+        } else {
+            Ok(self.wrap(cq, cc))
         }
-        self
     }
 
-    /// Sets the minimum timeout that the new `QueuePair` waits for ACK/NACK from remote QP before
-    /// retransmitting the packet.
-    ///
-    /// Defaults to 4 (65.536µs).
-    /// Valid only for RC QPs.
-    ///
-    /// The value zero is special value that waits an infinite time for the ACK/NACK (useful
-    /// for debugging). This means that if any packet in a message is being lost and no ACK or NACK
-    /// is being sent, no retry will ever occur and the QP will just stop sending data.
+    /// Request hardware completion timestamps for CQs created with `build_ex`.
     ///
-    /// For any other value of timeout, the time calculation is `4.096*2^timeout`µs, giving:
+    /// The timestamps are in HCA clock ticks, see `Context::hca_clock` for converting them.
     ///
-    ///  - 0 - infinite
-    ///  - 1 - 8.192 µs
-    ///  - 2 - 16.384 µs
-    ///  - 3 - 32.768 µs
-    ///  - 4 - 65.536 µs
-    ///  - 5 - 131.072 µs
-    ///  - 6 - 262.144 µs
-    ///  - 7 - 524.288 µs
-    ///  - 8 - 1.048 ms
-    ///  - 9 - 2.097 ms
-    ///  - 10 - 4.194 ms
-    ///  - 11 - 8.388 ms
-    ///  - 12 - 16.777 ms
-    ///  - 13 - 33.554 ms
-    ///  - 14 - 67.108 ms
-    ///  - 15 - 134.217 ms
-    ///  - 16 - 268.435 ms
-    ///  - 17 - 536.870 ms
-    ///  - 18 - 1.07 s
-    ///  - 19 - 2.14 s
-    ///  - 20 - 4.29 s
-    ///  - 21 - 8.58 s
-    ///  - 22 - 17.1 s
-    ///  - 23 - 34.3 s
-    ///  - 24 - 68.7 s
-    ///  - 25 - 137 s
-    ///  - 26 - 275 s
-    ///  - 27 - 550 s
-    ///  - 28 - 1100 s
-    ///  - 29 - 2200 s
-    ///  - 30 - 4400 s
-    ///  - 31 - 8800 s
-    pub fn set_timeout(&mut self, timeout: u8) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
-            self.timeout = Some(timeout);
-        }
-        self
+    /// Defaults to `false`.
+    pub fn set_completion_timestamp(&mut self, enabled: bool) -> &mut Self {
+        self.set_wc_flag(
+            ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP,
+            enabled,
+        )
     }
 
-    /// Sets the total number of times that the new `QueuePair` will try to resend the packets
-    /// before reporting an error because the remote side doesn't answer in the primary path.
-    ///
-    /// This 3 bit value defaults to 6.
-    /// Valid only for RC QPs.
+    /// Request wallclock completion timestamps for CQs created with `build_ex`.
     ///
-    /// # Panics
+    /// The timestamps are in nanoseconds since the Unix epoch, as translated by the device.
     ///
-    /// Panics if a count higher than 7 is given.
-    pub fn set_retry_count(&mut self, count: u8) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
-            assert!(count <= 7);
-            self.retry_count = Some(count);
+    /// Defaults to `false`.
+    pub fn set_completion_wallclock(&mut self, enabled: bool) -> &mut Self {
+        self.set_wc_flag(
+            ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP_WALLCLOCK,
+            enabled,
+        )
+    }
+
+    fn set_wc_flag(&mut self, flag: ffi::ibv_create_cq_wc_flags, enabled: bool) -> &mut Self {
+        if enabled {
+            self.wc_flags |= flag;
+        } else {
+            self.wc_flags &= ffi::ibv_create_cq_wc_flags(!flag.0);
         }
         self
     }
 
-    /// Sets the total number of times that the new `QueuePair` will try to resend the packets when
-    /// an RNR NACK was sent by the remote QP before reporting an error.
+    /// Create a new `ExtendedCompletionQueue` from this builder template.
     ///
-    /// This 3 bit value defaults to 6. The value 7 is special and specify to retry sending the
-    /// message indefinitely when a RNR Nack is being sent by remote side.
-    /// Valid only for RC QPs.
+    /// Unlike `build`, this uses `ibv_create_cq_ex`, which allows the CQ to report the completion
+    /// timestamps requested with `set_completion_timestamp` and `set_completion_wallclock`.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if a limit higher than 7 is given.
-    pub fn set_rnr_retry(&mut self, n: u8) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
-            assert!(n <= 7);
-            self.rnr_retry = Some(n);
+    ///  - `EINVAL`: Invalid `min_cq_entries` (must be `1 <= cqe <= dev_cap.max_cqe`), or invalid
+    ///    completion vector.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///  - `EOPNOTSUPP`: The device does not support extended CQs, or the requested timestamps.
+    pub fn build_ex(&self) -> Result<ExtendedCompletionQueue, Error> {
+        let Some(create_cq_ex) = verbs_get_ctx_op!(self.ctx.ctx, create_cq_ex) else {
+            return Err(Error::verb("ibv_create_cq_ex", nix::libc::EOPNOTSUPP).with_cq(self.id));
+        };
+
+        let cc = self.channel()?;
+        let mut attr = ffi::ibv_cq_init_attr_ex {
+            cqe: self.min_cq_entries as u32,
+            cq_context: ptr::null::<c_void>().wrapping_offset(self.id) as *mut _,
+            channel: cc.as_ref().map_or(ptr::null_mut(), |cc| cc.cc),
+            comp_vector: self.comp_vector,
+            wc_flags: self.wc_flags.0 as u64,
+            comp_mask: 0,
+            flags: 0,
+            parent_domain: ptr::null_mut(),
+        };
+        let cq = unsafe { create_cq_ex(self.ctx.ctx, &mut attr as *mut _) };
+
+        if cq.is_null() {
+            Err(Error::last_os_error("ibv_create_cq_ex").with_cq(self.id))
+        } else {
+            // an `ibv_cq_ex` starts with the fields of an `ibv_cq` (see `ibv_cq_ex_to_cq`)
+            Ok(ExtendedCompletionQueue {
+                cq: self.wrap(cq.cast(), cc),
+                wc_flags: self.wc_flags,
+            })
         }
-        self
     }
 
-    /// Set the number of outstanding RDMA reads & atomic operations on the destination Queue Pair.
-    ///
-    /// This defaults to 1.
-    /// Valid only for RC QPs.
-    pub fn set_max_rd_atomic(&mut self, max_rd_atomic: u8) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
-            self.max_rd_atomic = Some(max_rd_atomic);
+    /// Returns the completion channel to create the CQ with.
+    fn channel(&self) -> Result<Option<Arc<CompletionChannelInner>>, Error> {
+        Ok(match &self.channel {
+            ChannelMode::Own => Some(Arc::new(CompletionChannelInner::new(self.ctx.clone())?)),
+            ChannelMode::Shared(cc) => Some(cc.clone()),
+            ChannelMode::PollingOnly => None,
+        })
+    }
+
+    fn wrap(
+        &self,
+        cq: *mut ffi::ibv_cq,
+        cc: Option<Arc<CompletionChannelInner>>,
+    ) -> CompletionQueue {
+        ResourceCounters::created(&self.ctx.live.cqs);
+        CompletionQueue {
+            inner: Arc::new(CompletionQueueInner {
+                ctx: self.ctx.clone(),
+                cq,
+                cc: cc.map(|inner| CompletionChannel { inner }),
+                credits: self
+                    .credit_accounting
+                    .then(|| Credits::new(unsafe { *cq }.cqe as usize)),
+                senders: Mutex::new(HashMap::new()),
+                has_senders: AtomicBool::new(false),
+                debtors: Mutex::new(HashMap::new()),
+            }),
         }
-        self
     }
+}
 
-    /// Set the number of responder resources for handling incoming RDMA reads & atomic operations.
-    ///
-    /// This defaults to 1.
-    /// Valid only for RC QPs.
-    pub fn set_max_dest_rd_atomic(&mut self, max_dest_rd_atomic: u8) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
-            self.max_dest_rd_atomic = Some(max_dest_rd_atomic);
+/// The work completion fields that `ExtendedCompletionQueue`s always report.
+const STANDARD_WC_FLAGS: ffi::ibv_create_cq_wc_flags = ffi::ibv_create_cq_wc_flags(
+    ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_BYTE_LEN.0
+        | ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_IMM.0
+        | ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_QP_NUM.0
+        | ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_SRC_QP.0,
+);
+
+struct CompletionChannelInner {
+    ctx: Arc<ContextInner>,
+    cc: *mut ffi::ibv_comp_channel,
+}
+
+impl CompletionChannelInner {
+    fn new(ctx: Arc<ContextInner>) -> Result<Self, Error> {
+        let cc = unsafe { ffi::ibv_create_comp_channel(ctx.ctx) };
+        if cc.is_null() {
+            return Err(Error::last_os_error("ibv_create_comp_channel"));
         }
-        self
+        ResourceCounters::created(&ctx.live.ccs);
+        let inner = CompletionChannelInner { ctx, cc };
+
+        // the file descriptor needs to be set to non-blocking because `ibv_get_cq_event()`
+        // would block otherwise.
+        set_nonblocking(unsafe { *cc }.fd)?;
+        Ok(inner)
     }
 
-    /// Set the path MTU.
-    ///
-    /// Defaults to the port's active_mtu.
-    /// Valid only for RC and UC QPs.
-    /// The possible values are:
-    ///  - 1: 256
-    ///  - 2: 512
-    ///  - 3: 1024
-    ///  - 4: 2048
-    ///  - 5: 4096
-    pub fn set_path_mtu(&mut self, path_mtu: ibv_mtu) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
-            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
-        {
-            self.path_mtu = Some(path_mtu);
+    /// Destroys the completion channel, unless that already happened.
+    fn close(&mut self) -> Result<(), Error> {
+        if self.cc.is_null() {
+            return Ok(());
         }
-        self
+        let errno = unsafe { ffi::ibv_destroy_comp_channel(self.cc) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_destroy_comp_channel", errno));
+        }
+        self.cc = ptr::null_mut();
+        ResourceCounters::destroyed(&self.ctx.live.ccs);
+        Ok(())
     }
 
-    /// Set the PSN for the receive queue.
+    /// Reads the next completion event, and returns the CQ it is for.
     ///
-    /// Defaults to 0.
-    /// Valid only for RC and UC QPs.
-    pub fn set_rq_psn(&mut self, rq_psn: u32) -> &mut Self {
-        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
-            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
-        {
-            self.rq_psn = Some(rq_psn);
+    /// Returns `None` if no event is pending.
+    fn get_cq_event(&self) -> Result<Option<*mut ffi::ibv_cq>, Error> {
+        let mut out_cq = ptr::null_mut();
+        let mut out_cq_context = ptr::null_mut();
+        // The Completion Notification must be read using ibv_get_cq_event(). The file descriptor
+        // was put into non-blocking mode to make `ibv_get_cq_event()` non-blocking.
+        // SAFETY: c ffi call
+        let rc = unsafe { ffi::ibv_get_cq_event(self.cc, &mut out_cq, &mut out_cq_context) };
+        if rc < 0 {
+            let e = Error::last_os_error("ibv_get_cq_event");
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(e);
         }
-        self
+
+        // All completion events returned by ibv_get_cq_event() must eventually be acknowledged
+        // with ibv_ack_cq_events(), otherwise destroying the CQ blocks forever.
+        // SAFETY: c ffi call
+        unsafe { ffi::ibv_ack_cq_events(out_cq, 1) };
+        Ok(Some(out_cq))
     }
 
-    /// Set the opaque context value for the new `QueuePair`.
-    ///
-    /// Defaults to 0.
-    pub fn set_context(&mut self, ctx: isize) -> &mut Self {
-        self.ctx = ctx;
-        self
+    /// Blocks until the channel's file descriptor is readable, or `timeout` expires.
+    fn poll(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let pollfd = nix::poll::PollFd::new(
+            // SAFETY: the descriptor is only closed when the channel is destroyed, which does not
+            // happen while we hold `self`.
+            unsafe { BorrowedFd::borrow_raw({ *self.cc }.fd) },
+            nix::poll::PollFlags::POLLIN,
+        );
+        let ret = nix::poll::poll(
+            &mut [pollfd],
+            timeout
+                .map(nix::poll::PollTimeout::try_from)
+                .transpose()
+                .map_err(|_| {
+                    Error::Io(io::Error::other("failed to convert timeout to PollTimeout"))
+                })?,
+        )?;
+        match ret {
+            0 => Err(Error::TimedOut),
+            1 => Ok(()),
+            _ => unreachable!("we passed 1 fd to poll, but it returned {ret}"),
+        }
     }
+}
 
-    /// Set the maximum number of send requests in the work queue
-    ///
-    /// Defaults to 1.
-    pub fn set_max_send_wr(&mut self, max_send_wr: u32) -> &mut Self {
-        self.max_send_wr = max_send_wr;
-        self
+impl Drop for CompletionChannelInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("{e}; leaking the completion channel");
+        }
     }
+}
 
-    /// The maximum number of scatter/gather elements in any Work Request
-    /// that can be posted to the Send Queue in that Queue Pair.
+unsafe impl Send for CompletionChannelInner {}
+unsafe impl Sync for CompletionChannelInner {}
+
+/// A completion channel, which reports completion events of one or more `CompletionQueue`s.
+///
+/// A CQ reports a completion event on its channel when a work completion is added to it after it
+/// was armed with `CompletionQueue::req_notify`. Sharing a channel between many CQs allows waiting
+/// for any of them with a single file descriptor.
+///
+/// Create one with `Context::create_comp_channel`, and attach CQs to it with
+/// `CompletionQueueBuilder::set_channel`.
+#[derive(Clone)]
+pub struct CompletionChannel {
+    inner: Arc<CompletionChannelInner>,
+}
+
+impl CompletionChannel {
+    /// Returns the `id` of the next CQ that reported a completion event, or `None` if no event is
+    /// pending.
     ///
-    /// Value can be [0..dev_cap.max_sge]. There may be RDMA devices that
-    /// for specific transport types may support less scatter/gather elements
-    /// than the maximum reported value.
+    /// The event is acknowledged, and the CQ is disarmed. It has to be armed again with
+    /// `CompletionQueue::req_notify` to report further events.
     ///
-    /// Defaults to 1.
-    pub fn set_max_send_sge(&mut self, max_send_sge: u32) -> &mut Self {
-        self.max_send_sge = max_send_sge;
-        self
+    /// # Errors
+    ///
+    ///  - System errors: From the underlying `ibv_get_cq_event` call.
+    pub fn try_next_event(&self) -> Result<Option<isize>, Error> {
+        let cq = self.inner.get_cq_event()?;
+        Ok(cq.map(|cq| unsafe { *cq }.cq_context as isize))
     }
 
-    /// Set the maximum number of receive requests in the work queue
+    /// Waits for the next completion event, and returns the `id` of the CQ that reported it.
     ///
-    /// Defaults to 1.
-    pub fn set_max_recv_wr(&mut self, max_recv_wr: u32) -> &mut Self {
-        self.max_recv_wr = max_recv_wr;
-        self
+    /// Blocks until an event is available or the optional timeout expires. See
+    /// `CompletionChannel::try_next_event`.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::TimedOut`: If the timeout expires before an event is available.
+    /// - System errors: From underlying calls like `poll` or `ibv_get_cq_event`.
+    pub fn next_event(&self, timeout: Option<Duration>) -> Result<isize, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(id) = self.try_next_event()? {
+                return Ok(id);
+            }
+
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            self.inner.poll(remaining)?;
+        }
     }
 
-    /// The maximum number of scatter/gather elements in any Work Request
-    /// that can be posted to the Receive Queue in that Queue Pair.
+    /// Destroys the completion channel.
     ///
-    /// Value can be [0..dev_cap.max_sge]. There may be RDMA devices that
-    /// for specific transport types may support less scatter/gather elements
-    /// than the maximum reported value. This value is ignored if the
-    /// Queue Pair is associated with an SRQ.
+    /// Unlike dropping the last handle to the channel, this reports whether destroying it
+    /// succeeded.
     ///
-    /// Defaults to 1.
-    pub fn set_max_recv_sge(&mut self, max_recv_sge: u32) -> &mut Self {
-        self.max_recv_sge = max_recv_sge;
-        self
+    /// # Errors
+    ///
+    /// The channel is handed back in the `ResourceError`, so that destroying it can be retried,
+    /// together with:
+    ///
+    ///  - `Error::InUse`: Other handles to this channel, or `CompletionQueue`s using it, are still
+    ///    alive. Dropping this handle instead leaves destroying the channel to the last of them.
+    ///  - System errors: From `ibv_destroy_comp_channel`.
+    pub fn close(self) -> Result<(), ResourceError<Self>> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(mut inner) => inner.close().map_err(|e| {
+                let inner = Arc::new(inner);
+                ResourceError::new(CompletionChannel { inner }, e)
+            }),
+            Err(inner) => Err(ResourceError::new(
+                CompletionChannel { inner },
+                Error::InUse,
+            )),
+        }
+    }
+}
+
+impl AsRawFd for CompletionChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        unsafe { *self.inner.cc }.fd
+    }
+}
+
+impl AsFd for CompletionChannel {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // SAFETY: the descriptor is only closed when the channel is destroyed, and we hold a
+        // reference to it.
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+}
+
+/// The CQ entries that posted work requests reserve for their completions.
+struct Credits {
+    /// the number of CQ entries that are not reserved by posted work requests. Negative if the CQ
+    /// was shrunk below the outstanding work requests.
+    available: AtomicIsize,
+    /// the number of CQ entries that are reserved by posted work requests, whose completions were
+    /// not polled yet
+    outstanding: AtomicUsize,
+}
+
+impl Credits {
+    fn new(capacity: usize) -> Self {
+        Credits {
+            available: AtomicIsize::new(capacity as isize),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of CQ entries that are not reserved.
+    fn available(&self) -> usize {
+        self.available.load(Ordering::Relaxed).max(0) as usize
+    }
+
+    /// Reserves `n` CQ entries, if that many are available.
+    fn acquire(&self, n: usize) -> bool {
+        let reserved = self
+            .available
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+                (c >= n as isize).then_some(c - n as isize)
+            })
+            .is_ok();
+        if reserved {
+            self.outstanding.fetch_add(n, Ordering::Relaxed);
+        }
+        reserved
+    }
+
+    /// Returns up to `n` reserved CQ entries, and returns how many were reserved.
+    ///
+    /// Completions of work requests that did not reserve an entry, e.g. of receives posted to a
+    /// `SharedReceiveQueue`, must not make more entries available than the CQ has.
+    fn release(&self, n: usize) -> usize {
+        let Ok(outstanding) =
+            self.outstanding
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |o| {
+                    (o > 0 && n > 0).then(|| o.saturating_sub(n))
+                })
+        else {
+            return 0;
+        };
+        let released = n.min(outstanding);
+        self.available
+            .fetch_add(released as isize, Ordering::Relaxed);
+        released
+    }
+
+    /// Makes `grown` more CQ entries available, or fewer if negative.
+    fn resize(&self, grown: isize) {
+        self.available.fetch_add(grown, Ordering::Relaxed);
+    }
+}
+
+struct CompletionQueueInner {
+    ctx: Arc<ContextInner>,
+    cq: *mut ffi::ibv_cq,
+    cc: Option<CompletionChannel>,
+    /// the CQ entries reserved by posted work requests, if credit accounting is enabled
+    credits: Option<Credits>,
+    /// the send trackers of the `QueuePair`s that post unsignaled send requests to this CQ, by
+    /// their QP number
+    senders: Mutex<HashMap<u32, Weak<SendTracker>>>,
+    /// whether `senders` is not empty, to keep polling cheap if no unsignaled requests are used
+    has_senders: AtomicBool,
+    /// the CQ entries reserved by the `QueuePair`s using this CQ, by their QP number, if credit
+    /// accounting is enabled
+    debtors: Mutex<HashMap<u32, Debtor>>,
+}
+
+/// The CQ entries that a `QueuePair` reserved in a CQ with credit accounting.
+struct Debtor {
+    /// the number of work requests it posted, whose completions were not polled yet
+    debits: Arc<AtomicUsize>,
+    /// whether its receives complete on the CQ without reserving entries, since they are posted
+    /// to a `SharedReceiveQueue`
+    srq_receives: bool,
+}
+
+/// Takes up to `n` entries off `debits`, and returns how many there were.
+fn repay(debits: &AtomicUsize, n: usize) -> usize {
+    let owed = debits
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |d| {
+            Some(d.saturating_sub(n))
+        })
+        .unwrap_or_default();
+    n.min(owed)
+}
+
+impl CompletionQueueInner {
+    fn senders(&self) -> MutexGuard<'_, HashMap<u32, Weak<SendTracker>>> {
+        self.senders.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn debtors(&self) -> MutexGuard<'_, HashMap<u32, Debtor>> {
+        self.debtors.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts returning the CQ entries reserved by a `QueuePair` as its completions are polled.
+    fn register_debtor(&self, qp_num: u32, debits: &Arc<AtomicUsize>, srq_receives: bool) {
+        if self.credits.is_some() {
+            let debits = debits.clone();
+            self.debtors().insert(
+                qp_num,
+                Debtor {
+                    debits,
+                    srq_receives,
+                },
+            );
+        }
+    }
+
+    /// Starts retiring the send requests of a `QueuePair` as their completions are polled.
+    fn register_sender(&self, sends: &Arc<SendTracker>) {
+        self.senders().insert(sends.qp_num, Arc::downgrade(sends));
+        self.has_senders.store(true, Ordering::Relaxed);
+    }
+
+    /// Retires the send requests of the polled work completions, given by QP number, `wr_id` and
+    /// whether they succeeded, and returns the CQ entries that were reserved for them.
+    ///
+    /// `is_recv` tells whether a completion is known to be for a receive request.
+    fn complete(&self, completions: impl Iterator<Item = (u32, u64, bool, bool)>) {
+        let senders = self
+            .has_senders
+            .load(Ordering::Relaxed)
+            .then(|| self.senders());
+        let debtors = self.credits.as_ref().map(|_| self.debtors());
+        if senders.is_none() && debtors.is_none() {
+            return;
+        }
+        let mut repaid = 0;
+        for (qp_num, wr_id, success, is_recv) in completions {
+            let mut reserved = 1;
+            if let Some(sends) = senders
+                .as_ref()
+                .filter(|_| !is_recv)
+                .and_then(|senders| senders.get(&qp_num))
+                .and_then(Weak::upgrade)
+            {
+                // every tracked request reserved an entry, including the unsignaled ones that
+                // completed silently before this one
+                reserved = sends.retire(wr_id, success);
+            }
+            if let Some(debtor) = debtors.as_ref().and_then(|debtors| debtors.get(&qp_num)) {
+                if !(is_recv && debtor.srq_receives) {
+                    repaid += repay(&debtor.debits, reserved);
+                }
+            }
+        }
+        if let Some(credits) = &self.credits {
+            credits.release(repaid);
+        }
+    }
+
+    /// Reserves CQ entries for the completions of `n` work requests that are about to be posted
+    /// by the `QueuePair` owing `debits`.
+    ///
+    /// Must be undone with `release_credits` if posting the work requests fails.
+    fn acquire_credits(&self, debits: &AtomicUsize, n: usize) -> Result<(), Error> {
+        match &self.credits {
+            Some(credits) if !credits.acquire(n) => Err(Error::CompletionQueueFull {
+                cq_id: unsafe { *self.cq }.cq_context as isize,
+            }),
+            Some(_) => {
+                debits.fetch_add(n, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the CQ entries of `n` work requests of the `QueuePair` owing `debits`, which were
+    /// never posted, or discarded without a completion.
+    ///
+    /// No more entries are returned than the `QueuePair` reserved.
+    fn release_credits(&self, debits: &AtomicUsize, n: usize) {
+        if let Some(credits) = &self.credits {
+            credits.release(repay(debits, n));
+        }
+    }
+
+    /// Destroys the CQ, unless that already happened.
+    fn close(&mut self) -> Result<(), Error> {
+        if self.cq.is_null() {
+            return Ok(());
+        }
+        let id = unsafe { *self.cq }.cq_context as isize;
+        let errno = unsafe { ffi::ibv_destroy_cq(self.cq) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_destroy_cq", errno).with_cq(id));
+        }
+        self.cq = ptr::null_mut();
+        ResourceCounters::destroyed(&self.ctx.live.cqs);
+        Ok(())
+    }
+}
+
+impl Drop for CompletionQueueInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("{e}; leaking the completion queue");
+        }
+    }
+}
+
+unsafe impl Send for CompletionQueueInner {}
+unsafe impl Sync for CompletionQueueInner {}
+
+/// A completion queue that allows subscribing to the completion of queued sends and receives.
+#[derive(Clone)]
+pub struct CompletionQueue {
+    inner: Arc<CompletionQueueInner>,
+}
+
+impl CompletionQueue {
+    /// Returns the opaque identifier this CQ was created with.
+    ///
+    /// This is the `id` that is reported by `AsyncEvent::CqError`.
+    pub fn id(&self) -> isize {
+        unsafe { *self.inner.cq }.cq_context as isize
+    }
+
+    /// Destroys the CQ.
+    ///
+    /// Unlike dropping the last handle to the CQ, this reports whether destroying it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// The CQ is handed back in the `ResourceError`, so that destroying it can be retried,
+    /// together with:
+    ///
+    ///  - `Error::InUse`: Other handles to this CQ, or `QueuePair`s using it, are still alive.
+    ///    Dropping this handle instead leaves destroying the CQ to the last of them.
+    ///  - System errors: From `ibv_destroy_cq`.
+    pub fn close(self) -> Result<(), ResourceError<Self>> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(mut inner) => inner.close().map_err(|e| {
+                let inner = Arc::new(inner);
+                ResourceError::new(CompletionQueue { inner }, e)
+            }),
+            Err(inner) => Err(ResourceError::new(CompletionQueue { inner }, Error::InUse)),
+        }
+    }
+
+    /// Returns the number of work completions the CQ can hold.
+    ///
+    /// This is at least the `min_cq_entries` the CQ was created or last resized with, but the
+    /// device may have rounded it up.
+    pub fn capacity(&self) -> usize {
+        unsafe { *self.inner.cq }.cqe as usize
+    }
+
+    /// Returns how many more work completions may be added to the CQ, if it was created with
+    /// `CompletionQueueBuilder::set_credit_accounting`.
+    pub fn available_credits(&self) -> Option<usize> {
+        Some(self.inner.credits.as_ref()?.available())
+    }
+
+    /// Resizes the CQ, so that it can hold at least `min_cq_entries` work completions.
+    ///
+    /// Work completions that are already in the CQ are kept. The CQ can not be shrunk below the
+    /// number of work completions it currently holds. Use `capacity` to learn the size the device
+    /// actually allocated.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `min_cq_entries` is larger than the device supports, or smaller than the
+    ///    number of work completions currently in the CQ.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///  - `EOPNOTSUPP`: The device does not support resizing CQs.
+    pub fn resize(&self, min_cq_entries: usize) -> Result<(), Error> {
+        let cqe = i32::try_from(min_cq_entries)
+            .map_err(|_| Error::verb("ibv_resize_cq", nix::libc::EINVAL).with_cq(self.id()))?;
+        let old_capacity = self.capacity();
+        let errno = unsafe { ffi::ibv_resize_cq(self.inner.cq, cqe) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_resize_cq", errno).with_cq(self.id()));
+        }
+        if let Some(credits) = &self.inner.credits {
+            let grown = self.capacity() as isize - old_capacity as isize;
+            credits.resize(grown);
+        }
+        Ok(())
+    }
+
+    /// Sets up completion moderation, so that the CQ only generates a completion event once
+    /// `count` work completions were added to it, or `period` passed since the first of them was.
+    ///
+    /// This reduces the number of events that `wait` and `CompletionChannel` consumers wake up
+    /// for, at the cost of latency. Passing a `count` of 1 and a `period` of zero disables
+    /// moderation again.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `period` is longer than `u16::MAX` microseconds, or the device rejected the
+    ///    moderation parameters.
+    ///  - `EOPNOTSUPP`: The device does not support completion moderation. The error's `kind` is
+    ///    then `io::ErrorKind::Unsupported`.
+    pub fn set_moderation(&self, count: u16, period: Duration) -> Result<(), Error> {
+        let cq_period = u16::try_from(period.as_micros())
+            .map_err(|_| Error::verb("ibv_modify_cq", nix::libc::EINVAL).with_cq(self.id()))?;
+        let ctx = unsafe { *self.inner.cq }.context;
+        let Some(modify_cq) = verbs_get_ctx_op!(ctx, modify_cq) else {
+            return Err(Error::verb("ibv_modify_cq", nix::libc::EOPNOTSUPP).with_cq(self.id()));
+        };
+
+        let mut attr = ffi::ibv_modify_cq_attr {
+            attr_mask: ffi::ibv_cq_attr_mask::IBV_CQ_ATTR_MODERATE as u32,
+            moderate: ffi::ibv_moderate_cq {
+                cq_count: count,
+                cq_period,
+            },
+        };
+        let errno = unsafe { modify_cq(self.inner.cq, &mut attr as *mut _) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_modify_cq", errno).with_cq(self.id()));
+        }
+        Ok(())
+    }
+
+    /// Poll for (possibly multiple) work completions.
+    ///
+    /// A Work Completion indicates that a Work Request in a Work Queue, and all of the outstanding
+    /// unsignaled Work Requests that posted to that Work Queue, associated with this CQ have
+    /// completed. Any Receive Requests, signaled Send Requests and Send Requests that ended with
+    /// an error will generate Work Completions.
+    ///
+    /// When a Work Request ends, a Work Completion is added to the tail of the CQ that this Work
+    /// Queue is associated with. `poll` checks if Work Completions are present in a CQ, and pop
+    /// them from the head of the CQ in the order they entered it (FIFO) into `completions`. After
+    /// a Work Completion was popped from a CQ, it cannot be returned to it. `poll` returns the
+    /// subset of `completions` that successfully completed. If the returned slice has fewer
+    /// elements than the provided `completions` slice, the CQ was emptied.
+    ///
+    /// Not all attributes of the completed `ibv_wc`'s are always valid. If the completion status
+    /// is not `IBV_WC_SUCCESS`, only the following attributes are valid: `wr_id`, `status`,
+    /// `qp_num`, and `vendor_err`.
+    ///
+    /// Callers must ensure the CQ does not overrun (exceed its capacity), as this triggers an
+    ///  `IBV_EVENT_CQ_ERR` async event, rendering the CQ unusable. You can do this by limiting
+    /// the number of inflight Work Requests, or let `CompletionQueueBuilder::set_credit_accounting`
+    /// limit them. The event is reported as `AsyncEvent::CqError` by `Context::async_events`.
+    ///
+    /// Note that `poll` does not block or cause a context switch. This is why RDMA technologies
+    /// can achieve very low latency (below 1 µs).
+    #[inline]
+    pub fn poll<'c>(
+        &self,
+        completions: &'c mut [ffi::ibv_wc],
+    ) -> Result<&'c mut [ffi::ibv_wc], Error> {
+        // from http://www.rdmamojo.com/2013/02/15/ibv_poll_cq/
+        //
+        //   One should consume Work Completions at a rate that prevents the CQ from being overrun
+        //   (hold more Work Completions than the CQ size). In case of an CQ overrun, the async
+        //   event `IBV_EVENT_CQ_ERR` will be triggered, and the CQ cannot be used anymore.
+        //
+        // `CompletionQueueBuilder::set_credit_accounting` enforces this when posting.
+        let ctx: *mut ffi::ibv_context = unsafe { &*self.inner.cq }.context;
+        let ops = &mut unsafe { &mut *ctx }.ops;
+        let n = unsafe {
+            ops.poll_cq.as_mut().unwrap()(
+                self.inner.cq,
+                completions.len() as i32,
+                completions.as_mut_ptr(),
+            )
+        };
+
+        if n < 0 {
+            Err(Error::verb_without_errno("ibv_poll_cq").with_cq(self.id()))
+        } else {
+            let completions = &mut completions[0..n as usize];
+            self.inner.complete(completions.iter().map(|wc| {
+                // failed completions do not report their opcode
+                let is_recv = wc.is_valid()
+                    && wc.opcode() as u32 & ffi::ibv_wc_opcode::IBV_WC_RECV as u32 != 0;
+                (wc.qp_num, wc.wr_id(), wc.is_valid(), is_recv)
+            }));
+            Ok(completions)
+        }
+    }
+
+    /// Like `poll`, but returns the polled work completions as `WorkCompletion`s.
+    ///
+    /// `completions` is only used as the buffer to poll into. Failed work requests are returned as
+    /// `WorkCompletion::Failed`.
+    ///
+    /// # Errors
+    ///
+    ///  - System errors: From the underlying `poll` call.
+    #[inline]
+    pub fn poll_completions<'c>(
+        &self,
+        completions: &'c mut [ffi::ibv_wc],
+    ) -> Result<impl ExactSizeIterator<Item = WorkCompletion> + 'c, Error> {
+        Ok(self.poll(completions)?.iter().map(WorkCompletion::from))
+    }
+
+    /// Arms the CQ, so that it reports a completion event on its `CompletionChannel` once the
+    /// next work completion is added to it.
+    ///
+    /// The CQ is disarmed again once it reported an event. Work completions that are already in
+    /// the CQ do not cause an event, so the CQ should be polled once more after arming it.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::NoCompletionChannel`: The CQ was created without a completion channel.
+    pub fn req_notify(&self) -> Result<(), Error> {
+        self.arm(false)
+    }
+
+    /// Like `req_notify`, but the CQ only reports an event once a work completion for a
+    /// solicited message, or a failed work completion, is added to it.
+    ///
+    /// A received message is solicited if the sender posted it with
+    /// `QueuePair::post_send_solicited`. This lets the receiver sleep through the messages that do
+    /// not need its immediate attention.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::NoCompletionChannel`: The CQ was created without a completion channel.
+    pub fn req_notify_solicited(&self) -> Result<(), Error> {
+        self.arm(true)
+    }
+
+    fn arm(&self, solicited_only: bool) -> Result<(), Error> {
+        if self.inner.cc.is_none() {
+            return Err(Error::NoCompletionChannel);
+        }
+        // SAFETY: dereferencing completion queue context, which is guaranteed to not have
+        // been destroyed yet because we don't destroy it until in Drop, and given we have
+        // self, Drop has not been called. The context is guaranteed to not have been destroyed
+        // because the `CompletionQueue` holds a reference to the `Context` and we only destroy
+        // the context in Drop implementation of the `Context`.
+        let ctx = unsafe { *self.inner.cq }.context;
+        let errno = unsafe {
+            let ops = &mut { &mut *ctx }.ops;
+            ops.req_notify_cq.as_mut().unwrap()(self.inner.cq, solicited_only as i32)
+        };
+        if errno != 0 {
+            return Err(Error::verb("ibv_req_notify_cq", errno).with_cq(self.id()));
+        }
+        Ok(())
+    }
+
+    /// Waits for one or more work completions in a Completion Queue (CQ).
+    ///
+    /// Unlike `poll`, this method blocks until at least one work completion is available or the
+    /// optional timeout expires. It is designed to wait efficiently for completions when polling
+    /// alone is insufficient, such as in low-traffic scenarios.
+    ///
+    /// The returned slice reflects completed work requests (e.g., sends, receives) from the
+    /// associated Work Queue. Not all fields in `ibv_wc` are valid unless the status is
+    /// `IBV_WC_SUCCESS`.
+    ///
+    /// If the CQ shares its `CompletionChannel` with other CQs, this may consume the completion
+    /// events of those CQs. Use `CompletionChannel::next_event` to wait for several CQs instead.
+    ///
+    /// # Errors
+    /// - `Error::TimedOut`: If the timeout expires before any completions are available.
+    /// - `Error::NoCompletionChannel`: If the CQ was created without a completion channel.
+    /// - System errors: From underlying calls like `req_notify_cq`, `poll`, or `ibv_get_cq_event`.
+    pub fn wait<'c>(
+        &self,
+        completions: &'c mut [ffi::ibv_wc],
+        timeout: Option<Duration>,
+    ) -> Result<&'c mut [ffi::ibv_wc], Error> {
+        self.wait_for(completions, timeout, false)
+    }
+
+    /// Waits for one or more work completions, following `strategy`.
+    ///
+    /// The CQ is busy-polled and then polled while yielding, as long as `strategy` allows, before
+    /// blocking like `wait`. Returns the polled completions, and the phase of `strategy` in which
+    /// they were found. The time spent spinning and yielding counts towards `timeout`.
+    ///
+    /// # Errors
+    /// - `Error::TimedOut`: If the timeout expires before any completions are available.
+    /// - `Error::NoCompletionChannel`: If the CQ was created without a completion channel, and
+    ///   `strategy` ran out before any completions were available.
+    /// - System errors: From underlying calls like `req_notify_cq`, `poll`, or `ibv_get_cq_event`.
+    pub fn wait_with<'c>(
+        &self,
+        completions: &'c mut [ffi::ibv_wc],
+        strategy: &WaitStrategy,
+        timeout: Option<Duration>,
+    ) -> Result<(&'c mut [ffi::ibv_wc], WaitPhase), Error> {
+        let start = Instant::now();
+        let c = completions as *mut [ffi::ibv_wc];
+        let polled = strategy.spin_then_yield(|| match self.poll(unsafe { &mut *c }) {
+            Ok([]) => None,
+            Ok(polled) => Some(Ok(polled.len())),
+            Err(e) => Some(Err(e)),
+        });
+        if let Some((n, phase)) = polled {
+            return Ok((&mut completions[..n?], phase));
+        }
+
+        let remaining = match timeout {
+            Some(timeout) => match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Err(Error::TimedOut),
+            },
+            None => None,
+        };
+        let polled = self.wait(completions, remaining)?;
+        Ok((polled, WaitPhase::Block))
+    }
+
+    /// Like `wait`, but only wakes up for solicited messages and failed work requests.
+    ///
+    /// The CQ is armed with `req_notify_solicited`, so completions of unsolicited messages do not
+    /// end the wait. They are returned along with the solicited completion that does, or right
+    /// away if they are already in the CQ when `wait_solicited` is called.
+    ///
+    /// # Errors
+    /// - `Error::TimedOut`: If the timeout expires before a solicited completion is available.
+    /// - `Error::NoCompletionChannel`: If the CQ was created without a completion channel.
+    /// - System errors: From underlying calls like `req_notify_cq`, `poll`, or `ibv_get_cq_event`.
+    pub fn wait_solicited<'c>(
+        &self,
+        completions: &'c mut [ffi::ibv_wc],
+        timeout: Option<Duration>,
+    ) -> Result<&'c mut [ffi::ibv_wc], Error> {
+        self.wait_for(completions, timeout, true)
+    }
+
+    fn wait_for<'c>(
+        &self,
+        completions: &'c mut [ffi::ibv_wc],
+        timeout: Option<Duration>,
+        solicited_only: bool,
+    ) -> Result<&'c mut [ffi::ibv_wc], Error> {
+        let c = completions as *mut [ffi::ibv_wc];
+        let Some(cc) = &self.inner.cc else {
+            return Err(Error::NoCompletionChannel);
+        };
+
+        loop {
+            let polled_completions = self.poll(unsafe { &mut *c })?;
+            if !polled_completions.is_empty() {
+                return Ok(polled_completions);
+            }
+
+            self.arm(solicited_only)?;
+
+            // We poll again to avoid a race when Work Completions arrive between the first `poll()` and `req_notify_cq()`.
+            let polled_completions = self.poll(unsafe { &mut *c })?;
+            if !polled_completions.is_empty() {
+                return Ok(polled_completions);
+            }
+
+            cc.inner.poll(timeout)?;
+            // the event may be for another CQ sharing the channel, in which case we simply poll
+            // again and go back to sleep if there is nothing for us.
+            self.consume_event()?;
+        }
+    }
+
+    /// Reads and acknowledges a pending completion event from the CQ's `CompletionChannel`.
+    ///
+    /// Returns `false` if no event was pending. This is needed when waiting for the channel's
+    /// file descriptor (see `CompletionQueue::channel`) outside of `CompletionQueue::wait`:
+    /// once it becomes readable, the event has to be consumed before the CQ is armed again with
+    /// `CompletionQueue::req_notify`.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::NoCompletionChannel`: The CQ was created without a completion channel.
+    ///  - System errors: From the underlying `ibv_get_cq_event` call.
+    pub fn consume_event(&self) -> Result<bool, Error> {
+        let Some(cc) = &self.inner.cc else {
+            return Err(Error::NoCompletionChannel);
+        };
+        let cq = cc.inner.get_cq_event().map_err(|e| e.with_cq(self.id()))?;
+        Ok(cq.is_some())
+    }
+
+    /// Returns the CQ's `CompletionChannel`, or `None` if the CQ was created with
+    /// `CompletionQueueBuilder::set_polling_only`.
+    ///
+    /// The channel's file descriptor (see its `AsFd` implementation) becomes readable when the
+    /// armed CQ reports a completion event.
+    pub fn channel(&self) -> Option<&CompletionChannel> {
+        self.inner.cc.as_ref()
+    }
+
+    /// Returns the raw file descriptor of the CQ's `CompletionChannel`.
+    #[cfg(feature = "mio")]
+    fn channel_raw_fd(&self) -> io::Result<RawFd> {
+        let cc = self.channel().ok_or(Error::NoCompletionChannel)?;
+        Ok(cc.as_raw_fd())
+    }
+}
+
+/// Registers the file descriptor of the CQ's `CompletionChannel` (see
+/// `CompletionQueue::channel`).
+///
+/// The CQ only becomes readable after it was armed with `CompletionQueue::req_notify`, and the
+/// event has to be consumed with `CompletionQueue::consume_event` once it is. Registering a CQ
+/// without a completion channel fails with `io::ErrorKind::InvalidInput`.
+#[cfg(feature = "mio")]
+impl mio::event::Source for CompletionQueue {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.channel_raw_fd()?).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.channel_raw_fd()?).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.channel_raw_fd()?).deregister(registry)
+    }
+}
+
+/// A completion queue created with `ibv_create_cq_ex`, which can report completion timestamps.
+///
+/// Create one with `CompletionQueueBuilder::build_ex`. It dereferences to a `CompletionQueue`, so
+/// it can be used wherever one is expected, e.g. to create `QueuePair`s or to wait for
+/// completions. Its work completions can be polled either as `ibv_wc` with
+/// `CompletionQueue::poll`, or with their timestamps with `ExtendedCompletionQueue::poll_ex`.
+#[derive(Clone)]
+pub struct ExtendedCompletionQueue {
+    cq: CompletionQueue,
+    wc_flags: ffi::ibv_create_cq_wc_flags,
+}
+
+/// A work completion polled from an `ExtendedCompletionQueue`.
+///
+/// If `status` is not `IBV_WC_SUCCESS`, only `wr_id`, `status` and `vendor_err` are valid.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExtendedWorkCompletion {
+    /// The 64 bit value that was associated with the corresponding Work Request.
+    pub wr_id: u64,
+    /// The status of the completed Work Request.
+    pub status: ibv_wc_status,
+    /// The vendor error syndrome, if `status` is not `IBV_WC_SUCCESS`.
+    pub vendor_err: u32,
+    /// The operation that the corresponding Work Request performed.
+    pub opcode: ibv_wc_opcode,
+    /// The number of bytes transferred.
+    pub byte_len: u32,
+    /// The immediate data sent along with the message, if any.
+    pub imm_data: Option<u32>,
+    /// The local `QueuePair` number of the completed Work Request.
+    pub qp_num: u32,
+    /// The remote `QueuePair` number, for receive completions of UD `QueuePair`s.
+    pub src_qp: u32,
+    /// The bitwise OR of the `ibv_wc_flags` of the completion.
+    pub wc_flags: u32,
+    /// The completion timestamp in HCA clock ticks, if requested with
+    /// `CompletionQueueBuilder::set_completion_timestamp`.
+    pub completion_timestamp: Option<u64>,
+    /// The completion timestamp in nanoseconds since the Unix epoch, if requested with
+    /// `CompletionQueueBuilder::set_completion_wallclock`.
+    pub completion_wallclock_ns: Option<u64>,
+}
+
+impl Default for ExtendedWorkCompletion {
+    fn default() -> Self {
+        ExtendedWorkCompletion {
+            wr_id: 0,
+            status: ibv_wc_status::IBV_WC_GENERAL_ERR,
+            vendor_err: 0,
+            opcode: ibv_wc_opcode::IBV_WC_SEND,
+            byte_len: 0,
+            imm_data: None,
+            qp_num: 0,
+            src_qp: 0,
+            wc_flags: 0,
+            completion_timestamp: None,
+            completion_wallclock_ns: None,
+        }
+    }
+}
+
+impl ExtendedWorkCompletion {
+    /// Returns `true` if the corresponding Work Request completed successfully.
+    pub fn is_valid(&self) -> bool {
+        self.status == ibv_wc_status::IBV_WC_SUCCESS
+    }
+}
+
+impl Deref for ExtendedCompletionQueue {
+    type Target = CompletionQueue;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cq
+    }
+}
+
+impl ExtendedCompletionQueue {
+    fn cq_ex(&self) -> *mut ffi::ibv_cq_ex {
+        self.cq.inner.cq.cast()
+    }
+
+    fn has(&self, flag: ffi::ibv_create_cq_wc_flags) -> bool {
+        (self.wc_flags & flag).0 != 0
+    }
+
+    /// Poll for (possibly multiple) work completions, including their timestamps.
+    ///
+    /// This behaves like `CompletionQueue::poll`, but uses `ibv_start_poll`, `ibv_next_poll` and
+    /// `ibv_end_poll` to read the completions.
+    #[inline]
+    pub fn poll_ex<'c>(
+        &self,
+        completions: &'c mut [ExtendedWorkCompletion],
+    ) -> Result<&'c mut [ExtendedWorkCompletion], Error> {
+        if completions.is_empty() {
+            return Ok(completions);
+        }
+
+        let cq = self.cq_ex();
+        let mut attr = ffi::ibv_poll_cq_attr::default();
+        let errno = unsafe { (*cq).start_poll.unwrap()(cq, &mut attr as *mut _) };
+        match errno {
+            0 => {}
+            nix::libc::ENOENT => return Ok(&mut completions[..0]),
+            errno => return Err(Error::verb("ibv_start_poll", errno).with_cq(self.id())),
+        }
+
+        let mut n = 0;
+        let result = loop {
+            completions[n] = unsafe { self.read_current() };
+            n += 1;
+            if n == completions.len() {
+                break Ok(());
+            }
+            match unsafe { (*cq).next_poll.unwrap()(cq) } {
+                0 => {}
+                nix::libc::ENOENT => break Ok(()),
+                errno => break Err(Error::verb("ibv_next_poll", errno).with_cq(self.id())),
+            }
+        };
+        unsafe { (*cq).end_poll.unwrap()(cq) };
+        self.cq.inner.complete(completions[..n].iter().map(|wc| {
+            let is_recv =
+                wc.is_valid() && wc.opcode as u32 & ffi::ibv_wc_opcode::IBV_WC_RECV as u32 != 0;
+            (wc.qp_num, wc.wr_id, wc.is_valid(), is_recv)
+        }));
+        result.map(|()| &mut completions[..n])
+    }
+
+    /// Reads the completion the CQ currently points to.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called between a successful `start_poll` or `next_poll`, and `end_poll`.
+    unsafe fn read_current(&self) -> ExtendedWorkCompletion {
+        let cq = self.cq_ex();
+        let ops = unsafe { &*cq };
+        let mut wc = ExtendedWorkCompletion {
+            wr_id: ops.wr_id,
+            status: ops.status,
+            ..Default::default()
+        };
+        if !wc.is_valid() {
+            wc.vendor_err = unsafe { ops.read_vendor_err.unwrap()(cq) };
+            return wc;
+        }
+
+        use ffi::ibv_create_cq_wc_flags as f;
+        unsafe {
+            wc.opcode = ops.read_opcode.unwrap()(cq);
+            wc.wc_flags = ops.read_wc_flags.unwrap()(cq);
+            wc.byte_len = ops.read_byte_len.unwrap()(cq);
+            if (wc.wc_flags & ffi::ibv_wc_flags::IBV_WC_WITH_IMM.0) != 0 {
+                wc.imm_data = Some(ops.read_imm_data.unwrap()(cq));
+            }
+            wc.qp_num = ops.read_qp_num.unwrap()(cq);
+            wc.src_qp = ops.read_src_qp.unwrap()(cq);
+            if self.has(f::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP) {
+                wc.completion_timestamp = Some(ops.read_completion_ts.unwrap()(cq));
+            }
+            if self.has(f::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP_WALLCLOCK) {
+                wc.completion_wallclock_ns = Some(ops.read_completion_wallclock_ns.unwrap()(cq));
+            }
+        }
+        wc
+    }
+}
+
+/// Converts HCA clock ticks, as reported by `ExtendedWorkCompletion::completion_timestamp`, to
+/// durations and instants.
+///
+/// Create one with `Context::hca_clock`. It relates the HCA clock to the system's monotonic clock
+/// at the time it was created. Since the two clocks drift apart, a new `HcaClock` should be
+/// created periodically when converting to `Instant`s.
+#[derive(Debug, Copy, Clone)]
+pub struct HcaClock {
+    /// `DeviceAttributes::hca_core_clock`, in kHz
+    khz: u64,
+    /// `DeviceAttributes::completion_timestamp_mask`
+    mask: u64,
+    /// the HCA clock at `reference`
+    ticks: u64,
+    reference: Instant,
+}
+
+impl HcaClock {
+    /// Returns the frequency of the HCA clock in kHz.
+    pub fn frequency_khz(&self) -> u64 {
+        self.khz
+    }
+
+    /// Converts a number of HCA clock ticks to a `Duration`.
+    pub fn ticks_to_duration(&self, ticks: u64) -> Duration {
+        Duration::from_nanos((u128::from(ticks) * 1_000_000 / u128::from(self.khz)) as u64)
+    }
+
+    /// Returns the time between the timestamps `start` and `end`, taking into account that
+    /// timestamps wrap around.
+    pub fn elapsed(&self, start: u64, end: u64) -> Duration {
+        self.ticks_to_duration(end.wrapping_sub(start) & self.mask)
+    }
+
+    /// Converts the timestamp `ticks` to an `Instant` of the system's monotonic clock.
+    ///
+    /// Timestamps up to half the range of the timestamp mask before or after the creation of this
+    /// `HcaClock` are converted correctly.
+    pub fn to_instant(&self, ticks: u64) -> Instant {
+        let after = ticks.wrapping_sub(self.ticks) & self.mask;
+        if after <= self.mask / 2 {
+            self.reference + self.ticks_to_duration(after)
+        } else {
+            let before = self.ticks.wrapping_sub(ticks) & self.mask;
+            self.reference
+                .checked_sub(self.ticks_to_duration(before))
+                .unwrap_or(self.reference)
+        }
+    }
+}
+
+/// An unconfigured `QueuePair`.
+///
+/// A `QueuePairBuilder` is used to configure a `QueuePair` before it is allocated and initialized.
+/// To construct one, use `ProtectionDomain::create_qp`. See also [RDMAmojo] for many more details.
+///
+/// [RDMAmojo]: http://www.rdmamojo.com/2013/01/12/ibv_modify_qp/
+pub struct QueuePairBuilder {
+    ctx: isize,
+    pd: Arc<ProtectionDomainInner>,
+    port_num: u8,
+
+    send: Arc<CompletionQueueInner>,
+    max_send_wr: u32,
+    recv: Arc<CompletionQueueInner>,
+    max_recv_wr: u32,
+    srq: Option<Arc<SharedReceiveQueueInner>>,
+
+    gid_index: Option<u32>,
+    max_send_sge: u32,
+    max_recv_sge: u32,
+    max_inline_data: u32,
+    sq_sig_all: bool,
+    send_tracking: bool,
+
+    qp_type: ffi::ibv_qp_type,
+
+    // carried along to handshake phase
+    /// traffic class set in Global Routing Headers, only used if `gid_index` is set.
+    traffic_class: u8,
+    /// only valid for RC and UC
+    access: Option<ffi::ibv_access_flags>,
+    /// only valid for RC
+    timeout: Option<u8>,
+    /// only valid for RC
+    retry_count: Option<u8>,
+    /// only valid for RC
+    rnr_retry: Option<u8>,
+    /// only valid for RC
+    min_rnr_timer: Option<u8>,
+    /// only valid for RC
+    max_rd_atomic: Option<u8>,
+    /// only valid for RC
+    max_dest_rd_atomic: Option<u8>,
+    /// only valid for RC and UC, defaults to the port's active MTU when unset
+    path_mtu: Option<ibv_mtu>,
+    /// only valid for RC and UC, defaults to the `psn` of the remote endpoint when unset
+    rq_psn: Option<u32>,
+    /// random when unset
+    sq_psn: Option<u32>,
+    /// only valid for UD
+    qkey: Option<u32>,
+    /// service level (0-15). Higher value means higher priority.
+    service_level: u8,
+    pkey_index: u16,
+    src_path_bits: u8,
+    /// only used if `gid_index` is set
+    hop_limit: u8,
+    /// only used if `gid_index` is set
+    flow_label: u32,
+}
+
+impl QueuePairBuilder {
+    /// Prepare a new `QueuePair` builder.
+    ///
+    /// `max_send_wr` is the maximum number of outstanding Work Requests that can be posted to the
+    /// Send Queue in that Queue Pair. Value must be in `[0..dev_cap.max_qp_wr]`. There may be RDMA
+    /// devices that for specific transport types may support less outstanding Work Requests than
+    /// the maximum reported value.
+    ///
+    /// Similarly, `max_recv_wr` is the maximum number of outstanding Work Requests that can be
+    /// posted to the Receive Queue in that Queue Pair. Value must be in `[0..dev_cap.max_qp_wr]`.
+    /// There may be RDMA devices that for specific transport types may support less outstanding
+    /// Work Requests than the maximum reported value. This value is ignored if the Queue Pair is
+    /// associated with an SRQ
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        pd: Arc<ProtectionDomainInner>,
+        send: Arc<CompletionQueueInner>,
+        max_send_wr: u32,
+        recv: Arc<CompletionQueueInner>,
+        max_recv_wr: u32,
+        qp_type: ffi::ibv_qp_type,
+        max_send_sge: u32,
+        max_recv_sge: u32,
+    ) -> QueuePairBuilder {
+        QueuePairBuilder {
+            ctx: 0,
+            pd,
+            port_num: DEFAULT_PORT_NUM,
+
+            gid_index: None,
+            traffic_class: 0,
+            send,
+            max_send_wr,
+            recv,
+            max_recv_wr,
+            srq: None,
+
+            max_send_sge,
+            max_recv_sge,
+            max_inline_data: 0,
+            sq_sig_all: false,
+            send_tracking: false,
+
+            qp_type,
+
+            access: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC
+                || qp_type == ffi::ibv_qp_type::IBV_QPT_UC)
+                .then_some(ffi::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE),
+            min_rnr_timer: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(16),
+            retry_count: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(6),
+            rnr_retry: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(6),
+            timeout: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(4),
+            max_rd_atomic: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(1),
+            max_dest_rd_atomic: (qp_type == ffi::ibv_qp_type::IBV_QPT_RC).then_some(1),
+            path_mtu: None,
+            rq_psn: None,
+            sq_psn: None,
+            qkey: (qp_type == ffi::ibv_qp_type::IBV_QPT_UD).then_some(DEFAULT_QKEY),
+            service_level: 0,
+            pkey_index: 0,
+            src_path_bits: 0,
+            hop_limit: 0xff,
+            flow_label: 0,
+        }
+    }
+
+    /// Set the access flags for the new `QueuePair`.
+    ///
+    /// Valid only for RC and UC QPs.
+    ///
+    /// Defaults to `IBV_ACCESS_LOCAL_WRITE`.
+    pub fn set_access(&mut self, access: ffi::ibv_access_flags) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
+            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
+        {
+            self.access = Some(access);
+        }
+        self
+    }
+
+    /// Set the access flags of the new `QueuePair` such that it allows remote reads and writes.
+    ///
+    /// Valid only for RC and UC QPs.
+    pub fn allow_remote_rw(&mut self) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
+            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
+        {
+            self.access = Some(
+                self.access.expect("always set to Some in new")
+                    | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_WRITE
+                    | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_READ,
+            );
+        }
+        self
+    }
+
+    /// Set the physical port that the new `QueuePair` is associated with.
+    ///
+    /// The port is used to look up the local LID and GID, and is the port the `QueuePair` is bound
+    /// to during `PreparedQueuePair::handshake`. Valid port numbers are returned by
+    /// `Context::ports`.
+    ///
+    /// Defaults to 1.
+    pub fn set_port(&mut self, port_num: u8) -> &mut Self {
+        self.port_num = port_num;
+        self
+    }
+
+    /// Set the service level of the new `QueuePair`.
+    /// service level (0-15). Higher value means higher priority.
+    /// Defaults to 0.
+    pub fn set_service_level(&mut self, service_level: u8) -> &mut Self {
+        self.service_level = service_level;
+        self
+    }
+
+    /// Sets the GID table index that should be used for the new `QueuePair`.
+    /// The entry corresponds to the index in `Context::gid_table()`. This is only used if the
+    /// `QueuePairEndpoint` that is passed to `QueuePair::handshake()` has a `gid`.
+    ///
+    /// Defaults to unset.
+    pub fn set_gid_index(&mut self, gid_index: u32) -> &mut Self {
+        self.gid_index = Some(gid_index);
+        self
+    }
+
+    /// Sets the traffic class of the Global Routing Headers (GRH).
+    ///
+    /// This value is only used if a `gid_index` was specified. Using this value, the originator
+    /// of the packets specifies the required delivery priority for handling them by the routers.
+    ///
+    /// Defaults to 0.
+    pub fn set_traffic_class(&mut self, traffic_class: u8) -> &mut Self {
+        self.traffic_class = traffic_class;
+        self
+    }
+
+    /// Sets the minimum RNR NAK Timer Field Value for the new `QueuePair`.
+    ///
+    /// Defaults to 16 (2.56 ms delay).
+    /// Valid only for RC QPs.
+    ///
+    /// When an incoming message to this QP should consume a Work Request from the Receive Queue,
+    /// but no Work Request is outstanding on that Queue, the QP will send an RNR NAK packet to
+    /// the initiator. It does not affect RNR NAKs sent for other reasons. The value must be one of
+    /// the following values:
+    ///
+    ///  - 0 - 655.36 ms delay
+    ///  - 1 - 0.01 ms delay
+    ///  - 2 - 0.02 ms delay
+    ///  - 3 - 0.03 ms delay
+    ///  - 4 - 0.04 ms delay
+    ///  - 5 - 0.06 ms delay
+    ///  - 6 - 0.08 ms delay
+    ///  - 7 - 0.12 ms delay
+    ///  - 8 - 0.16 ms delay
+    ///  - 9 - 0.24 ms delay
+    ///  - 10 - 0.32 ms delay
+    ///  - 11 - 0.48 ms delay
+    ///  - 12 - 0.64 ms delay
+    ///  - 13 - 0.96 ms delay
+    ///  - 14 - 1.28 ms delay
+    ///  - 15 - 1.92 ms delay
+    ///  - 16 - 2.56 ms delay
+    ///  - 17 - 3.84 ms delay
+    ///  - 18 - 5.12 ms delay
+    ///  - 19 - 7.68 ms delay
+    ///  - 20 - 10.24 ms delay
+    ///  - 21 - 15.36 ms delay
+    ///  - 22 - 20.48 ms delay
+    ///  - 23 - 30.72 ms delay
+    ///  - 24 - 40.96 ms delay
+    ///  - 25 - 61.44 ms delay
+    ///  - 26 - 81.92 ms delay
+    ///  - 27 - 122.88 ms delay
+    ///  - 28 - 163.84 ms delay
+    ///  - 29 - 245.76 ms delay
+    ///  - 30 - 327.68 ms delay
+    ///  - 31 - 491.52 ms delay
+    pub fn set_min_rnr_timer(&mut self, timer: u8) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
+            self.min_rnr_timer = Some(timer);
+        }
+        self
+    }
+
+    /// Sets the minimum timeout that the new `QueuePair` waits for ACK/NACK from remote QP before
+    /// retransmitting the packet.
+    ///
+    /// Defaults to 4 (65.536µs).
+    /// Valid only for RC QPs.
+    ///
+    /// The value zero is special value that waits an infinite time for the ACK/NACK (useful
+    /// for debugging). This means that if any packet in a message is being lost and no ACK or NACK
+    /// is being sent, no retry will ever occur and the QP will just stop sending data.
+    ///
+    /// For any other value of timeout, the time calculation is `4.096*2^timeout`µs, giving:
+    ///
+    ///  - 0 - infinite
+    ///  - 1 - 8.192 µs
+    ///  - 2 - 16.384 µs
+    ///  - 3 - 32.768 µs
+    ///  - 4 - 65.536 µs
+    ///  - 5 - 131.072 µs
+    ///  - 6 - 262.144 µs
+    ///  - 7 - 524.288 µs
+    ///  - 8 - 1.048 ms
+    ///  - 9 - 2.097 ms
+    ///  - 10 - 4.194 ms
+    ///  - 11 - 8.388 ms
+    ///  - 12 - 16.777 ms
+    ///  - 13 - 33.554 ms
+    ///  - 14 - 67.108 ms
+    ///  - 15 - 134.217 ms
+    ///  - 16 - 268.435 ms
+    ///  - 17 - 536.870 ms
+    ///  - 18 - 1.07 s
+    ///  - 19 - 2.14 s
+    ///  - 20 - 4.29 s
+    ///  - 21 - 8.58 s
+    ///  - 22 - 17.1 s
+    ///  - 23 - 34.3 s
+    ///  - 24 - 68.7 s
+    ///  - 25 - 137 s
+    ///  - 26 - 275 s
+    ///  - 27 - 550 s
+    ///  - 28 - 1100 s
+    ///  - 29 - 2200 s
+    ///  - 30 - 4400 s
+    ///  - 31 - 8800 s
+    pub fn set_timeout(&mut self, timeout: u8) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
+            self.timeout = Some(timeout);
+        }
+        self
+    }
+
+    /// Sets the total number of times that the new `QueuePair` will try to resend the packets
+    /// before reporting an error because the remote side doesn't answer in the primary path.
+    ///
+    /// This 3 bit value defaults to 6.
+    /// Valid only for RC QPs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a count higher than 7 is given.
+    pub fn set_retry_count(&mut self, count: u8) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
+            assert!(count <= 7);
+            self.retry_count = Some(count);
+        }
+        self
+    }
+
+    /// Sets the total number of times that the new `QueuePair` will try to resend the packets when
+    /// an RNR NACK was sent by the remote QP before reporting an error.
+    ///
+    /// This 3 bit value defaults to 6. The value 7 is special and specify to retry sending the
+    /// message indefinitely when a RNR Nack is being sent by remote side.
+    /// Valid only for RC QPs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a limit higher than 7 is given.
+    pub fn set_rnr_retry(&mut self, n: u8) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
+            assert!(n <= 7);
+            self.rnr_retry = Some(n);
+        }
+        self
+    }
+
+    /// Set the number of outstanding RDMA reads & atomic operations on the destination Queue Pair.
+    ///
+    /// This defaults to 1.
+    /// Valid only for RC QPs.
+    pub fn set_max_rd_atomic(&mut self, max_rd_atomic: u8) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
+            self.max_rd_atomic = Some(max_rd_atomic);
+        }
+        self
+    }
+
+    /// Set the number of responder resources for handling incoming RDMA reads & atomic operations.
+    ///
+    /// This defaults to 1.
+    /// Valid only for RC QPs.
+    pub fn set_max_dest_rd_atomic(&mut self, max_dest_rd_atomic: u8) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC {
+            self.max_dest_rd_atomic = Some(max_dest_rd_atomic);
+        }
+        self
+    }
+
+    /// Set the path MTU.
+    ///
+    /// Defaults to the port's active_mtu.
+    /// Valid only for RC and UC QPs.
+    /// The possible values are:
+    ///  - 1: 256
+    ///  - 2: 512
+    ///  - 3: 1024
+    ///  - 4: 2048
+    ///  - 5: 4096
+    pub fn set_path_mtu(&mut self, path_mtu: ibv_mtu) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
+            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
+        {
+            self.path_mtu = Some(path_mtu);
+        }
+        self
+    }
+
+    /// Set the PSN for the receive queue.
+    ///
+    /// This must match the `sq_psn` of the remote `QueuePair`. Only the lower 24 bits are used.
+    ///
+    /// Defaults to the `psn` of the remote `QueuePairEndpoint`, which does match.
+    /// Valid only for RC and UC QPs.
+    pub fn set_rq_psn(&mut self, rq_psn: u32) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
+            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
+        {
+            self.rq_psn = Some(rq_psn);
+        }
+        self
+    }
+
+    /// Set the PSN of the first packet sent by the `QueuePair`.
+    ///
+    /// It is carried to the remote side in `QueuePairEndpoint::psn`. Only the lower 24 bits are
+    /// used.
+    ///
+    /// Defaults to a random PSN, so that packets of an earlier connection between the same
+    /// `QueuePair` numbers are not mistaken for packets of this one. `QueuePair::reset` draws a
+    /// new one for the next connection, unless a PSN was set here.
+    pub fn set_sq_psn(&mut self, sq_psn: u32) -> &mut Self {
+        self.sq_psn = Some(sq_psn);
+        self
+    }
+
+    /// Set the index of the partition key of the `QueuePair` in the P_Key table of its port.
+    ///
+    /// Both sides of a connection must be in the same partition. Use `Context::pkey_index` to
+    /// find the index of a partition key.
+    ///
+    /// Defaults to 0, the default partition.
+    pub fn set_pkey_index(&mut self, pkey_index: u16) -> &mut Self {
+        self.pkey_index = pkey_index;
+        self
+    }
+
+    /// Set the source path bits, which select one of the LIDs of the local port if its LMC is
+    /// non-zero (see `PortAttributes::lmc`).
+    ///
+    /// Defaults to 0.
+    pub fn set_src_path_bits(&mut self, src_path_bits: u8) -> &mut Self {
+        self.src_path_bits = src_path_bits;
+        self
+    }
+
+    /// Sets the hop limit of the Global Routing Headers (GRH), i.e. the number of routers a
+    /// packet may pass.
+    ///
+    /// This value is only used if a `gid_index` was specified.
+    ///
+    /// Defaults to 0xff.
+    pub fn set_hop_limit(&mut self, hop_limit: u8) -> &mut Self {
+        self.hop_limit = hop_limit;
+        self
+    }
+
+    /// Sets the flow label of the Global Routing Headers (GRH).
+    ///
+    /// This value is only used if a `gid_index` was specified. Packets of the same flow take the
+    /// same path, and on RoCE v2 the flow label usually selects the UDP source port, which spreads
+    /// different flows over the links of the fabric. Only the lower 20 bits are used.
+    ///
+    /// Defaults to 0.
+    pub fn set_flow_label(&mut self, flow_label: u32) -> &mut Self {
+        self.flow_label = flow_label;
+        self
+    }
+
+    /// Set the Q_Key of the `QueuePair`.
+    ///
+    /// Incoming messages are only accepted if they carry this Q_Key, and senders must pass it to
+    /// `QueuePair::post_send_ud`. Q_Keys with the most significant bit set are controlled Q_Keys,
+    /// which may only be used by privileged processes.
+    ///
+    /// Defaults to `DEFAULT_QKEY`.
+    /// Valid only for UD QPs.
+    pub fn set_qkey(&mut self, qkey: u32) -> &mut Self {
+        if self.qp_type == ffi::ibv_qp_type::IBV_QPT_UD {
+            self.qkey = Some(qkey);
+        }
+        self
+    }
+
+    /// Set the opaque context value for the new `QueuePair`.
+    ///
+    /// Defaults to 0.
+    pub fn set_context(&mut self, ctx: isize) -> &mut Self {
+        self.ctx = ctx;
+        self
+    }
+
+    /// Set the maximum number of send requests in the work queue
+    ///
+    /// Defaults to 1.
+    pub fn set_max_send_wr(&mut self, max_send_wr: u32) -> &mut Self {
+        self.max_send_wr = max_send_wr;
+        self
+    }
+
+    /// The maximum number of scatter/gather elements in any Work Request
+    /// that can be posted to the Send Queue in that Queue Pair.
+    ///
+    /// Value can be [0..dev_cap.max_sge]. There may be RDMA devices that
+    /// for specific transport types may support less scatter/gather elements
+    /// than the maximum reported value.
+    ///
+    /// Defaults to 1.
+    pub fn set_max_send_sge(&mut self, max_send_sge: u32) -> &mut Self {
+        self.max_send_sge = max_send_sge;
+        self
+    }
+
+    /// Set the maximum size of the data that send requests can carry inline, in bytes.
+    ///
+    /// Inline data is copied into the work request, so it is sent without a `MemoryRegion`, and
+    /// its buffer can be reused right away (see `QueuePair::post_send_inline`). The device may
+    /// round this up; the actual value is reported by `QueuePair::max_inline_data`. Creating the
+    /// `QueuePair` fails if the device cannot support this much inline data.
+    ///
+    /// Defaults to 0.
+    pub fn set_max_inline_data(&mut self, max_inline_data: u32) -> &mut Self {
+        self.max_inline_data = max_inline_data;
+        self
+    }
+
+    /// Make every send request generate a work completion, even if it was posted without
+    /// `IBV_SEND_SIGNALED`.
+    ///
+    /// Defaults to `false`.
+    pub fn set_sq_sig_all(&mut self, sq_sig_all: bool) -> &mut Self {
+        self.sq_sig_all = sq_sig_all;
+        self
+    }
+
+    /// Track the send requests of the `QueuePair`, which allows posting unsignaled requests.
+    ///
+    /// Unsignaled requests, posted without `IBV_SEND_SIGNALED` through
+    /// `QueuePair::post_send_with_flags` and its siblings, save the cost of a work completion. In
+    /// exchange, there is no completion that tells when their memory can be reused. With
+    /// tracking, the `QueuePair` learns that from the completions of the signaled requests posted
+    /// after them, as they are polled from the send CQ, and reports it through
+    /// `QueuePair::is_send_complete`.
+    ///
+    /// Tracking costs some bookkeeping on every post and poll, and with credit accounting, every
+    /// request reserves a CQ entry, since failed and flushed unsignaled requests do generate a
+    /// completion. The send CQ must only be polled through this crate, and must not be the
+    /// receive CQ of the `QueuePair`, since failed completions do not tell sends and receives
+    /// apart. Not needed with `set_sq_sig_all`, since all requests are signaled then.
+    ///
+    /// Defaults to `false`.
+    pub fn set_send_tracking(&mut self, enabled: bool) -> &mut Self {
+        self.send_tracking = enabled;
+        self
+    }
+
+    /// Set the maximum number of receive requests in the work queue
+    ///
+    /// Defaults to 1.
+    pub fn set_max_recv_wr(&mut self, max_recv_wr: u32) -> &mut Self {
+        self.max_recv_wr = max_recv_wr;
+        self
+    }
+
+    /// The maximum number of scatter/gather elements in any Work Request
+    /// that can be posted to the Receive Queue in that Queue Pair.
+    ///
+    /// Value can be [0..dev_cap.max_sge]. There may be RDMA devices that
+    /// for specific transport types may support less scatter/gather elements
+    /// than the maximum reported value. This value is ignored if the
+    /// Queue Pair is associated with an SRQ.
+    ///
+    /// Defaults to 1.
+    pub fn set_max_recv_sge(&mut self, max_recv_sge: u32) -> &mut Self {
+        self.max_recv_sge = max_recv_sge;
+        self
+    }
+
+    /// Associate the new `QueuePair` with a shared receive queue.
+    ///
+    /// Receive requests for the `QueuePair` must then be posted with
+    /// `SharedReceiveQueue::post_receive` instead of `QueuePair::post_receive`, and
+    /// `max_recv_wr` and `max_recv_sge` are ignored.
+    ///
+    /// Valid for RC, UC and UD QPs.
+    pub fn set_srq(&mut self, srq: &SharedReceiveQueue) -> &mut Self {
+        self.srq = Some(srq.inner.clone());
+        self
+    }
+
+    /// Create a new `QueuePair` from this builder template.
+    ///
+    /// The returned `QueuePair` is associated with the builder's `ProtectionDomain`.
+    ///
+    /// This method will fail if asked to create QP of a type other than `IBV_QPT_RC` or
+    /// `IBV_QPT_UD` associated with an SRQ.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: Invalid `ProtectionDomain`, sending or receiving `Context`, invalid port, or
+    ///    invalid value provided in `max_send_wr`, `max_recv_wr`, or in `max_inline_data`. Also
+    ///    returned if send tracking is enabled, but the send CQ is also the receive CQ.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///  - `ENOSYS`: QP with this Transport Service Type isn't supported by this RDMA device.
+    ///  - `EPERM`: Not enough permissions to create a QP with this Transport Service Type.
+    ///  - Other: the chosen port is not in `ACTIVE` or `ARMED` state.
+    pub fn build(&self) -> Result<PreparedQueuePair, Error> {
+        let port_attr = self.pd.ctx.query_port(self.port_num)?;
+        let path_mtu = if self.qp_type == ffi::ibv_qp_type::IBV_QPT_RC
+            || self.qp_type == ffi::ibv_qp_type::IBV_QPT_UC
+        {
+            Some(self.path_mtu.unwrap_or(port_attr.active_mtu))
+        } else {
+            None
+        };
+
+        let mut attr = ffi::ibv_qp_init_attr {
+            qp_context: unsafe { ptr::null::<c_void>().offset(self.ctx) } as *mut _,
+            send_cq: self.send.cq as *const _ as *mut _,
+            recv_cq: self.recv.cq as *const _ as *mut _,
+            srq: self.srq.as_ref().map_or(ptr::null_mut(), |srq| srq.srq),
+            cap: ffi::ibv_qp_cap {
+                max_send_wr: self.max_send_wr,
+                max_recv_wr: self.max_recv_wr,
+                max_send_sge: self.max_send_sge,
+                max_recv_sge: self.max_recv_sge,
+                max_inline_data: self.max_inline_data,
+            },
+            qp_type: self.qp_type,
+            sq_sig_all: self.sq_sig_all as i32,
+        };
+
+        let shared_cq = Arc::ptr_eq(&self.send, &self.recv);
+        let tracking = self.send_tracking && !self.sq_sig_all;
+        if tracking && shared_cq {
+            return Err(Error::verb("ibv_create_qp", nix::libc::EINVAL).with_port(self.port_num));
+        }
+        let qp = unsafe { ffi::ibv_create_qp(self.pd.pd, &mut attr as *mut _) };
+        if qp.is_null() {
+            Err(Error::last_os_error("ibv_create_qp").with_port(self.port_num))
+        } else {
+            ResourceCounters::created(&self.pd.ctx.live.qps);
+            let qp_num = unsafe { *qp }.qp_num;
+            let sends = Arc::new(SendTracker::new(qp_num, tracking));
+            if sends.tracking {
+                self.send.register_sender(&sends);
+            }
+            let send_debits = Arc::new(AtomicUsize::new(0));
+            let recv_debits = if shared_cq {
+                send_debits.clone()
+            } else {
+                Arc::new(AtomicUsize::new(0))
+            };
+            self.send
+                .register_debtor(qp_num, &send_debits, shared_cq && self.srq.is_some());
+            if !shared_cq && self.srq.is_none() {
+                self.recv.register_debtor(qp_num, &recv_debits, false);
+            }
+            Ok(PreparedQueuePair {
+                lid: port_attr.lid,
+                qp: QueuePair {
+                    pd: self.pd.clone(),
+                    qp,
+                    cq: (self.send.clone(), self.recv.clone()),
+                    srq: self.srq.clone(),
+                    sq_sig_all: self.sq_sig_all,
+                    max_inline_data: attr.cap.max_inline_data,
+                    sends,
+                    debits: (send_debits, recv_debits),
+                    conn: ConnectionOptions {
+                        port_num: self.port_num,
+                        gid_index: self.gid_index,
+                        path: PathOptions {
+                            service_level: self.service_level,
+                            traffic_class: self.traffic_class,
+                            src_path_bits: self.src_path_bits,
+                            hop_limit: self.hop_limit,
+                            flow_label: self.flow_label,
+                        },
+                        pkey_index: self.pkey_index,
+                        access: self.access,
+                        timeout: self.timeout,
+                        retry_count: self.retry_count,
+                        rnr_retry: self.rnr_retry,
+                        min_rnr_timer: self.min_rnr_timer,
+                        max_rd_atomic: self.max_rd_atomic,
+                        max_dest_rd_atomic: self.max_dest_rd_atomic,
+                        path_mtu,
+                        rq_psn: self.rq_psn,
+                        sq_psn: self.sq_psn.unwrap_or_else(random_psn),
+                        fixed_sq_psn: self.sq_psn.is_some(),
+                        qkey: self.qkey,
+                    },
+                    _state: PhantomData,
+                },
+            })
+        }
+    }
+}
+
+/// An allocated but uninitialized `QueuePair`.
+///
+/// Specifically, this `QueuePair` has been allocated with `ibv_create_qp`, but has not yet been
+/// initialized with calls to `ibv_modify_qp`.
+///
+/// To complete the construction of the `QueuePair`, you will need to obtain the
+/// `QueuePairEndpoint` of the remote end (by using `PreparedQueuePair::endpoint`), and then call
+/// `PreparedQueuePair::handshake` on both sides with the other side's `QueuePairEndpoint`:
+///
+/// ```rust,ignore
+/// // on host 1
+/// let pqp: PreparedQueuePair = ...;
+/// let host1end = pqp.endpoint();
+/// host2.send(host1end);
+/// let host2end = host2.recv();
+/// let qp = pqp.handshake(host2end);
+///
+/// // on host 2
+/// let pqp: PreparedQueuePair = ...;
+/// let host2end = pqp.endpoint();
+/// host1.send(host2end);
+/// let host1end = host1.recv();
+/// let qp = pqp.handshake(host1end);
+/// ```
+pub struct PreparedQueuePair {
+    qp: QueuePair<Reset>,
+    /// port local identifier
+    lid: u16,
+}
+
+/// The attributes that connect a `QueuePair`, carried from the `QueuePairBuilder`.
+///
+/// They are kept with the `QueuePair`, so that it can be connected again by
+/// `QueuePair::reconnect`.
+#[derive(Clone, Copy)]
+struct ConnectionOptions {
+    /// physical port the `QueuePair` is associated with
+    port_num: u8,
+    gid_index: Option<u32>,
+    path: PathOptions,
+    pkey_index: u16,
+    /// only valid for RC and UC
+    access: Option<ffi::ibv_access_flags>,
+    /// only valid for RC
+    min_rnr_timer: Option<u8>,
+    /// only valid for RC
+    timeout: Option<u8>,
+    /// only valid for RC
+    retry_count: Option<u8>,
+    /// only valid for RC
+    rnr_retry: Option<u8>,
+    /// only valid for RC
+    max_rd_atomic: Option<u8>,
+    /// only valid for RC
+    max_dest_rd_atomic: Option<u8>,
+    /// only valid for RC and UC
+    path_mtu: Option<ibv_mtu>,
+    /// only valid for RC and UC, taken from the remote endpoint when unset
+    rq_psn: Option<u32>,
+    sq_psn: u32,
+    /// whether `sq_psn` was set on the builder, rather than drawn at random
+    fixed_sq_psn: bool,
+    /// only valid for UD
+    qkey: Option<u32>,
+}
+
+/// The attributes of the path to a remote `QueuePair`, which end up in its address vector.
+#[derive(Clone, Copy)]
+struct PathOptions {
+    /// service level (0-15). Higher value means higher priority.
+    service_level: u8,
+    /// traffic class set in Global Routing Headers, only used for global routing
+    traffic_class: u8,
+    src_path_bits: u8,
+    /// only used for global routing
+    hop_limit: u8,
+    /// only used for global routing
+    flow_label: u32,
+}
+
+impl Default for PathOptions {
+    fn default() -> Self {
+        PathOptions {
+            service_level: 0,
+            traffic_class: 0,
+            src_path_bits: 0,
+            hop_limit: 0xff,
+            flow_label: 0,
+        }
+    }
+}
+
+/// Returns a random 24 bit packet sequence number.
+fn random_psn() -> u32 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+
+    // every `RandomState` is seeded differently, which is random enough for a PSN
+    RandomState::new().hash_one(()) as u32 & 0xff_ffff
+}
+
+/// A Global identifier for ibv.
+///
+/// This struct acts as a rust wrapper for `ffi::ibv_gid`. We use it instead of
+/// `ffi::ibv_giv` because `ffi::ibv_gid` is actually an untagged union.
+///
+/// ```c
+/// union ibv_gid {
+///     uint8_t   raw[16];
+///     struct {
+///         __be64 subnet_prefix;
+///         __be64 interface_id;
+///     } global;
+/// };
+/// ```
+///
+/// It appears that `global` exists for convenience, but can be safely ignored.
+/// For continuity, the methods `subnet_prefix` and `interface_id` are provided.
+/// These methods read the array as big endian, regardless of native cpu
+/// endianness.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Default, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[repr(C)]
+pub struct Gid {
+    raw: [u8; 16],
+}
+
+impl Gid {
+    /// Expose the subnet_prefix component of the `Gid` as a u64. This is
+    /// equivalent to accessing the `global.subnet_prefix` component of the
+    /// `ffi::ibv_gid` union.
+    pub fn subnet_prefix(&self) -> u64 {
+        u64::from_be_bytes(self.raw[..8].try_into().unwrap())
+    }
+
+    /// Expose the interface_id component of the `Gid` as a u64. This is
+    /// equivalent to accessing the `global.interface_id` component of the
+    /// `ffi::ibv_gid` union.
+    pub fn interface_id(&self) -> u64 {
+        u64::from_be_bytes(self.raw[8..].try_into().unwrap())
+    }
+}
+
+impl From<ffi::ibv_gid> for Gid {
+    fn from(gid: ffi::ibv_gid) -> Self {
+        Self {
+            raw: unsafe { gid.raw },
+        }
+    }
+}
+
+impl From<Gid> for ffi::ibv_gid {
+    fn from(mut gid: Gid) -> Self {
+        *gid.as_mut()
+    }
+}
+
+impl AsRef<ffi::ibv_gid> for Gid {
+    fn as_ref(&self) -> &ffi::ibv_gid {
+        unsafe { &*self.raw.as_ptr().cast::<ffi::ibv_gid>() }
+    }
+}
+
+impl AsMut<ffi::ibv_gid> for Gid {
+    fn as_mut(&mut self) -> &mut ffi::ibv_gid {
+        unsafe { &mut *self.raw.as_mut_ptr().cast::<ffi::ibv_gid>() }
+    }
+}
+
+/// A Global identifier entry for ibv.
+///
+/// This struct acts as a rust wrapper for `ffi::ibv_gid_entry`. We use it instead of
+/// `ffi::ibv_gid_entry` because `ffi::ibv_gid` is wrapped by `Gid`.
+#[derive(Debug, Clone)]
+pub struct GidEntry {
+    /// The GID entry.
+    pub gid: Gid,
+    /// The GID table index of this entry.
+    pub gid_index: u32,
+    /// The port number that this GID belongs to.
+    pub port_num: u32,
+    /// enum ibv_gid_type, can be one of IBV_GID_TYPE_IB, IBV_GID_TYPE_ROCE_V1 or IBV_GID_TYPE_ROCE_V2.
+    pub gid_type: ibv_gid_type,
+    /// The interface index of the net device associated with this GID.
+    ///
+    /// It is 0 if there is no net device associated with it.
+    pub ndev_ifindex: u32,
+}
+
+impl From<ffi::ibv_gid_entry> for GidEntry {
+    fn from(gid_entry: ffi::ibv_gid_entry) -> Self {
+        Self {
+            gid: gid_entry.gid.into(),
+            gid_index: gid_entry.gid_index,
+            port_num: gid_entry.port_num,
+            gid_type: match gid_entry.gid_type {
+                0 => ibv_gid_type::IBV_GID_TYPE_IB,
+                1 => ibv_gid_type::IBV_GID_TYPE_ROCE_V1,
+                2 => ibv_gid_type::IBV_GID_TYPE_ROCE_V2,
+                x => panic!("unknown ibv_gid_type: {x}"),
+            },
+            ndev_ifindex: gid_entry.ndev_ifindex,
+        }
+    }
+}
+
+/// An identifier for the network endpoint of a `QueuePair`.
+///
+/// Internally, this contains the `QueuePair`'s `qp_num`, as well as the context's `lid` and `gid`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(C)]
+pub struct QueuePairEndpoint {
+    /// the `QueuePair`'s `qp_num`
+    pub num: u32,
+    /// the context's `lid`
+    pub lid: u16,
+    /// the context's `gid`, used for global routing
+    pub gid: Option<Gid>,
+    /// the PSN of the first packet the `QueuePair` sends, which the remote `QueuePair` expects
+    /// as its `rq_psn`
+    pub psn: u32,
+}
+
+impl PreparedQueuePair {
+    /// Get the network endpoint for this `QueuePair`.
+    ///
+    /// This endpoint will need to be communicated to the `QueuePair` on the remote end.
+    pub fn endpoint(&self) -> Result<QueuePairEndpoint, Error> {
+        self.qp.endpoint_with_lid(self.lid)
+    }
+
+    /// Set up the `QueuePair` such that it is ready to exchange packets with a remote `QueuePair`.
+    ///
+    /// Internally, this uses `ibv_modify_qp` to mark the `QueuePair` as initialized
+    /// (`IBV_QPS_INIT`), ready to receive (`IBV_QPS_RTR`), and ready to send (`IBV_QPS_RTS`).
+    /// Further discussion of the protocol can be found on [RDMAmojo].
+    ///
+    /// If the endpoint contains a Gid, the routing will be global, with the hop limit, flow label
+    /// and traffic class set on the `QueuePairBuilder`.
+    ///
+    /// The `QueuePair` is bound to the port chosen with `QueuePairBuilder::set_port`.
+    ///
+    /// UD `QueuePair`s are not connected to a single remote `QueuePair`, so for them `remote` is
+    /// ignored and this is the same as `PreparedQueuePair::handshake_ud`.
+    ///
+    /// The `QueuePair` expects the first packet from `remote` to carry `remote.psn`, unless
+    /// `QueuePairBuilder::set_rq_psn` was used. The partition, service level and source path bits
+    /// are also set on the `QueuePairBuilder`.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: Invalid value provided in `attr` or in `attr_mask`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///
+    /// [RDMAmojo]: http://www.rdmamojo.com/2014/01/18/connecting-queue-pairs/
+    pub fn handshake(self, remote: QueuePairEndpoint) -> Result<QueuePair, Error> {
+        self.qp.connect(Some(&remote))?;
+        Ok(self.qp.into_state())
+    }
+
+    /// Initializes the `QueuePair` (`IBV_QPS_INIT`), and associates it with its port, as the
+    /// first step of `PreparedQueuePair::handshake`.
+    ///
+    /// The returned `QueuePair` accepts receive requests. Continue with `QueuePair::to_rtr` once
+    /// the remote `QueuePairEndpoint` is known:
+    ///
+    /// ```rust,ignore
+    /// let qp = pqp.to_init()?;
+    /// let remote = exchange(pqp_endpoint);
+    /// let mut qp = qp.to_rtr(remote)?;
+    /// unsafe { qp.post_receive(&[mr.slice(..)], 0) }?;
+    /// // tell the remote side that receives are posted, and wait for it to do the same
+    /// let mut qp = qp.to_rts()?;
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The `PreparedQueuePair` is handed back in the `ResourceError`, together with:
+    ///
+    ///  - `EINVAL`: Invalid value provided in the attributes set on the `QueuePairBuilder`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn to_init(self) -> Result<QueuePair<Init>, ResourceError<Self>> {
+        match self.qp.modify_to_init(self.qp.is_ud()) {
+            Ok(()) => Ok(self.qp.into_state()),
+            Err(e) => Err(ResourceError::new(self, e)),
+        }
+    }
+
+    /// Set up a UD `QueuePair` such that it is ready to send and receive datagrams.
+    ///
+    /// Unlike RC and UC `QueuePair`s, a UD `QueuePair` is not connected to a remote `QueuePair`.
+    /// Instead, the destination of every send is given to `QueuePair::post_send_ud` as an
+    /// `AddressHandle`, so no remote endpoint is needed here.
+    ///
+    /// Internally, this moves the `QueuePair` to `IBV_QPS_INIT` with the Q_Key set through
+    /// `QueuePairBuilder::set_qkey`, and then to `IBV_QPS_RTR` and `IBV_QPS_RTS`.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: The `QueuePair` is not a UD `QueuePair`, or an invalid value was provided.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn handshake_ud(self) -> Result<QueuePair, Error> {
+        self.qp.connect(None)?;
+        Ok(self.qp.into_state())
+    }
+}
+
+impl QueuePair<Init> {
+    /// Makes the `QueuePair` ready to receive (`IBV_QPS_RTR`) from `remote`, as the second step
+    /// of `PreparedQueuePair::handshake`.
+    ///
+    /// For UD `QueuePair`s, `remote` is ignored.
+    ///
+    /// # Errors
+    ///
+    /// The `QueuePair` is handed back in the `ResourceError`, still initialized, together with:
+    ///
+    ///  - `EINVAL`: Invalid value provided in the attributes set on the `QueuePairBuilder`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///  - `Error::MissingGidIndex`: `remote` has a GID, but no local GID index was set.
+    pub fn to_rtr(self, remote: QueuePairEndpoint) -> Result<QueuePair<Rtr>, ResourceError<Self>> {
+        let remote = Some(&remote).filter(|_| !self.is_ud());
+        match self.modify_to_rtr(remote) {
+            Ok(()) => Ok(self.into_state()),
+            Err(e) => Err(ResourceError::new(self, e)),
+        }
+    }
+}
+
+impl QueuePair<Rtr> {
+    /// Makes the `QueuePair` ready to send (`IBV_QPS_RTS`), as the last step of
+    /// `PreparedQueuePair::handshake`.
+    ///
+    /// # Errors
+    ///
+    /// The `QueuePair` is handed back in the `ResourceError`, still ready to receive, together
+    /// with:
+    ///
+    ///  - `EINVAL`: Invalid value provided in the attributes set on the `QueuePairBuilder`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn to_rts(self) -> Result<QueuePair, ResourceError<Self>> {
+        match self.modify_to_rts(self.is_ud()) {
+            Ok(()) => Ok(self.into_state()),
+            Err(e) => Err(ResourceError::new(self, e)),
+        }
+    }
+}
+
+/// Builds the address vector that reaches `remote` through the local port `port_num`.
+///
+/// If the endpoint contains a Gid, the routing will be global, using the local GID at
+/// `gid_index`.
+fn address_vector(
+    remote: &QueuePairEndpoint,
+    port_num: u8,
+    gid_index: Option<u32>,
+    path: &PathOptions,
+) -> Result<ffi::ibv_ah_attr, Error> {
+    let mut ah_attr = ffi::ibv_ah_attr {
+        dlid: remote.lid,
+        sl: path.service_level,
+        src_path_bits: path.src_path_bits,
+        port_num,
+        grh: Default::default(),
+        ..Default::default()
+    };
+    if let Some(gid) = remote.gid {
+        ah_attr.is_global = 1;
+        ah_attr.grh.dgid = gid.into();
+        ah_attr.grh.flow_label = path.flow_label & 0xf_ffff;
+        ah_attr.grh.hop_limit = path.hop_limit;
+        ah_attr.grh.sgid_index = gid_index.ok_or(Error::MissingGidIndex)? as u8;
+        ah_attr.grh.traffic_class = path.traffic_class;
+    }
+    Ok(ah_attr)
+}
+
+pub struct MemoryRegion {
+    mr: *mut ffi::ibv_mr,
+    bytes: BytesMut,
+    pd: Arc<ProtectionDomainInner>,
+}
+
+unsafe impl Send for MemoryRegion {}
+unsafe impl Sync for MemoryRegion {}
+
+impl MemoryRegion {
+    pub fn lkey(&self) -> u32 {
+        unsafe { (*self.mr).lkey }
+    }
+
+    pub fn rkey(&self) -> u32 {
+        unsafe { (*self.mr).rkey }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    /// Deregisters the memory region, and returns the memory back.
+    ///
+    /// # Errors
+    ///
+    /// The `MemoryRegion` is handed back in the `ResourceError`, still registered, so that
+    /// deregistration can be retried. If it is dropped instead, the memory is leaked, since the
+    /// device may still access it.
+    pub fn deregister(mut self) -> Result<BytesMut, ResourceError<Self>> {
+        match self.dereg() {
+            Ok(()) => Ok(mem::take(&mut self.bytes)),
+            Err(e) => Err(ResourceError::new(self, e)),
+        }
+    }
+
+    /// Deregisters the memory region and frees the memory.
+    ///
+    /// Unlike dropping the `MemoryRegion`, this reports whether deregistration succeeded.
+    ///
+    /// # Errors
+    ///
+    /// See `MemoryRegion::deregister`.
+    pub fn close(self) -> Result<(), ResourceError<Self>> {
+        self.deregister().map(drop)
+    }
+
+    /// Deregisters the memory region, unless that already happened.
+    fn dereg(&mut self) -> Result<(), Error> {
+        if self.mr.is_null() {
+            return Ok(());
+        }
+        let errno = unsafe { ffi::ibv_dereg_mr(self.mr) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_dereg_mr", errno));
+        }
+        self.mr = ptr::null_mut();
+        ResourceCounters::destroyed(&self.pd.ctx.live.mrs);
+        Ok(())
+    }
+
+    pub fn slice_local(
+        &self,
+        bounds: impl RangeBounds<usize>,
+    ) -> impl Iterator<Item = LocalMemorySlice> + '_ {
+        self._slice(bounds).map(|(start, end)| LocalMemorySlice {
+            _sge: ibv_sge {
+                addr: start as u64,
+                length: unsafe { end.offset_from(start) as u32 },
+                lkey: self.lkey(),
+            },
+        })
+    }
+
+    pub fn slice_remote(
+        &self,
+        bounds: impl RangeBounds<usize>,
+    ) -> impl Iterator<Item = RemoteMemorySlice> + '_ {
+        self._slice(bounds).map(|(start, end)| RemoteMemorySlice {
+            addr: start as u64,
+            length: unsafe { end.offset_from(start) } as u32,
+            rkey: self.rkey(),
+        })
+    }
+
+    fn _slice(
+        &self,
+        bounds: impl RangeBounds<usize>,
+    ) -> impl Iterator<Item = (*const u8, *const u8)> + '_ {
+        let start_off = match bounds.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let end_off = match bounds.end_bound() {
+            Bound::Included(&n) => n + 1,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.bytes.len(),
+        };
+        assert!(start_off <= self.bytes.len());
+        assert!(end_off <= self.bytes.len());
+        assert!(start_off <= end_off);
+
+        let start = unsafe { self.bytes.as_ptr().add(start_off) };
+        let end = unsafe { self.bytes.as_ptr().add(end_off) };
+
+        let mut curr = start;
+        iter::from_fn(move || {
+            if curr >= end {
+                return None;
+            }
+
+            let start = curr;
+            let remaining = unsafe { end.offset_from(start) } as usize;
+            let length = remaining.min(u32::MAX as usize);
+
+            curr = unsafe { curr.add(length) };
+            Some((start, curr))
+        })
+    }
+}
+
+impl Deref for MemoryRegion {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for MemoryRegion {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for MemoryRegion {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_slice_mut()
+    }
+}
+
+impl AsMut<[u8]> for MemoryRegion {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_slice_mut()
+    }
+}
+
+impl Drop for MemoryRegion {
+    fn drop(&mut self) {
+        if let Err(e) = self.dereg() {
+            // the device may still access the memory
+            mem::forget(mem::take(&mut self.bytes));
+            log::error!("{e}; leaking the memory region");
+        }
+    }
+}
+
+impl Debug for MemoryRegion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryRegion")
+            .field("addr", &self.as_ptr())
+            .field("length", &self.len())
+            .field("lkey", &self.lkey())
+            .field("rkey", &self.rkey())
+            .finish()
+    }
+}
+
+/// Local memory slice.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(transparent)]
+pub struct LocalMemorySlice {
+    _sge: ffi::ibv_sge,
+}
+
+impl LocalMemorySlice {
+    /// Get the address of the local memory slice.
+    pub fn addr(&self) -> u64 {
+        self._sge.addr
+    }
+
+    /// Get the length of the local memory slice.
+    pub fn len(&self) -> usize {
+        self._sge.length as usize
+    }
+
+    /// Get is_empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the remote key of the local memory slice.
+    pub fn rkey(&self) -> u32 {
+        self._sge.lkey
+    }
+}
+
+/// Remote memory slice.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RemoteMemorySlice {
+    addr: u64,
+    length: u32,
+    rkey: u32,
+}
+
+impl RemoteMemorySlice {
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+    pub fn len(&self) -> usize {
+        self.length as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn rkey(&self) -> u32 {
+        self.rkey
+    }
+
+    pub fn slice(&self, bounds: impl RangeBounds<usize>) -> Self {
+        let start = match bounds.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match bounds.end_bound() {
+            Bound::Included(&n) => n + 1,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(start <= end);
+        let start_addr = self.addr + start as u64;
+        let end_addr = self.addr + end as u64;
+        let length = (end_addr - start_addr)
+            .try_into()
+            .expect("memory region slice length exceeds u32::MAX");
+        Self {
+            addr: start_addr,
+            length,
+            rkey: self.rkey,
+        }
+    }
+}
+
+struct ProtectionDomainInner {
+    ctx: Arc<ContextInner>,
+    pd: *mut ffi::ibv_pd,
+}
+
+impl ProtectionDomainInner {
+    /// Deallocates the PD, unless that already happened.
+    fn close(&mut self) -> Result<(), Error> {
+        if self.pd.is_null() {
+            return Ok(());
+        }
+        let errno = unsafe { ffi::ibv_dealloc_pd(self.pd) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_dealloc_pd", errno));
+        }
+        self.pd = ptr::null_mut();
+        ResourceCounters::destroyed(&self.ctx.live.pds);
+        Ok(())
+    }
+}
+
+impl Drop for ProtectionDomainInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("{e}; leaking the protection domain");
+        }
+    }
+}
+
+unsafe impl Sync for ProtectionDomainInner {}
+unsafe impl Send for ProtectionDomainInner {}
+
+/// A protection domain for a device's context.
+#[derive(Clone)]
+pub struct ProtectionDomain {
+    inner: Arc<ProtectionDomainInner>,
+}
+
+impl ProtectionDomain {
+    /// Creates a queue pair builder associated with this protection domain.
+    ///
+    /// `send` and `recv` are the device `Context` to associate with the send and receive queues
+    /// respectively. `send` and `recv` may refer to the same `Context`.
+    ///
+    /// `qp_type` indicates the requested Transport Service Type of this QP:
+    ///
+    ///  - `IBV_QPT_RC`: Reliable Connection
+    ///  - `IBV_QPT_UC`: Unreliable Connection
+    ///  - `IBV_QPT_UD`: Unreliable Datagram
+    ///
+    /// The `QueuePair` is associated with port 1 unless another port is chosen with
+    /// `QueuePairBuilder::set_port`.
+    ///
+    /// Note that both this protection domain, *and* both provided completion queues, must outlive
+    /// the resulting `QueuePair`.
+    pub fn create_qp(
+        &self,
+        send: &CompletionQueue,
+        recv: &CompletionQueue,
+        qp_type: ffi::ibv_qp_type,
+    ) -> Result<QueuePairBuilder, Error> {
+        Ok(QueuePairBuilder::new(
+            self.inner.clone(),
+            send.inner.clone(),
+            1,
+            recv.inner.clone(),
+            1,
+            qp_type,
+            1,
+            1,
+        ))
+    }
+    pub fn allocate_zeroed(&self, size: usize) -> Result<MemoryRegion, Error> {
+        let bytes = BytesMut::zeroed(size);
+        self.register(bytes)
+    }
+
+    pub unsafe fn allocate(&self, size: usize) -> Result<MemoryRegion, Error> {
+        let mut bytes = BytesMut::with_capacity(size);
+        bytes.set_len(size);
+        self.register(bytes)
+    }
+
+    pub fn register(&self, mut bytes: BytesMut) -> Result<MemoryRegion, Error> {
+        let addr = bytes.as_mut_ptr();
+        let length = bytes.len();
+        let mr = unsafe {
+            ffi::ibv_reg_mr(
+                self.inner.pd,
+                addr.cast(),
+                length,
+                DEFAULT_ACCESS_FLAGS.0 as _,
+            )
+        };
+        if mr.is_null() {
+            Err(Error::last_os_error("ibv_reg_mr"))
+        } else {
+            ResourceCounters::created(&self.inner.ctx.live.mrs);
+            Ok(MemoryRegion {
+                mr,
+                bytes,
+                pd: self.inner.clone(),
+            })
+        }
+    }
+
+    /// Creates a shared receive queue associated with this protection domain.
+    ///
+    /// `max_wr` is the maximum number of outstanding receive requests in the SRQ, and `max_sge` the
+    /// maximum number of scatter/gather elements in any of them. They must not exceed
+    /// `DeviceAttributes::max_srq_wr` and `DeviceAttributes::max_srq_sge` respectively.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: Invalid `max_wr` or `max_sge`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///  - `ENOSYS`: The device does not support SRQs.
+    pub fn create_srq(&self, max_wr: u32, max_sge: u32) -> Result<SharedReceiveQueue, Error> {
+        let mut attr = ffi::ibv_srq_init_attr {
+            srq_context: ptr::null_mut(),
+            attr: ffi::ibv_srq_attr {
+                max_wr,
+                max_sge,
+                srq_limit: 0,
+            },
+        };
+        let srq = unsafe { ffi::ibv_create_srq(self.inner.pd, &mut attr as *mut _) };
+        if srq.is_null() {
+            return Err(Error::last_os_error("ibv_create_srq"));
+        }
+        ResourceCounters::created(&self.inner.ctx.live.srqs);
+        Ok(SharedReceiveQueue {
+            inner: Arc::new(SharedReceiveQueueInner {
+                pd: self.inner.clone(),
+                srq,
+            }),
+        })
+    }
+
+    /// Creates an address handle for sending datagrams to `remote` with
+    /// `QueuePair::post_send_ud`.
+    ///
+    /// The datagrams are sent through the local port `port_num`. If `remote` contains a Gid, they
+    /// are globally routed using the local GID at `gid_index`, which is then required.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: Invalid `port_num` or `remote`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///  - `Error::MissingGidIndex`: `remote` has a Gid, but `gid_index` is `None`.
+    pub fn create_ah(
+        &self,
+        port_num: u8,
+        gid_index: Option<u32>,
+        remote: &QueuePairEndpoint,
+    ) -> Result<AddressHandle, Error> {
+        let mut ah_attr = address_vector(remote, port_num, gid_index, &PathOptions::default())?;
+        let ah = unsafe { ffi::ibv_create_ah(self.inner.pd, &mut ah_attr as *mut _) };
+        if ah.is_null() {
+            return Err(Error::last_os_error("ibv_create_ah").with_port(port_num));
+        }
+        ResourceCounters::created(&self.inner.ctx.live.ahs);
+        Ok(AddressHandle {
+            pd: self.inner.clone(),
+            ah,
+        })
+    }
+
+    /// Deallocates the protection domain.
+    ///
+    /// Unlike dropping the last handle to the PD, this reports whether deallocation succeeded.
+    ///
+    /// # Errors
+    ///
+    /// The PD is handed back in the `ResourceError`, so that deallocation can be retried,
+    /// together with:
+    ///
+    ///  - `Error::InUse`: Other handles to this PD, or resources such as `QueuePair`s or
+    ///    `MemoryRegion`s created from it, are still alive. Dropping this handle instead leaves
+    ///    deallocating the PD to the last of them.
+    ///  - System errors: From `ibv_dealloc_pd`.
+    pub fn close(self) -> Result<(), ResourceError<Self>> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(mut inner) => inner.close().map_err(|e| {
+                let inner = Arc::new(inner);
+                ResourceError::new(ProtectionDomain { inner }, e)
+            }),
+            Err(inner) => Err(ResourceError::new(ProtectionDomain { inner }, Error::InUse)),
+        }
+    }
+}
+
+struct SharedReceiveQueueInner {
+    pd: Arc<ProtectionDomainInner>,
+    srq: *mut ffi::ibv_srq,
+}
+
+impl SharedReceiveQueueInner {
+    /// Destroys the SRQ, unless that already happened.
+    fn close(&mut self) -> Result<(), Error> {
+        if self.srq.is_null() {
+            return Ok(());
+        }
+        let errno = unsafe { ffi::ibv_destroy_srq(self.srq) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_destroy_srq", errno));
+        }
+        self.srq = ptr::null_mut();
+        ResourceCounters::destroyed(&self.pd.ctx.live.srqs);
+        Ok(())
+    }
+}
+
+impl Drop for SharedReceiveQueueInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("{e}; leaking the shared receive queue");
+        }
+    }
+}
+
+unsafe impl Sync for SharedReceiveQueueInner {}
+unsafe impl Send for SharedReceiveQueueInner {}
+
+/// A shared receive queue (SRQ).
+///
+/// An SRQ holds receive requests on behalf of many `QueuePair`s, so that they can share a single
+/// pool of receive buffers instead of each `QueuePair` keeping its own receive queue filled. A
+/// `QueuePair` uses an SRQ if it is associated with one through `QueuePairBuilder::set_srq`.
+///
+/// Completions of receive requests posted to an SRQ are reported on the receive `CompletionQueue`
+/// of the `QueuePair` that the message arrived on.
+///
+/// See also [RDMAmojo] for more details.
+///
+/// [RDMAmojo]: https://www.rdmamojo.com/2012/05/28/ibv_create_srq/
+#[derive(Clone)]
+pub struct SharedReceiveQueue {
+    inner: Arc<SharedReceiveQueueInner>,
+}
+
+/// The attributes of a `SharedReceiveQueue`, as returned by `SharedReceiveQueue::query`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct SrqAttributes {
+    /// The maximum number of outstanding receive requests in the SRQ.
+    pub max_wr: u32,
+    /// The maximum number of scatter/gather elements in any receive request.
+    pub max_sge: u32,
+    /// The limit below which the number of outstanding receive requests raises an
+    /// `AsyncEvent::SrqLimitReached`, or 0 if the limit is not armed.
+    pub srq_limit: u32,
+}
+
+impl From<ffi::ibv_srq_attr> for SrqAttributes {
+    fn from(attr: ffi::ibv_srq_attr) -> Self {
+        SrqAttributes {
+            max_wr: attr.max_wr,
+            max_sge: attr.max_sge,
+            srq_limit: attr.srq_limit,
+        }
+    }
+}
+
+impl SharedReceiveQueue {
+    /// Returns the kernel handle of this SRQ.
+    ///
+    /// This is the `srq_handle` that is reported by the `AsyncEvent`s concerning this SRQ.
+    pub fn handle(&self) -> u32 {
+        unsafe { *self.inner.srq }.handle
+    }
+
+    /// Arms the SRQ limit.
+    ///
+    /// Once the number of outstanding receive requests in the SRQ drops below `srq_limit`, an
+    /// `AsyncEvent::SrqLimitReached` is raised and the limit is disarmed again. This allows
+    /// replenishing the SRQ before it runs empty.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `srq_limit` is larger than the maximum number of outstanding receive requests.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn modify(&self, srq_limit: u32) -> Result<(), Error> {
+        let mut attr = ffi::ibv_srq_attr {
+            srq_limit,
+            ..Default::default()
+        };
+        let errno = unsafe {
+            ffi::ibv_modify_srq(
+                self.inner.srq,
+                &mut attr as *mut _,
+                ffi::ibv_srq_attr_mask::IBV_SRQ_LIMIT as i32,
+            )
+        };
+        if errno != 0 {
+            return Err(Error::verb("ibv_modify_srq", errno));
+        }
+        Ok(())
     }
 
-    /// Create a new `QueuePair` from this builder template.
+    /// Queries the current attributes of the SRQ.
+    pub fn query(&self) -> Result<SrqAttributes, Error> {
+        let mut attr = ffi::ibv_srq_attr::default();
+        let errno = unsafe { ffi::ibv_query_srq(self.inner.srq, &mut attr as *mut _) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_query_srq", errno));
+        }
+        Ok(attr.into())
+    }
+
+    /// Posts a receive request to the SRQ.
     ///
-    /// The returned `QueuePair` is associated with the builder's `ProtectionDomain`.
+    /// The request can be consumed by a message arriving on any of the `QueuePair`s associated
+    /// with the SRQ. The completion is reported on the receive `CompletionQueue` of that
+    /// `QueuePair`, and `wr_id` is echoed in it.
     ///
-    /// This method will fail if asked to create QP of a type other than `IBV_QPT_RC` or
-    /// `IBV_QPT_UD` associated with an SRQ.
+    /// Unlike `QueuePair::post_receive`, this takes `&self`, since posting to an SRQ is
+    /// thread-safe.
+    ///
+    /// # Safety
+    ///
+    /// The memory region can only be safely reused or dropped after the request is fully executed
+    /// and a work completion has been retrieved from the corresponding completion queue.
     ///
     /// # Errors
     ///
-    ///  - `EINVAL`: Invalid `ProtectionDomain`, sending or receiving `Context`, or invalid value
-    ///    provided in `max_send_wr`, `max_recv_wr`, or in `max_inline_data`.
-    ///  - `ENOMEM`: Not enough resources to complete this operation.
-    ///  - `ENOSYS`: QP with this Transport Service Type isn't supported by this RDMA device.
-    ///  - `EPERM`: Not enough permissions to create a QP with this Transport Service Type.
-    pub fn build(&self) -> io::Result<PreparedQueuePair> {
-        let mut attr = ffi::ibv_qp_init_attr {
-            qp_context: unsafe { ptr::null::<c_void>().offset(self.ctx) } as *mut _,
-            send_cq: self.send.cq as *const _ as *mut _,
-            recv_cq: self.recv.cq as *const _ as *mut _,
-            srq: ptr::null::<ffi::ibv_srq>() as *mut _,
-            cap: ffi::ibv_qp_cap {
-                max_send_wr: self.max_send_wr,
-                max_recv_wr: self.max_recv_wr,
-                max_send_sge: self.max_send_sge,
-                max_recv_sge: self.max_recv_sge,
-                max_inline_data: self.max_inline_data,
-            },
-            qp_type: self.qp_type,
-            sq_sig_all: 0,
+    ///  - `EINVAL`: Invalid value provided in the Work Request.
+    ///  - `ENOMEM`: The SRQ is full or not enough resources to complete this operation.
+    #[inline]
+    pub unsafe fn post_receive(&self, local: &[LocalMemorySlice], wr_id: u64) -> Result<(), Error> {
+        let mut wr = ffi::ibv_recv_wr {
+            wr_id,
+            next: ptr::null_mut(),
+            sg_list: local.as_ptr() as *mut ffi::ibv_sge,
+            num_sge: local.len() as i32,
         };
+        let mut bad_wr: *mut ffi::ibv_recv_wr = ptr::null_mut();
 
-        let qp = unsafe { ffi::ibv_create_qp(self.pd.pd, &mut attr as *mut _) };
-        if qp.is_null() {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(PreparedQueuePair {
-                lid: self.port_attr.lid,
-                qp: QueuePair {
-                    pd: self.pd.clone(),
-                    qp,
-                    _cq: (self.send.clone(), self.recv.clone()),
-                },
-                gid_index: self.gid_index,
-                traffic_class: self.traffic_class,
-                access: self.access,
-                timeout: self.timeout,
-                retry_count: self.retry_count,
-                rnr_retry: self.rnr_retry,
-                min_rnr_timer: self.min_rnr_timer,
-                max_rd_atomic: self.max_rd_atomic,
-                max_dest_rd_atomic: self.max_dest_rd_atomic,
-                path_mtu: self.path_mtu,
-                rq_psn: self.rq_psn,
-                service_level: self.service_level,
-            })
+        let ctx = unsafe { *self.inner.srq }.context;
+        let ops = &mut unsafe { *ctx }.ops;
+        let errno = unsafe {
+            ops.post_srq_recv.as_mut().unwrap()(
+                self.inner.srq,
+                &mut wr as *mut _,
+                &mut bad_wr as *mut _,
+            )
+        };
+        if errno != 0 {
+            return Err(Error::verb("ibv_post_srq_recv", errno));
         }
+        Ok(())
     }
-}
 
-/// An allocated but uninitialized `QueuePair`.
-///
-/// Specifically, this `QueuePair` has been allocated with `ibv_create_qp`, but has not yet been
-/// initialized with calls to `ibv_modify_qp`.
-///
-/// To complete the construction of the `QueuePair`, you will need to obtain the
-/// `QueuePairEndpoint` of the remote end (by using `PreparedQueuePair::endpoint`), and then call
-/// `PreparedQueuePair::handshake` on both sides with the other side's `QueuePairEndpoint`:
-///
-/// ```rust,ignore
-/// // on host 1
-/// let pqp: PreparedQueuePair = ...;
-/// let host1end = pqp.endpoint();
-/// host2.send(host1end);
-/// let host2end = host2.recv();
-/// let qp = pqp.handshake(host2end);
-///
-/// // on host 2
-/// let pqp: PreparedQueuePair = ...;
-/// let host2end = pqp.endpoint();
-/// host1.send(host2end);
-/// let host1end = host1.recv();
-/// let qp = pqp.handshake(host1end);
-/// ```
-pub struct PreparedQueuePair {
-    qp: QueuePair,
-    /// port local identifier
-    lid: u16,
-    // carried from builder
-    gid_index: Option<u32>,
-    /// traffic class set in Global Routing Headers, only used if `gid_index` is set.
-    traffic_class: u8,
-    /// only valid for RC and UC
-    access: Option<ffi::ibv_access_flags>,
-    /// only valid for RC
-    min_rnr_timer: Option<u8>,
-    /// only valid for RC
-    timeout: Option<u8>,
-    /// only valid for RC
-    retry_count: Option<u8>,
-    /// only valid for RC
-    rnr_retry: Option<u8>,
-    /// only valid for RC
-    max_rd_atomic: Option<u8>,
-    /// only valid for RC
-    max_dest_rd_atomic: Option<u8>,
-    /// only valid for RC and UC
-    path_mtu: Option<ibv_mtu>,
-    /// only valid for RC and UC
-    rq_psn: Option<u32>,
-    /// service level (0-15). Higher value means higher priority.
-    service_level: u8,
+    /// Destroys the SRQ.
+    ///
+    /// Unlike dropping the last handle to the SRQ, this reports whether destroying it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// The SRQ is handed back in the `ResourceError`, so that destroying it can be retried,
+    /// together with:
+    ///
+    ///  - `Error::InUse`: Other handles to this SRQ, or `QueuePair`s associated with it, are
+    ///    still alive. Dropping this handle instead leaves destroying the SRQ to the last of them.
+    ///  - System errors: From `ibv_destroy_srq`.
+    pub fn close(self) -> Result<(), ResourceError<Self>> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(mut inner) => inner.close().map_err(|e| {
+                let inner = Arc::new(inner);
+                ResourceError::new(SharedReceiveQueue { inner }, e)
+            }),
+            Err(inner) => Err(ResourceError::new(
+                SharedReceiveQueue { inner },
+                Error::InUse,
+            )),
+        }
+    }
 }
 
-/// A Global identifier for ibv.
-///
-/// This struct acts as a rust wrapper for `ffi::ibv_gid`. We use it instead of
-/// `ffi::ibv_giv` because `ffi::ibv_gid` is actually an untagged union.
-///
-/// ```c
-/// union ibv_gid {
-///     uint8_t   raw[16];
-///     struct {
-///         __be64 subnet_prefix;
-///         __be64 interface_id;
-///     } global;
-/// };
-/// ```
+/// The address of a remote node, used to send datagrams with `QueuePair::post_send_ud`.
 ///
-/// It appears that `global` exists for convenience, but can be safely ignored.
-/// For continuity, the methods `subnet_prefix` and `interface_id` are provided.
-/// These methods read the array as big endian, regardless of native cpu
-/// endianness.
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Default, Copy, Clone, Debug, Eq, PartialEq, Hash)]
-#[repr(C)]
-pub struct Gid {
-    raw: [u8; 16],
+/// Create one with `ProtectionDomain::create_ah`. A single `AddressHandle` can be used to reach
+/// all `QueuePair`s on the same remote port.
+pub struct AddressHandle {
+    pd: Arc<ProtectionDomainInner>,
+    ah: *mut ffi::ibv_ah,
 }
 
-impl Gid {
-    /// Expose the subnet_prefix component of the `Gid` as a u64. This is
-    /// equivalent to accessing the `global.subnet_prefix` component of the
-    /// `ffi::ibv_gid` union.
-    pub fn subnet_prefix(&self) -> u64 {
-        u64::from_be_bytes(self.raw[..8].try_into().unwrap())
+unsafe impl Send for AddressHandle {}
+unsafe impl Sync for AddressHandle {}
+
+impl AddressHandle {
+    /// Destroys the address handle.
+    ///
+    /// Unlike dropping the `AddressHandle`, this reports whether destroying it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// The `AddressHandle` is handed back in the `ResourceError`, so that destroying it can be
+    /// retried, together with the error of `ibv_destroy_ah`.
+    pub fn close(mut self) -> Result<(), ResourceError<Self>> {
+        match self.destroy() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(ResourceError::new(self, e)),
+        }
     }
 
-    /// Expose the interface_id component of the `Gid` as a u64. This is
-    /// equivalent to accessing the `global.interface_id` component of the
-    /// `ffi::ibv_gid` union.
-    pub fn interface_id(&self) -> u64 {
-        u64::from_be_bytes(self.raw[8..].try_into().unwrap())
+    /// Destroys the address handle, unless that already happened.
+    fn destroy(&mut self) -> Result<(), Error> {
+        if self.ah.is_null() {
+            return Ok(());
+        }
+        let errno = unsafe { ffi::ibv_destroy_ah(self.ah) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_destroy_ah", errno));
+        }
+        self.ah = ptr::null_mut();
+        ResourceCounters::destroyed(&self.pd.ctx.live.ahs);
+        Ok(())
     }
 }
 
-impl From<ffi::ibv_gid> for Gid {
-    fn from(gid: ffi::ibv_gid) -> Self {
-        Self {
-            raw: unsafe { gid.raw },
+impl Drop for AddressHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.destroy() {
+            log::error!("{e}; leaking the address handle");
         }
     }
 }
 
-impl From<Gid> for ffi::ibv_gid {
-    fn from(mut gid: Gid) -> Self {
-        *gid.as_mut()
-    }
+/// The Global Routing Header (GRH) of a datagram received by a UD `QueuePair`.
+///
+/// Receive requests of UD `QueuePair`s get the GRH of the incoming message in the first
+/// `Grh::LEN` bytes of their buffer, followed by the actual message. If the message did not have
+/// a GRH, these bytes are undefined, but reserved nevertheless. The `IBV_WC_GRH` flag of the
+/// work completion tells the two cases apart.
+///
+/// For RoCE v2 over IPv4, the last 20 bytes of the GRH area contain the IPv4 header instead, and
+/// the fields of a parsed `Grh` are not meaningful.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Grh {
+    /// The traffic class of the message.
+    pub traffic_class: u8,
+    /// The flow label of the message.
+    pub flow_label: u32,
+    /// The length of the message after the GRH, in bytes.
+    pub payload_length: u16,
+    /// The next header field, which identifies the header following the GRH.
+    pub next_header: u8,
+    /// The remaining hop limit of the message.
+    pub hop_limit: u8,
+    /// The GID of the sender.
+    pub sgid: Gid,
+    /// The GID the message was sent to.
+    pub dgid: Gid,
 }
 
-impl AsRef<ffi::ibv_gid> for Gid {
-    fn as_ref(&self) -> &ffi::ibv_gid {
-        unsafe { &*self.raw.as_ptr().cast::<ffi::ibv_gid>() }
+impl Grh {
+    /// The number of bytes reserved for the GRH at the start of UD receive buffers.
+    pub const LEN: usize = 40;
+
+    /// Parses the GRH at the start of `buf`, which was received into with the completion `wc`.
+    ///
+    /// Returns `None` if the message did not have a GRH, or if `buf` is too short.
+    pub fn parse(wc: &ibv_wc, buf: &[u8]) -> Option<Grh> {
+        if (wc.wc_flags & ffi::ibv_wc_flags::IBV_WC_GRH).0 == 0 || buf.len() < Self::LEN {
+            return None;
+        }
+        let version_class_flow = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+        Some(Grh {
+            traffic_class: (version_class_flow >> 20) as u8,
+            flow_label: version_class_flow & 0xf_ffff,
+            payload_length: u16::from_be_bytes(buf[4..6].try_into().unwrap()),
+            next_header: buf[6],
+            hop_limit: buf[7],
+            sgid: Gid {
+                raw: buf[8..24].try_into().unwrap(),
+            },
+            dgid: Gid {
+                raw: buf[24..40].try_into().unwrap(),
+            },
+        })
     }
-}
 
-impl AsMut<ffi::ibv_gid> for Gid {
-    fn as_mut(&mut self) -> &mut ffi::ibv_gid {
-        unsafe { &mut *self.raw.as_mut_ptr().cast::<ffi::ibv_gid>() }
+    /// Returns the message in `buf` without the space reserved for the GRH, where `buf` was
+    /// received into with the completion `wc`.
+    pub fn payload<'a>(wc: &ibv_wc, buf: &'a [u8]) -> &'a [u8] {
+        let end = wc.len().min(buf.len());
+        buf.get(Self::LEN..end).unwrap_or_default()
     }
 }
 
-/// A Global identifier entry for ibv.
+/// A fully initialized and ready `QueuePair`.
 ///
-/// This struct acts as a rust wrapper for `ffi::ibv_gid_entry`. We use it instead of
-/// `ffi::ibv_gid_entry` because `ffi::ibv_gid` is wrapped by `Gid`.
-#[derive(Debug, Clone)]
-pub struct GidEntry {
-    /// The GID entry.
-    pub gid: Gid,
-    /// The GID table index of this entry.
-    pub gid_index: u32,
-    /// The port number that this GID belongs to.
-    pub port_num: u32,
-    /// enum ibv_gid_type, can be one of IBV_GID_TYPE_IB, IBV_GID_TYPE_ROCE_V1 or IBV_GID_TYPE_ROCE_V2.
-    pub gid_type: ibv_gid_type,
-    /// The interface index of the net device associated with this GID.
-    ///
-    /// It is 0 if there is no net device associated with it.
-    pub ndev_ifindex: u32,
+/// A queue pair is the actual object that sends and receives data in the RDMA architecture
+/// (something like a socket). It's not exactly like a socket, however. A socket is an abstraction,
+/// which is maintained by the network stack and doesn't have a physical resource behind it. A QP
+/// is a resource of an RDMA device and a QP number can be used by one process at the same time
+/// (similar to a socket that is associated with a specific TCP or UDP port number)
+///
+/// The type parameter is the state the `QueuePair` was brought into: `Init`, `Rtr`, or `Rts`,
+/// the default, and `Drained` or `Reset` on the way to reconnecting it. Receive requests can be
+/// posted in the states that implement `Receiving`, send requests only in `Rts`. See
+/// `PreparedQueuePair::to_init` for taking the states one at a time.
+pub struct QueuePair<S = Rts> {
+    pd: Arc<ProtectionDomainInner>,
+    qp: *mut ffi::ibv_qp,
+    /// the send and the receive CQ
+    cq: (Arc<CompletionQueueInner>, Arc<CompletionQueueInner>),
+    srq: Option<Arc<SharedReceiveQueueInner>>,
+    sq_sig_all: bool,
+    /// the inline data capacity the QP was created with
+    max_inline_data: u32,
+    sends: Arc<SendTracker>,
+    /// the CQ entries reserved in the send and the receive CQ, if they do credit accounting
+    debits: (Arc<AtomicUsize>, Arc<AtomicUsize>),
+    conn: ConnectionOptions,
+    _state: PhantomData<fn() -> S>,
 }
 
-impl From<ffi::ibv_gid_entry> for GidEntry {
-    fn from(gid_entry: ffi::ibv_gid_entry) -> Self {
-        Self {
-            gid: gid_entry.gid.into(),
-            gid_index: gid_entry.gid_index,
-            port_num: gid_entry.port_num,
-            gid_type: match gid_entry.gid_type {
-                0 => ibv_gid_type::IBV_GID_TYPE_IB,
-                1 => ibv_gid_type::IBV_GID_TYPE_ROCE_V1,
-                2 => ibv_gid_type::IBV_GID_TYPE_ROCE_V2,
-                x => panic!("unknown ibv_gid_type: {x}"),
-            },
-            ndev_ifindex: gid_entry.ndev_ifindex,
+/// The attributes of a `QueuePair`, as returned by `QueuePair::query`.
+#[derive(Debug, Clone, Copy)]
+pub struct QueuePairAttributes {
+    /// The current state of the `QueuePair`.
+    pub state: ibv_qp_state,
+    /// The path MTU. Only valid for RC and UC `QueuePair`s.
+    pub path_mtu: ibv_mtu,
+    /// The number of the remote `QueuePair`. Only valid for RC and UC `QueuePair`s.
+    pub dest_qp_num: u32,
+    /// The packet sequence number of the next packet sent.
+    pub sq_psn: u32,
+    /// The packet sequence number expected next from the remote `QueuePair`.
+    pub rq_psn: u32,
+    /// The Q_Key. Only valid for UD `QueuePair`s.
+    pub qkey: u32,
+    /// The allowed incoming RDMA and atomic operations. Only valid for RC and UC `QueuePair`s.
+    pub access: ibv_access_flags,
+    /// The index into the P_Key table of the port.
+    pub pkey_index: u16,
+    /// The physical port the `QueuePair` is associated with.
+    pub port_num: u8,
+    /// The local ACK timeout, as an exponent of 4.096 µs. Only valid for RC `QueuePair`s.
+    pub timeout: u8,
+    /// The number of retries after an ACK timeout. Only valid for RC `QueuePair`s.
+    pub retry_count: u8,
+    /// The number of retries after an RNR NAK. Only valid for RC `QueuePair`s.
+    pub rnr_retry: u8,
+    /// The RNR NAK timer code. Only valid for RC `QueuePair`s.
+    pub min_rnr_timer: u8,
+    /// The number of outstanding RDMA reads and atomics as initiator.
+    pub max_rd_atomic: u8,
+    /// The number of outstanding RDMA reads and atomics as target.
+    pub max_dest_rd_atomic: u8,
+    /// Whether the Send Queue is still draining, in `IBV_QPS_SQD`.
+    pub sq_draining: bool,
+    /// The maximum number of outstanding send requests.
+    pub max_send_wr: u32,
+    /// The maximum number of outstanding receive requests.
+    pub max_recv_wr: u32,
+    /// The maximum inline data, see `QueuePair::max_inline_data`.
+    pub max_inline_data: u32,
+}
+
+impl From<ffi::ibv_qp_attr> for QueuePairAttributes {
+    fn from(attr: ffi::ibv_qp_attr) -> Self {
+        QueuePairAttributes {
+            state: attr.qp_state,
+            path_mtu: attr.path_mtu,
+            dest_qp_num: attr.dest_qp_num,
+            sq_psn: attr.sq_psn,
+            rq_psn: attr.rq_psn,
+            qkey: attr.qkey,
+            access: ibv_access_flags(attr.qp_access_flags),
+            pkey_index: attr.pkey_index,
+            port_num: attr.port_num,
+            timeout: attr.timeout,
+            retry_count: attr.retry_cnt,
+            rnr_retry: attr.rnr_retry,
+            min_rnr_timer: attr.min_rnr_timer,
+            max_rd_atomic: attr.max_rd_atomic,
+            max_dest_rd_atomic: attr.max_dest_rd_atomic,
+            sq_draining: attr.sq_draining != 0,
+            max_send_wr: attr.cap.max_send_wr,
+            max_recv_wr: attr.cap.max_recv_wr,
+            max_inline_data: attr.cap.max_inline_data,
         }
     }
 }
 
-/// An identifier for the network endpoint of a `QueuePair`.
+/// Tracks the send requests of a `QueuePair`, to tell when the memory of unsignaled requests can
+/// be reused.
 ///
-/// Internally, this contains the `QueuePair`'s `qp_num`, as well as the context's `lid` and `gid`.
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[repr(C)]
-pub struct QueuePairEndpoint {
-    /// the `QueuePair`'s `qp_num`
-    pub num: u32,
-    /// the context's `lid`
-    pub lid: u16,
-    /// the context's `gid`, used for global routing
-    pub gid: Option<Gid>,
+/// Each posted send request gets a sequence number. The requests of a Send Queue complete in
+/// order, so once the completion of a signaled request is polled, all requests posted before it
+/// are complete too.
+struct SendTracker {
+    qp_num: u32,
+    /// whether `pending` and `completed` are maintained
+    tracking: bool,
+    next_seq: AtomicU64,
+    /// all requests with a lower sequence number are complete
+    completed: AtomicU64,
+    /// the `wr_id`s of all posted requests from sequence number `completed` on, in posting order,
+    /// and whether they are signaled
+    pending: Mutex<VecDeque<(u64, bool)>>,
 }
 
-impl PreparedQueuePair {
-    /// Get the network endpoint for this `QueuePair`.
+impl SendTracker {
+    fn new(qp_num: u32, tracking: bool) -> Self {
+        SendTracker {
+            qp_num,
+            tracking,
+            next_seq: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn pending(&self) -> MutexGuard<'_, VecDeque<(u64, bool)>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Forgets the pending requests, which were discarded by resetting the `QueuePair`.
+    fn reset(&self) {
+        let mut pending = self.pending();
+        pending.clear();
+        let next_seq = self.next_seq.load(Ordering::Relaxed);
+        self.completed.store(next_seq, Ordering::Release);
+    }
+
+    /// Retires the requests up to the one that a Send Queue completion for `wr_id` is for, and
+    /// returns how many were retired.
     ///
-    /// This endpoint will need to be communicated to the `QueuePair` on the remote end.
-    pub fn endpoint(&self) -> io::Result<QueuePairEndpoint> {
-        let num = unsafe { &*self.qp.qp }.qp_num;
-        let gid = if let Some(gid_index) = self.gid_index {
-            let mut gid = ffi::ibv_gid::default();
-            let rc = unsafe {
-                ffi::ibv_query_gid(self.qp.pd.ctx.ctx, PORT_NUM, gid_index as i32, &mut gid)
-            };
-            if rc < 0 {
-                return Err(io::Error::last_os_error());
+    /// Send Queue completions arrive in posting order. A successful one is for the oldest pending
+    /// signaled request, and the unsignaled requests before it completed silently. A failed one
+    /// is for the oldest pending request with its `wr_id`, signaled or not; from then on, every
+    /// request gets a (flushed) completion of its own. A completion that matches neither, which
+    /// should not happen, retires nothing.
+    fn retire(&self, wr_id: u64, success: bool) -> usize {
+        let mut pending = self.pending();
+        let mut retired = 0;
+        for &(id, signaled) in pending.iter() {
+            retired += 1;
+            if id == wr_id && (signaled || !success) {
+                pending.drain(..retired);
+                self.completed.fetch_add(retired as u64, Ordering::Release);
+                return retired;
             }
-            Some(Gid::from(gid))
-        } else {
-            None
-        };
-        Ok(QueuePairEndpoint {
-            num,
-            lid: self.lid,
-            gid,
-        })
+            if signaled {
+                break;
+            }
+        }
+        0
     }
+}
 
-    /// Set up the `QueuePair` such that it is ready to exchange packets with a remote `QueuePair`.
+unsafe impl<S> Send for QueuePair<S> {}
+unsafe impl<S> Sync for QueuePair<S> {}
+
+impl<S> QueuePair<S> {
+    /// Returns the number of this `QueuePair`.
     ///
-    /// Internally, this uses `ibv_modify_qp` to mark the `QueuePair` as initialized
-    /// (`IBV_QPS_INIT`), ready to receive (`IBV_QPS_RTR`), and ready to send (`IBV_QPS_RTS`).
-    /// Further discussion of the protocol can be found on [RDMAmojo].
+    /// This is the number that the remote end connects to, and that is reported by the
+    /// `AsyncEvent`s concerning this `QueuePair`.
+    pub fn qp_num(&self) -> u32 {
+        unsafe { *self.qp }.qp_num
+    }
+
+    /// Destroys the `QueuePair`.
     ///
-    /// If the endpoint contains a Gid, the routing will be global. This means:
-    /// ```text,ignore
-    /// ah_attr.is_global = 1;
-    /// ah_attr.grh.hop_limit = 0xff;
-    /// ```
+    /// Unlike dropping the `QueuePair`, this reports whether destroying it succeeded.
     ///
-    /// The handshake also sets the following parameters, which are currently not configurable:
+    /// # Errors
     ///
-    /// # Examples
+    /// The `QueuePair` is handed back in the `ResourceError`, so that destroying it can be
+    /// retried, together with:
     ///
-    /// ```text,ignore
-    /// port_num = PORT_NUM;
-    /// pkey_index = 0;
-    /// sq_psn = 0;
+    ///  - `EBUSY`: The `QueuePair` is still attached to a multicast group.
+    pub fn close(mut self) -> Result<(), ResourceError<Self>> {
+        match self.destroy() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(ResourceError::new(self, e)),
+        }
+    }
+
+    /// Returns the maximum number of bytes that can be sent inline, e.g. with `post_send_inline`.
     ///
-    /// ah_attr.sl = 0;
-    /// ah_attr.src_path_bits = 0;
-    /// ```
+    /// This is the capacity the device granted for `QueuePairBuilder::set_max_inline_data`, which
+    /// may be more than was asked for.
+    pub fn max_inline_data(&self) -> u32 {
+        self.max_inline_data
+    }
+
+    /// Modifies the attributes of the `QueuePair` selected by `mask`.
+    fn modify(
+        &self,
+        attr: &mut ffi::ibv_qp_attr,
+        mask: ffi::ibv_qp_attr_mask,
+    ) -> Result<(), Error> {
+        let errno = unsafe { ffi::ibv_modify_qp(self.qp, attr as *mut _, mask.0 as i32) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_modify_qp", errno)
+                .with_qp(self.qp_num())
+                .with_qp_state(attr.qp_state));
+        }
+        Ok(())
+    }
+
+    /// Queries the current state and attributes of the `QueuePair`.
     ///
     /// # Errors
     ///
-    ///  - `EINVAL`: Invalid value provided in `attr` or in `attr_mask`.
+    ///  - `EINVAL`: Invalid `QueuePair`.
     ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn query(&self) -> Result<QueuePairAttributes, Error> {
+        let mut attr = ffi::ibv_qp_attr::default();
+        let mut init_attr = ffi::ibv_qp_init_attr::default();
+        // the mask is only a hint, providers may fill in all attributes either way
+        let mask = ffi::ibv_qp_attr_mask::IBV_QP_STATE
+            | ffi::ibv_qp_attr_mask::IBV_QP_CUR_STATE
+            | ffi::ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS
+            | ffi::ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
+            | ffi::ibv_qp_attr_mask::IBV_QP_PORT
+            | ffi::ibv_qp_attr_mask::IBV_QP_QKEY
+            | ffi::ibv_qp_attr_mask::IBV_QP_AV
+            | ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU
+            | ffi::ibv_qp_attr_mask::IBV_QP_TIMEOUT
+            | ffi::ibv_qp_attr_mask::IBV_QP_RETRY_CNT
+            | ffi::ibv_qp_attr_mask::IBV_QP_RNR_RETRY
+            | ffi::ibv_qp_attr_mask::IBV_QP_RQ_PSN
+            | ffi::ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC
+            | ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER
+            | ffi::ibv_qp_attr_mask::IBV_QP_SQ_PSN
+            | ffi::ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC
+            | ffi::ibv_qp_attr_mask::IBV_QP_CAP
+            | ffi::ibv_qp_attr_mask::IBV_QP_DEST_QPN;
+        let errno = unsafe {
+            ffi::ibv_query_qp(
+                self.qp,
+                &mut attr as *mut _,
+                mask.0 as i32,
+                &mut init_attr as *mut _,
+            )
+        };
+        if errno != 0 {
+            return Err(Error::verb("ibv_query_qp", errno).with_qp(self.qp_num()));
+        }
+        Ok(attr.into())
+    }
+
+    /// Returns the current state of the `QueuePair`.
     ///
-    /// [RDMAmojo]: http://www.rdmamojo.com/2014/01/18/connecting-queue-pairs/
-    pub fn handshake(self, remote: QueuePairEndpoint) -> io::Result<QueuePair> {
-        // init and associate with port
+    /// A `QueuePair` moves to `IBV_QPS_ERR` on its own, e.g. once a work request fails with
+    /// `IBV_WC_RETRY_EXC_ERR`. It then stays there until it is reset, see `QueuePair::reset`.
+    ///
+    /// # Errors
+    ///
+    /// See `QueuePair::query`.
+    pub fn state(&self) -> Result<ibv_qp_state, Error> {
+        self.query().map(|attr| attr.state)
+    }
+
+    /// Returns `true` if this is a UD `QueuePair`.
+    fn is_ud(&self) -> bool {
+        unsafe { *self.qp }.qp_type == ffi::ibv_qp_type::IBV_QPT_UD
+    }
+
+    /// Moves the `QueuePair` from `IBV_QPS_RESET` through `IBV_QPS_INIT` and `IBV_QPS_RTR` to
+    /// `IBV_QPS_RTS`.
+    ///
+    /// `remote` is ignored for UD `QueuePair`s, and connects all others. Without it, the
+    /// `QueuePair` is set up as a UD `QueuePair`.
+    fn connect(&self, remote: Option<&QueuePairEndpoint>) -> Result<(), Error> {
+        let remote = remote.filter(|_| !self.is_ud());
+        self.modify_to_init(remote.is_none())?;
+        self.modify_to_rtr(remote)?;
+        self.modify_to_rts(remote.is_none())
+    }
+
+    /// Moves the `QueuePair` to `IBV_QPS_INIT`, and associates it with its port.
+    fn modify_to_init(&self, ud: bool) -> Result<(), Error> {
+        let conn = &self.conn;
         let mut attr = ffi::ibv_qp_attr {
             qp_state: ffi::ibv_qp_state::IBV_QPS_INIT,
-            pkey_index: 0,
-            port_num: PORT_NUM,
+            pkey_index: conn.pkey_index,
+            port_num: conn.port_num,
             ..Default::default()
         };
         let mut mask = ffi::ibv_qp_attr_mask::IBV_QP_STATE
             | ffi::ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
             | ffi::ibv_qp_attr_mask::IBV_QP_PORT;
-        if let Some(access) = self.access {
+        if ud {
+            attr.qkey = conn.qkey.unwrap_or(DEFAULT_QKEY);
+            mask |= ffi::ibv_qp_attr_mask::IBV_QP_QKEY;
+        } else if let Some(access) = conn.access {
             attr.qp_access_flags = access.0;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS;
         }
-        let errno = unsafe { ffi::ibv_modify_qp(self.qp.qp, &mut attr as *mut _, mask.0 as i32) };
-        if errno != 0 {
-            return Err(io::Error::from_raw_os_error(errno));
-        }
+        self.modify(&mut attr, mask)
+    }
+
+    /// Moves the `QueuePair` to `IBV_QPS_RTR`, connected to `remote` unless it is UD.
+    fn modify_to_rtr(&self, remote: Option<&QueuePairEndpoint>) -> Result<(), Error> {
+        let conn = &self.conn;
+        let Some(remote) = remote else {
+            let mut attr = ffi::ibv_qp_attr {
+                qp_state: ffi::ibv_qp_state::IBV_QPS_RTR,
+                ..Default::default()
+            };
+            return self.modify(&mut attr, ffi::ibv_qp_attr_mask::IBV_QP_STATE);
+        };
 
-        // set ready to receive
         let mut attr = ffi::ibv_qp_attr {
             qp_state: ffi::ibv_qp_state::IBV_QPS_RTR,
-            // TODO: this is only valid for RC and UC
             dest_qp_num: remote.num,
-            // TODO: this is only valid for RC and UC
-            ah_attr: ffi::ibv_ah_attr {
-                dlid: remote.lid,
-                sl: self.service_level,
-                src_path_bits: 0,
-                port_num: PORT_NUM,
-                grh: Default::default(),
-                ..Default::default()
-            },
+            ah_attr: address_vector(remote, conn.port_num, conn.gid_index, &conn.path)?,
             ..Default::default()
         };
-        if let Some(gid) = remote.gid {
-            attr.ah_attr.is_global = 1;
-            attr.ah_attr.grh.dgid = gid.into();
-            attr.ah_attr.grh.hop_limit = 0xff;
-            attr.ah_attr.grh.sgid_index = self
-                .gid_index
-                .ok_or_else(|| io::Error::other("gid was set for remote but not local"))?
-                as u8;
-            attr.ah_attr.grh.traffic_class = self.traffic_class;
-        }
         let mut mask = ffi::ibv_qp_attr_mask::IBV_QP_STATE
             | ffi::ibv_qp_attr_mask::IBV_QP_AV
             | ffi::ibv_qp_attr_mask::IBV_QP_DEST_QPN;
-        if let Some(max_dest_rd_atomic) = self.max_dest_rd_atomic {
+        if let Some(max_dest_rd_atomic) = conn.max_dest_rd_atomic {
             attr.max_dest_rd_atomic = max_dest_rd_atomic;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC;
         }
-        if let Some(min_rnr_timer) = self.min_rnr_timer {
+        if let Some(min_rnr_timer) = conn.min_rnr_timer {
             attr.min_rnr_timer = min_rnr_timer;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
         }
-        if let Some(path_mtu) = self.path_mtu {
+        if let Some(path_mtu) = conn.path_mtu {
             attr.path_mtu = path_mtu;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU;
         }
-        if let Some(rq_psn) = self.rq_psn {
-            attr.rq_psn = rq_psn;
-            mask |= ffi::ibv_qp_attr_mask::IBV_QP_RQ_PSN;
-        }
-        let errno = unsafe { ffi::ibv_modify_qp(self.qp.qp, &mut attr as *mut _, mask.0 as i32) };
-        if errno != 0 {
-            return Err(io::Error::from_raw_os_error(errno));
-        }
+        attr.rq_psn = conn.rq_psn.unwrap_or(remote.psn) & 0xff_ffff;
+        mask |= ffi::ibv_qp_attr_mask::IBV_QP_RQ_PSN;
+        self.modify(&mut attr, mask)
+    }
 
-        // set ready to send
+    /// Moves the `QueuePair` to `IBV_QPS_RTS`.
+    fn modify_to_rts(&self, ud: bool) -> Result<(), Error> {
+        let conn = &self.conn;
         let mut attr = ffi::ibv_qp_attr {
             qp_state: ffi::ibv_qp_state::IBV_QPS_RTS,
-            sq_psn: 0,
+            sq_psn: conn.sq_psn & 0xff_ffff,
             ..Default::default()
         };
         let mut mask = ffi::ibv_qp_attr_mask::IBV_QP_STATE | ffi::ibv_qp_attr_mask::IBV_QP_SQ_PSN;
-        if let Some(timeout) = self.timeout {
+        if ud {
+            return self.modify(&mut attr, mask);
+        }
+        if let Some(timeout) = conn.timeout {
             attr.timeout = timeout;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_TIMEOUT;
         }
-        if let Some(retry_count) = self.retry_count {
+        if let Some(retry_count) = conn.retry_count {
             attr.retry_cnt = retry_count;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_RETRY_CNT;
         }
-        if let Some(rnr_retry) = self.rnr_retry {
+        if let Some(rnr_retry) = conn.rnr_retry {
             attr.rnr_retry = rnr_retry;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_RNR_RETRY;
         }
-        if let Some(max_rd_atomic) = self.max_rd_atomic {
+        if let Some(max_rd_atomic) = conn.max_rd_atomic {
             attr.max_rd_atomic = max_rd_atomic;
             mask |= ffi::ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC;
         }
-        let errno = unsafe { ffi::ibv_modify_qp(self.qp.qp, &mut attr as *mut _, mask.0 as i32) };
-        if errno != 0 {
-            return Err(io::Error::from_raw_os_error(errno));
-        }
-
-        Ok(self.qp)
-    }
-}
-
-pub struct MemoryRegion {
-    mr: *mut ffi::ibv_mr,
-    bytes: ManuallyDrop<BytesMut>,
-    _pd: Arc<ProtectionDomainInner>,
-}
-
-unsafe impl Send for MemoryRegion {}
-unsafe impl Sync for MemoryRegion {}
-
-impl MemoryRegion {
-    pub fn lkey(&self) -> u32 {
-        unsafe { (*self.mr).lkey }
-    }
-
-    pub fn rkey(&self) -> u32 {
-        unsafe { (*self.mr).rkey }
-    }
-
-    pub fn as_slice(&self) -> &[u8] {
-        &self.bytes
+        self.modify(&mut attr, mask)
     }
 
-    pub fn as_slice_mut(&mut self) -> &mut [u8] {
-        &mut self.bytes
+    /// Get the network endpoint for this `QueuePair`, as `PreparedQueuePair::endpoint` does.
+    ///
+    /// After `QueuePair::reset`, its `psn` is the one the next connection starts from, so the
+    /// remote side needs the endpoint again to reconnect.
+    ///
+    /// # Errors
+    ///
+    ///  - System errors: From querying the port or the GID of the `QueuePair`.
+    pub fn endpoint(&self) -> Result<QueuePairEndpoint, Error> {
+        let lid = self.pd.ctx.query_port(self.conn.port_num)?.lid;
+        self.endpoint_with_lid(lid)
     }
 
-    pub fn deregister(mut self) -> io::Result<BytesMut> {
-        let bytes = unsafe { ManuallyDrop::take(&mut self.bytes) };
-        unsafe {
-            let errno = ffi::ibv_dereg_mr(self.mr);
-            if errno != 0 {
-                return Err(io::Error::from_raw_os_error(errno));
+    fn endpoint_with_lid(&self, lid: u16) -> Result<QueuePairEndpoint, Error> {
+        let port_num = self.conn.port_num;
+        let gid = if let Some(gid_index) = self.conn.gid_index {
+            let mut gid = ffi::ibv_gid::default();
+            let rc = unsafe {
+                ffi::ibv_query_gid(self.pd.ctx.ctx, port_num, gid_index as i32, &mut gid)
+            };
+            if rc < 0 {
+                return Err(Error::last_os_error("ibv_query_gid").with_port(port_num));
             }
+            Some(Gid::from(gid))
+        } else {
+            None
         };
-        mem::forget(self);
-        Ok(bytes)
-    }
-
-    pub fn slice_local(
-        &self,
-        bounds: impl RangeBounds<usize>,
-    ) -> impl Iterator<Item = LocalMemorySlice> + '_ {
-        self._slice(bounds).map(|(start, end)| LocalMemorySlice {
-            _sge: ibv_sge {
-                addr: start as u64,
-                length: unsafe { end.offset_from(start) as u32 },
-                lkey: self.lkey(),
-            },
-        })
-    }
-
-    pub fn slice_remote(
-        &self,
-        bounds: impl RangeBounds<usize>,
-    ) -> impl Iterator<Item = RemoteMemorySlice> + '_ {
-        self._slice(bounds).map(|(start, end)| RemoteMemorySlice {
-            addr: start as u64,
-            length: unsafe { end.offset_from(start) } as u32,
-            rkey: self.rkey(),
-        })
-    }
-
-    fn _slice(
-        &self,
-        bounds: impl RangeBounds<usize>,
-    ) -> impl Iterator<Item = (*const u8, *const u8)> + '_ {
-        let start_off = match bounds.start_bound() {
-            Bound::Included(&n) => n,
-            Bound::Excluded(&n) => n + 1,
-            Bound::Unbounded => 0,
-        };
-        let end_off = match bounds.end_bound() {
-            Bound::Included(&n) => n + 1,
-            Bound::Excluded(&n) => n,
-            Bound::Unbounded => self.bytes.len(),
-        };
-        assert!(start_off <= self.bytes.len());
-        assert!(end_off <= self.bytes.len());
-        assert!(start_off <= end_off);
-
-        let start = unsafe { self.bytes.as_ptr().add(start_off) };
-        let end = unsafe { self.bytes.as_ptr().add(end_off) };
-
-        let mut curr = start;
-        iter::from_fn(move || {
-            if curr >= end {
-                return None;
-            }
-
-            let start = curr;
-            let remaining = unsafe { end.offset_from(start) } as usize;
-            let length = remaining.min(u32::MAX as usize);
-
-            curr = unsafe { curr.add(length) };
-            Some((start, curr))
+        Ok(QueuePairEndpoint {
+            num: self.qp_num(),
+            lid,
+            gid,
+            psn: self.conn.sq_psn,
         })
     }
-}
-
-impl Deref for MemoryRegion {
-    type Target = [u8];
-
-    fn deref(&self) -> &Self::Target {
-        self.as_slice()
-    }
-}
-
-impl AsRef<[u8]> for MemoryRegion {
-    fn as_ref(&self) -> &[u8] {
-        self.as_slice()
-    }
-}
-
-impl DerefMut for MemoryRegion {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.as_slice_mut()
-    }
-}
-
-impl AsMut<[u8]> for MemoryRegion {
-    fn as_mut(&mut self) -> &mut [u8] {
-        self.as_slice_mut()
-    }
-}
 
-impl Drop for MemoryRegion {
-    fn drop(&mut self) {
-        unsafe {
-            let errno = ffi::ibv_dereg_mr(self.mr);
-            if errno != 0 {
-                panic!("dereg_mr failed: {}", io::Error::from_raw_os_error(errno));
-            }
+    /// Hands the `QueuePair` over to the typestate of the state it was moved to.
+    fn into_state<T>(mut self) -> QueuePair<T> {
+        // dropping `self` does not destroy the QP once its pointer was taken
+        let qp = mem::replace(&mut self.qp, ptr::null_mut());
+        QueuePair {
+            pd: self.pd.clone(),
+            qp,
+            cq: self.cq.clone(),
+            srq: self.srq.clone(),
+            sq_sig_all: self.sq_sig_all,
+            max_inline_data: self.max_inline_data,
+            sends: self.sends.clone(),
+            debits: self.debits.clone(),
+            conn: self.conn,
+            _state: PhantomData,
         }
     }
-}
-
-impl Debug for MemoryRegion {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.debug_struct("MemoryRegion")
-            .field("addr", &self.as_ptr())
-            .field("length", &self.len())
-            .field("lkey", &self.lkey())
-            .field("rkey", &self.rkey())
-            .finish()
-    }
-}
-
-/// Local memory slice.
-#[derive(Debug, Default, Copy, Clone)]
-#[repr(transparent)]
-pub struct LocalMemorySlice {
-    _sge: ffi::ibv_sge,
-}
-
-impl LocalMemorySlice {
-    /// Get the address of the local memory slice.
-    pub fn addr(&self) -> u64 {
-        self._sge.addr
-    }
 
-    /// Get the length of the local memory slice.
-    pub fn len(&self) -> usize {
-        self._sge.length as usize
+    /// Destroys the `QueuePair`, unless that already happened.
+    fn destroy(&mut self) -> Result<(), Error> {
+        if self.qp.is_null() {
+            return Ok(());
+        }
+        let qp_num = self.qp_num();
+        let errno = unsafe { ffi::ibv_destroy_qp(self.qp) };
+        if errno != 0 {
+            return Err(Error::verb("ibv_destroy_qp", errno).with_qp(qp_num));
+        }
+        self.qp = ptr::null_mut();
+        if self.sends.tracking {
+            self.cq.0.senders().remove(&qp_num);
+        }
+        ResourceCounters::destroyed(&self.pd.ctx.live.qps);
+        // destroying the QP removed its completions from the CQs
+        for (cq, debits) in [(&self.cq.0, &self.debits.0), (&self.cq.1, &self.debits.1)] {
+            cq.debtors().remove(&qp_num);
+            cq.release_credits(debits, usize::MAX);
+        }
+        Ok(())
     }
 
-    /// Get is_empty
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    /// Returns the number of send requests that are known to be complete.
+    ///
+    /// Since send requests complete in order, all requests with a sequence number below this are
+    /// complete, i.e. it is the sequence number of the oldest send request that may still be
+    /// outstanding. This only advances as completions are polled from the send CQ, and always
+    /// returns 0 unless the `QueuePair` was built with `QueuePairBuilder::set_send_tracking`.
+    pub fn completed_sends(&self) -> u64 {
+        self.sends.completed.load(Ordering::Acquire)
     }
 
-    /// Get the remote key of the local memory slice.
-    pub fn rkey(&self) -> u32 {
-        self._sge.lkey
+    /// Returns `true` if the send request with sequence number `seq`, as returned by
+    /// `post_send_with_flags` and its siblings, is known to be complete.
+    ///
+    /// Its memory can then be reused, whether it was signaled or not. See `completed_sends`.
+    pub fn is_send_complete(&self, seq: u64) -> bool {
+        seq < self.completed_sends()
     }
 }
 
-/// Remote memory slice.
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-pub struct RemoteMemorySlice {
-    addr: u64,
-    length: u32,
-    rkey: u32,
-}
+impl<S: Receiving> QueuePair<S> {
+    /// Posts a linked list of Work Requests (WRs) to the Receive Queue of this Queue Pair.
+    ///
+    /// Generates a HW-specific Receive Request out of it and add it to the tail of the Queue
+    /// Pair's Receive Queue without performing any context switch. The RDMA device will take one
+    /// of those Work Requests as soon as an incoming opcode to that QP will consume a Receive
+    /// Request (RR). If there is a failure in one of the WRs because the Receive Queue is full or
+    /// one of the attributes in the WR is bad, it stops immediately and return the pointer to that
+    /// WR.
+    ///
+    /// `wr_id` is a 64 bits value associated with this WR. When a Work Completion is generated
+    /// when this Work Request ends, it will contain this value.
+    ///
+    /// Internally, the memory at `mr[range]` will be received into as a single `ibv_recv_wr`.
+    ///
+    /// See also [DDMAmojo's `ibv_post_recv` documentation][1].
+    ///
+    /// # Safety
+    ///
+    /// The memory region can only be safely reused or dropped after the request is fully executed
+    /// and a work completion has been retrieved from the corresponding completion queue (i.e.,
+    /// until `CompletionQueue::poll` returns a completion for this receive).
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: Invalid value provided in the Work Request, or the `QueuePair` is associated
+    ///    with a `SharedReceiveQueue`.
+    ///  - `ENOMEM`: Receive Queue is full or not enough resources to complete this operation.
+    ///  - `EFAULT`: Invalid value provided in `QueuePair`.
+    ///  - `Error::CompletionQueueFull`: The receive CQ has credit accounting enabled, and has no
+    ///    room for the completion of this receive.
+    ///
+    /// [1]: http://www.rdmamojo.com/2013/02/02/ibv_post_recv/
+    #[inline]
+    pub unsafe fn post_receive(
+        &mut self,
+        local: &[LocalMemorySlice],
+        wr_id: u64,
+    ) -> Result<(), Error> {
+        let mut wr = ffi::ibv_recv_wr {
+            wr_id,
+            next: ptr::null::<ffi::ibv_send_wr>() as *mut _,
+            sg_list: local.as_ptr() as *mut ffi::ibv_sge,
+            num_sge: local.len() as i32,
+        };
+        let mut bad_wr: *mut ffi::ibv_recv_wr = ptr::null::<ffi::ibv_recv_wr>() as *mut _;
+
+        // If the QP qp is associated with a shared receive queue, you must use the function
+        // ibv_post_srq_recv(), and not ibv_post_recv(), since the QP's own receive queue will not
+        // be used.
+        if self.srq.is_some() {
+            return Err(Error::verb("ibv_post_recv", nix::libc::EINVAL).with_qp(self.qp_num()));
+        }
 
-impl RemoteMemorySlice {
-    pub fn addr(&self) -> u64 {
-        self.addr
-    }
-    pub fn len(&self) -> usize {
-        self.length as usize
-    }
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
-    pub fn rkey(&self) -> u32 {
-        self.rkey
-    }
+        // If a WR is being posted to a UD QP, the Global Routing Header (GRH) of the incoming
+        // message will be placed in the first 40 bytes of the buffer(s) in the scatter list. If no
+        // GRH is present in the incoming message, then the first  bytes  will  be undefined. This
+        // means that in all cases, the actual data of the incoming message will start at an offset
+        // of 40 bytes into the buffer(s) in the scatter list. See `Grh`.
 
-    pub fn slice(&self, bounds: impl RangeBounds<usize>) -> Self {
-        let start = match bounds.start_bound() {
-            Bound::Included(&n) => n,
-            Bound::Excluded(&n) => n + 1,
-            Bound::Unbounded => 0,
-        };
-        let end = match bounds.end_bound() {
-            Bound::Included(&n) => n + 1,
-            Bound::Excluded(&n) => n,
-            Bound::Unbounded => self.len(),
+        self.cq.1.acquire_credits(&self.debits.1, 1)?;
+        let ctx = unsafe { *self.qp }.context;
+        let ops = &mut unsafe { *ctx }.ops;
+        let errno = unsafe {
+            ops.post_recv.as_mut().unwrap()(self.qp, &mut wr as *mut _, &mut bad_wr as *mut _)
         };
-        assert!(start <= end);
-        let start_addr = self.addr + start as u64;
-        let end_addr = self.addr + end as u64;
-        let length = (end_addr - start_addr)
-            .try_into()
-            .expect("memory region slice length exceeds u32::MAX");
-        Self {
-            addr: start_addr,
-            length,
-            rkey: self.rkey,
+        if errno != 0 {
+            self.cq.1.release_credits(&self.debits.1, 1);
+            Err(Error::verb("ibv_post_recv", errno).with_qp(self.qp_num()))
+        } else {
+            Ok(())
         }
     }
-}
 
-struct ProtectionDomainInner {
-    ctx: Arc<ContextInner>,
-    pd: *mut ffi::ibv_pd,
-}
+    /// Posts all requests of `batch` to the Receive Queue with a single `ibv_post_recv` call.
+    ///
+    /// This saves notifying the device once per request. `batch` is left unchanged, and can be
+    /// cleared and reused once the call returns.
+    ///
+    /// # Safety
+    ///
+    /// The memory of each request can only be safely reused or dropped after its work completion
+    /// has been retrieved from the corresponding completion queue, as for `post_receive`.
+    ///
+    /// # Errors
+    ///
+    /// The `BatchError` reports the index of the first request that was rejected, along with:
+    ///
+    ///  - `EINVAL`: Invalid value provided in the Work Request, or the `QueuePair` is associated
+    ///    with a `SharedReceiveQueue`.
+    ///  - `ENOMEM`: Receive Queue is full or not enough resources to complete this operation.
+    ///  - `EFAULT`: Invalid value provided in `QueuePair`.
+    ///  - `Error::CompletionQueueFull`: The receive CQ has credit accounting enabled, and has no
+    ///    room for the completions of the whole batch. No request was posted.
+    pub unsafe fn post_receive_batch(&self, batch: &mut RecvBatch<'_>) -> Result<(), BatchError> {
+        if self.srq.is_some() {
+            let error = Error::verb("ibv_post_recv", nix::libc::EINVAL).with_qp(self.qp_num());
+            return Err(BatchError { index: 0, error });
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let n = batch.len();
+        self.cq
+            .1
+            .acquire_credits(&self.debits.1, n)
+            .map_err(|error| BatchError { index: 0, error })?;
 
-impl Drop for ProtectionDomainInner {
-    fn drop(&mut self) {
-        let errno = unsafe { ffi::ibv_dealloc_pd(self.pd) };
+        let mut bad_wr: *mut ffi::ibv_recv_wr = ptr::null_mut();
+        let ctx = unsafe { *self.qp }.context;
+        let ops = &mut unsafe { *ctx }.ops;
+        let errno = unsafe {
+            ops.post_recv.as_mut().unwrap()(self.qp, batch.link(), &mut bad_wr as *mut _)
+        };
         if errno != 0 {
-            let e = io::Error::from_raw_os_error(errno);
-            panic!("dealloc_pd failed: {e}");
+            let index = if bad_wr.is_null() {
+                0
+            } else {
+                batch.index_of(bad_wr)
+            };
+            self.cq.1.release_credits(&self.debits.1, n - index);
+            let error = Error::verb("ibv_post_recv", errno).with_qp(self.qp_num());
+            return Err(BatchError { index, error });
         }
+        Ok(())
     }
 }
 
-unsafe impl Sync for ProtectionDomainInner {}
-unsafe impl Send for ProtectionDomainInner {}
-
-/// A protection domain for a device's context.
-#[derive(Clone)]
-pub struct ProtectionDomain {
-    inner: Arc<ProtectionDomainInner>,
-}
-
-impl ProtectionDomain {
-    /// Creates a queue pair builder associated with this protection domain.
+impl<S> QueuePair<S> {
+    /// Moves the `QueuePair` to `IBV_QPS_RESET`, from any state.
     ///
-    /// `send` and `recv` are the device `Context` to associate with the send and receive queues
-    /// respectively. `send` and `recv` may refer to the same `Context`.
+    /// This discards all outstanding work requests without generating work completions, so the
+    /// device may still access their memory until the reset completes. Completions that were not
+    /// polled yet are removed from the CQs, and the CQ credits of all these requests are
+    /// returned. Use `QueuePair::drain` first to flush them into the CQs instead. Send requests
+    /// that were not known to be complete count as complete afterwards (see
+    /// `QueuePair::completed_sends`).
     ///
-    /// `qp_type` indicates the requested Transport Service Type of this QP:
+    /// Unless `QueuePairBuilder::set_sq_psn` was used, a new random PSN is drawn for the next
+    /// connection, which `QueuePair::endpoint` reports.
     ///
-    ///  - `IBV_QPT_RC`: Reliable Connection
-    ///  - `IBV_QPT_UC`: Unreliable Connection
-    ///  - `IBV_QPT_UD`: Unreliable Datagram
+    /// # Errors
     ///
-    /// Note that both this protection domain, *and* both provided completion queues, must outlive
-    /// the resulting `QueuePair`.
-    pub fn create_qp(
-        &self,
-        send: &CompletionQueue,
-        recv: &CompletionQueue,
-        qp_type: ffi::ibv_qp_type,
-    ) -> io::Result<QueuePairBuilder> {
-        let port_attr = self.inner.ctx.query_port()?;
-        Ok(QueuePairBuilder::new(
-            self.inner.clone(),
-            port_attr,
-            send.inner.clone(),
-            1,
-            recv.inner.clone(),
-            1,
-            qp_type,
-            1,
-            1,
-        ))
+    /// The `QueuePair` is handed back in the `ResourceError`, left as it was, together with:
+    ///
+    ///  - `EINVAL`: Invalid `QueuePair`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    pub fn reset(mut self) -> Result<QueuePair<Reset>, ResourceError<Self>> {
+        if let Err(e) = self.modify_to_reset() {
+            return Err(ResourceError::new(self, e));
+        }
+        self.sends.reset();
+        self.cq.0.release_credits(&self.debits.0, usize::MAX);
+        self.cq.1.release_credits(&self.debits.1, usize::MAX);
+        if !self.conn.fixed_sq_psn {
+            self.conn.sq_psn = random_psn();
+        }
+        Ok(self.into_state())
     }
-    pub fn allocate_zeroed(&self, size: usize) -> io::Result<MemoryRegion> {
-        let bytes = BytesMut::zeroed(size);
-        self.register(bytes)
+
+    /// Moves the `QueuePair` to `IBV_QPS_RESET`.
+    fn modify_to_reset(&self) -> Result<(), Error> {
+        let mut attr = ffi::ibv_qp_attr {
+            qp_state: ffi::ibv_qp_state::IBV_QPS_RESET,
+            ..Default::default()
+        };
+        self.modify(&mut attr, ffi::ibv_qp_attr_mask::IBV_QP_STATE)
     }
+}
 
-    pub unsafe fn allocate(&self, size: usize) -> io::Result<MemoryRegion> {
-        let mut bytes = BytesMut::with_capacity(size);
-        bytes.set_len(size);
-        self.register(bytes)
+impl QueuePair<Reset> {
+    /// Initializes the `QueuePair` again, as `PreparedQueuePair::to_init` does for a new one.
+    ///
+    /// # Errors
+    ///
+    /// See `PreparedQueuePair::to_init`. The `QueuePair` is handed back in the `ResourceError`.
+    pub fn to_init(self) -> Result<QueuePair<Init>, ResourceError<Self>> {
+        match self.modify_to_init(self.is_ud()) {
+            Ok(()) => Ok(self.into_state()),
+            Err(e) => Err(ResourceError::new(self, e)),
+        }
     }
 
-    pub fn register(&self, mut bytes: BytesMut) -> io::Result<MemoryRegion> {
-        let addr = bytes.as_mut_ptr();
-        let length = bytes.len();
-        let mr = unsafe {
-            ffi::ibv_reg_mr(
-                self.inner.pd,
-                addr.cast(),
-                length,
-                DEFAULT_ACCESS_FLAGS.0 as _,
-            )
-        };
-        if mr.is_null() {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(MemoryRegion {
-                mr,
-                bytes: ManuallyDrop::new(bytes),
-                _pd: self.inner.clone(),
-            })
+    /// Connects the `QueuePair` to `remote` again, as `PreparedQueuePair::handshake` would.
+    ///
+    /// Together with `QueuePair::reset`, this recovers a `QueuePair` that went to the error
+    /// state, without creating a new one. The attributes set on the `QueuePairBuilder` are
+    /// applied again. For UD `QueuePair`s, `remote` is ignored.
+    ///
+    /// Both sides should reset their `QueuePair`s, which draws new PSNs, and reconnect each to
+    /// the other's `QueuePair::endpoint`. Packets of the old connection are then not mistaken for
+    /// packets of the new one.
+    ///
+    /// # Errors
+    ///
+    /// See `PreparedQueuePair::handshake`. If connecting fails, the steps that succeeded are
+    /// undone, and the `QueuePair` is handed back in the `ResourceError`, so it can be reconnected
+    /// again. If even undoing them fails, which should only happen for a broken device, the
+    /// `QueuePair` is left in whatever state it reached; `QueuePair::reset` can be retried then.
+    pub fn reconnect(self, remote: QueuePairEndpoint) -> Result<QueuePair, ResourceError<Self>> {
+        match self.connect(Some(&remote)) {
+            Ok(()) => Ok(self.into_state()),
+            Err(e) => {
+                if let Err(undo) = self.modify_to_reset() {
+                    log::error!("{undo}; the queue pair was not reset after failing to connect");
+                }
+                Err(ResourceError::new(self, e))
+            }
         }
     }
 }
 
-/// A fully initialized and ready `QueuePair`.
-///
-/// A queue pair is the actual object that sends and receives data in the RDMA architecture
-/// (something like a socket). It's not exactly like a socket, however. A socket is an abstraction,
-/// which is maintained by the network stack and doesn't have a physical resource behind it. A QP
-/// is a resource of an RDMA device and a QP number can be used by one process at the same time
-/// (similar to a socket that is associated with a specific TCP or UDP port number)
-pub struct QueuePair {
-    pd: Arc<ProtectionDomainInner>,
-    qp: *mut ffi::ibv_qp,
-    _cq: (Arc<CompletionQueueInner>, Arc<CompletionQueueInner>),
-}
+impl QueuePair {
+    /// Moves the `QueuePair` to `IBV_QPS_ERR`, which flushes all outstanding work requests into
+    /// the CQs with `IBV_WC_WR_FLUSH_ERR`, so that their memory can be reclaimed.
+    ///
+    /// To tell when flushing is done, an empty marker request with `wr_id` is posted to the Send
+    /// Queue, and to the Receive Queue unless the `QueuePair` uses a `SharedReceiveQueue`. Work
+    /// queues are flushed in order, so once the completions of the markers were polled, all
+    /// requests posted before them have been flushed. Returns the drained `QueuePair` with the
+    /// number of markers posted, i.e. the number of marker completions to wait for. Pick a
+    /// `wr_id` that no other request uses. Both markers carry it, and if the CQs are shared, a
+    /// flushed receive cannot be told apart from a flushed send, so count the marker completions
+    /// rather than telling them apart.
+    ///
+    /// UD `QueuePair`s get no Send Queue marker, since a UD send needs an address handle. Their
+    /// send requests are still flushed, but only their own completions tell when. Neither does a
+    /// work queue that is full; its requests are flushed all the same.
+    ///
+    /// The CQ entries of the markers are reserved before the `QueuePair` is moved. If that or the
+    /// move fails, the `QueuePair` is handed back in the `ResourceError`, and left as it was.
+    ///
+    /// Afterwards, `QueuePair::reset` and `QueuePair::reconnect` make the `QueuePair` usable
+    /// again.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: Invalid `QueuePair`.
+    ///  - `ENOMEM`: Not enough resources to complete this operation.
+    ///  - `Error::CompletionQueueFull`: A CQ has credit accounting enabled, and has no room for
+    ///    the completion of a marker.
+    pub fn drain(self, wr_id: u64) -> Result<(QueuePair<Drained>, usize), ResourceError<Self>> {
+        let send_marker = !self.is_ud();
+        let recv_marker = self.srq.is_none();
+        if let Err(e) = self
+            .cq
+            .0
+            .acquire_credits(&self.debits.0, send_marker as usize)
+        {
+            return Err(ResourceError::new(self, e));
+        }
+        if let Err(e) = self
+            .cq
+            .1
+            .acquire_credits(&self.debits.1, recv_marker as usize)
+        {
+            self.cq
+                .0
+                .release_credits(&self.debits.0, send_marker as usize);
+            return Err(ResourceError::new(self, e));
+        }
+
+        let mut attr = ffi::ibv_qp_attr {
+            qp_state: ffi::ibv_qp_state::IBV_QPS_ERR,
+            ..Default::default()
+        };
+        if let Err(e) = self.modify(&mut attr, ffi::ibv_qp_attr_mask::IBV_QP_STATE) {
+            self.cq
+                .0
+                .release_credits(&self.debits.0, send_marker as usize);
+            self.cq
+                .1
+                .release_credits(&self.debits.1, recv_marker as usize);
+            return Err(ResourceError::new(self, e));
+        }
 
-unsafe impl Send for QueuePair {}
-unsafe impl Sync for QueuePair {}
+        let mut markers = 0;
+        if send_marker {
+            let mut wr = ffi::ibv_send_wr {
+                wr_id,
+                next: ptr::null_mut(),
+                sg_list: ptr::null_mut(),
+                num_sge: 0,
+                opcode: ffi::ibv_wr_opcode::IBV_WR_SEND,
+                send_flags: ffi::ibv_send_flags::IBV_SEND_SIGNALED.0,
+                wr: Default::default(),
+                qp_type: Default::default(),
+                __bindgen_anon_1: Default::default(),
+                __bindgen_anon_2: Default::default(),
+            };
+            // SAFETY: the marker does not reference any memory, and its credit is reserved.
+            // Failing releases the credit.
+            if unsafe { self.post_reserved_send_wrs(slice::from_mut(&mut wr)) }.is_ok() {
+                markers += 1;
+            }
+        }
+        if recv_marker {
+            let mut wr = ffi::ibv_recv_wr {
+                wr_id,
+                next: ptr::null_mut(),
+                sg_list: ptr::null_mut(),
+                num_sge: 0,
+            };
+            let mut bad_wr: *mut ffi::ibv_recv_wr = ptr::null_mut();
+            let ctx = unsafe { *self.qp }.context;
+            let ops = &mut unsafe { *ctx }.ops;
+            let errno = unsafe {
+                ops.post_recv.as_mut().unwrap()(self.qp, &mut wr as *mut _, &mut bad_wr as *mut _)
+            };
+            if errno == 0 {
+                markers += 1;
+            } else {
+                self.cq.1.release_credits(&self.debits.1, 1);
+            }
+        }
+        Ok((self.into_state(), markers))
+    }
 
-impl QueuePair {
     /// Posts a linked list of Work Requests (WRs) to the Send Queue of this Queue Pair.
     ///
     /// Generates a HW-specific Send Request for the memory at `mr[range]`, and adds it to the tail
@@ -1850,6 +5490,8 @@ impl QueuePair {
     ///  - `EINVAL`: Invalid value provided in the Work Request.
     ///  - `ENOMEM`: Send Queue is full or not enough resources to complete this operation.
     ///  - `EFAULT`: Invalid value provided in `QueuePair`.
+    ///  - `Error::CompletionQueueFull`: The send CQ has credit accounting enabled, and has no room
+    ///    for the completion of this send.
     ///
     /// [1]: http://www.rdmamojo.com/2013/01/26/ibv_post_send/
     #[inline]
@@ -1858,14 +5500,76 @@ impl QueuePair {
         local: &[LocalMemorySlice],
         wr_id: u64,
         imm_data: Option<u32>,
-    ) -> io::Result<()> {
+    ) -> Result<(), Error> {
+        let send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED;
+        unsafe { self.post_send_with_flags(local, wr_id, imm_data, send_flags) }.map(|_| ())
+    }
+
+    /// Like `post_send`, but marks the message as solicited.
+    ///
+    /// The receiver's CQ then reports a completion event for it even if it was armed with
+    /// `CompletionQueue::req_notify_solicited`, e.g. by `CompletionQueue::wait_solicited`. Use
+    /// this for messages the receiver must react to right away.
+    ///
+    /// # Safety
+    ///
+    /// See `post_send`.
+    ///
+    /// # Errors
+    ///
+    /// See `post_send`.
+    #[inline]
+    pub unsafe fn post_send_solicited(
+        &mut self,
+        local: &[LocalMemorySlice],
+        wr_id: u64,
+        imm_data: Option<u32>,
+    ) -> Result<(), Error> {
+        let send_flags =
+            ffi::ibv_send_flags::IBV_SEND_SIGNALED | ffi::ibv_send_flags::IBV_SEND_SOLICITED;
+        unsafe { self.post_send_with_flags(local, wr_id, imm_data, send_flags) }.map(|_| ())
+    }
+
+    /// Like `post_send`, but with the given `send_flags` instead of just `IBV_SEND_SIGNALED`.
+    ///
+    /// Returns the sequence number of the send, which counts all send requests posted to this
+    /// `QueuePair`, starting at 0. It tells when the send is complete through
+    /// `QueuePair::is_send_complete`.
+    ///
+    /// Without `IBV_SEND_SIGNALED`, and unless the `QueuePair` was built with
+    /// `QueuePairBuilder::set_sq_sig_all`, the send is unsignaled and generates no work completion
+    /// unless it fails. With `IBV_SEND_INLINE`, the data is copied into the request, and `local`
+    /// can be reused as soon as this returns. With `IBV_SEND_SOLICITED`, the send is solicited, as
+    /// for `post_send_solicited`.
+    ///
+    /// # Safety
+    ///
+    /// The memory region of a signaled send can be reused or dropped as for `post_send`. That of
+    /// an unsignaled send, unless it is inline, only once `QueuePair::is_send_complete` returns
+    /// `true` for its sequence number, i.e. once the completion of a later signaled request has
+    /// been retrieved from the send CQ.
+    ///
+    /// # Errors
+    ///
+    /// See `post_send`, and:
+    ///
+    ///  - `EINVAL`: The send is unsignaled, but the `QueuePair` was not built with
+    ///    `QueuePairBuilder::set_send_tracking`.
+    #[inline]
+    pub unsafe fn post_send_with_flags(
+        &mut self,
+        local: &[LocalMemorySlice],
+        wr_id: u64,
+        imm_data: Option<u32>,
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
         let mut wr = ffi::ibv_send_wr {
             wr_id,
             next: ptr::null::<ffi::ibv_send_wr>() as *mut _,
             sg_list: local.as_ptr() as *mut ffi::ibv_sge,
             num_sge: local.len() as i32,
             opcode: ffi::ibv_wr_opcode::IBV_WR_SEND,
-            send_flags: ffi::ibv_send_flags::IBV_SEND_SIGNALED.0,
+            send_flags: send_flags.0,
             wr: Default::default(),
             qp_type: Default::default(),
             __bindgen_anon_1: Default::default(),
@@ -1875,15 +5579,7 @@ impl QueuePair {
             wr.__bindgen_anon_1.imm_data = imm;
             wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM;
         }
-        let mut bad_wr: *mut ffi::ibv_send_wr = ptr::null::<ffi::ibv_send_wr>() as *mut _;
 
-        // TODO:
-        //
-        // ibv_post_send()  posts the linked list of work requests (WRs) starting with wr to the
-        // send queue of the queue pair qp.  It stops processing WRs from this list at the first
-        // failure (that can  be  detected  immediately  while  requests  are  being posted), and
-        // returns this failing WR through bad_wr.
-        //
         // The user should not alter or destroy AHs associated with WRs until request is fully
         // executed and  a  work  completion  has been retrieved from the corresponding completion
         // queue (CQ) to avoid unexpected behavior.
@@ -1891,83 +5587,141 @@ impl QueuePair {
         // ... However, if the IBV_SEND_INLINE flag was set, the  buffer  can  be reused
         // immediately after the call returns.
 
-        let ctx = unsafe { *self.qp }.context;
-        let ops = &mut unsafe { *ctx }.ops;
-        let errno = unsafe {
-            ops.post_send.as_mut().unwrap()(self.qp, &mut wr as *mut _, &mut bad_wr as *mut _)
-        };
-        if errno != 0 {
-            Err(io::Error::from_raw_os_error(errno))
-        } else {
-            Ok(())
+        unsafe { self.post_send_wrs(slice::from_mut(&mut wr)) }.map_err(|(_, e)| e)
+    }
+
+    /// Sends `data` inline, i.e. copied into the work request, without a `MemoryRegion`.
+    ///
+    /// This is meant for small messages, like control messages. Since the data is copied while
+    /// posting, `data` can be reused as soon as this returns. Apart from that, this behaves like
+    /// `post_send`, and the send generates a work completion with `wr_id`.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::InlineTooLarge`: `data` is larger than `QueuePair::max_inline_data`.
+    ///  - All errors of `post_send`.
+    pub fn post_send_inline(
+        &mut self,
+        data: &[u8],
+        wr_id: u64,
+        imm_data: Option<u32>,
+    ) -> Result<(), Error> {
+        let local = self.inline_slice(data)?;
+        let send_flags =
+            ffi::ibv_send_flags::IBV_SEND_SIGNALED | ffi::ibv_send_flags::IBV_SEND_INLINE;
+        // SAFETY: the device does not access `data` after posting, since it is sent inline.
+        unsafe { self.post_send_with_flags(slice::from_ref(&local), wr_id, imm_data, send_flags) }
+            .map(|_| ())
+    }
+
+    /// Describes `data` for an inline send, which needs no `lkey`.
+    fn inline_slice(&self, data: &[u8]) -> Result<LocalMemorySlice, Error> {
+        if data.len() > self.max_inline_data as usize {
+            return Err(Error::InlineTooLarge {
+                len: data.len(),
+                max_inline_data: self.max_inline_data,
+            });
         }
+        Ok(LocalMemorySlice {
+            _sge: ffi::ibv_sge {
+                addr: data.as_ptr() as u64,
+                length: data.len() as u32,
+                lkey: 0,
+            },
+        })
     }
 
-    /// Posts a linked list of Work Requests (WRs) to the Receive Queue of this Queue Pair.
-    ///
-    /// Generates a HW-specific Receive Request out of it and add it to the tail of the Queue
-    /// Pair's Receive Queue without performing any context switch. The RDMA device will take one
-    /// of those Work Requests as soon as an incoming opcode to that QP will consume a Receive
-    /// Request (RR). If there is a failure in one of the WRs because the Receive Queue is full or
-    /// one of the attributes in the WR is bad, it stops immediately and return the pointer to that
-    /// WR.
-    ///
-    /// `wr_id` is a 64 bits value associated with this WR. When a Work Completion is generated
-    /// when this Work Request ends, it will contain this value.
+    /// Sends a datagram from a UD `QueuePair` to the remote `QueuePair` `remote_qpn`.
     ///
-    /// Internally, the memory at `mr[range]` will be received into as a single `ibv_recv_wr`.
+    /// `ah` is the address of the node the remote `QueuePair` is on, and `remote_qkey` is its Q_Key
+    /// (see `QueuePairBuilder::set_qkey`). Apart from that, this behaves like `post_send`. The
+    /// message must fit into a single packet, i.e. it must not be larger than the path MTU.
     ///
-    /// See also [DDMAmojo's `ibv_post_recv` documentation][1].
+    /// The receiver gets the message at an offset of `Grh::LEN` bytes into its receive buffer.
     ///
     /// # Safety
     ///
-    /// The memory region can only be safely reused or dropped after the request is fully executed
-    /// and a work completion has been retrieved from the corresponding completion queue (i.e.,
-    /// until `CompletionQueue::poll` returns a completion for this receive).
+    /// The memory region and `ah` can only be safely reused or dropped after the request is fully
+    /// executed and a work completion has been retrieved from the corresponding completion queue.
     ///
     /// # Errors
     ///
-    ///  - `EINVAL`: Invalid value provided in the Work Request.
-    ///  - `ENOMEM`: Receive Queue is full or not enough resources to complete this operation.
+    ///  - `EINVAL`: Invalid value provided in the Work Request, or this is not a UD `QueuePair`.
+    ///  - `ENOMEM`: Send Queue is full or not enough resources to complete this operation.
     ///  - `EFAULT`: Invalid value provided in `QueuePair`.
+    ///  - `Error::CompletionQueueFull`: The send CQ has credit accounting enabled, and has no room
+    ///    for the completion of this send.
+    #[inline]
+    pub unsafe fn post_send_ud(
+        &mut self,
+        ah: &AddressHandle,
+        remote_qpn: u32,
+        remote_qkey: u32,
+        local: &[LocalMemorySlice],
+        wr_id: u64,
+        imm_data: Option<u32>,
+    ) -> Result<(), Error> {
+        let send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED;
+        unsafe {
+            self.post_send_ud_with_flags(
+                ah,
+                remote_qpn,
+                remote_qkey,
+                local,
+                wr_id,
+                imm_data,
+                send_flags,
+            )
+        }
+        .map(|_| ())
+    }
+
+    /// Like `post_send_ud`, but with the given `send_flags`, as for `post_send_with_flags`.
     ///
-    /// [1]: http://www.rdmamojo.com/2013/02/02/ibv_post_recv/
+    /// # Safety
+    ///
+    /// See `post_send_ud` and `post_send_with_flags`. `ah` must be kept alive as long as the
+    /// memory region, even for inline sends.
+    ///
+    /// # Errors
+    ///
+    /// See `post_send_ud` and `post_send_with_flags`.
     #[inline]
-    pub unsafe fn post_receive(
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn post_send_ud_with_flags(
         &mut self,
+        ah: &AddressHandle,
+        remote_qpn: u32,
+        remote_qkey: u32,
         local: &[LocalMemorySlice],
         wr_id: u64,
-    ) -> io::Result<()> {
-        let mut wr = ffi::ibv_recv_wr {
+        imm_data: Option<u32>,
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
+        let mut wr = ffi::ibv_send_wr {
             wr_id,
-            next: ptr::null::<ffi::ibv_send_wr>() as *mut _,
+            next: ptr::null_mut(),
             sg_list: local.as_ptr() as *mut ffi::ibv_sge,
             num_sge: local.len() as i32,
+            opcode: ffi::ibv_wr_opcode::IBV_WR_SEND,
+            send_flags: send_flags.0,
+            wr: ffi::ibv_send_wr__bindgen_ty_2 {
+                ud: ffi::ibv_send_wr__bindgen_ty_2__bindgen_ty_3 {
+                    ah: ah.ah,
+                    remote_qpn,
+                    remote_qkey,
+                },
+            },
+            qp_type: Default::default(),
+            __bindgen_anon_1: Default::default(),
+            __bindgen_anon_2: Default::default(),
         };
-        let mut bad_wr: *mut ffi::ibv_recv_wr = ptr::null::<ffi::ibv_recv_wr>() as *mut _;
-
-        // TODO:
-        //
-        // If the QP qp is associated with a shared receive queue, you must use the function
-        // ibv_post_srq_recv(), and not ibv_post_recv(), since the QP's own receive queue will not
-        // be used.
-        //
-        // If a WR is being posted to a UD QP, the Global Routing Header (GRH) of the incoming
-        // message will be placed in the first 40 bytes of the buffer(s) in the scatter list. If no
-        // GRH is present in the incoming message, then the first  bytes  will  be undefined. This
-        // means that in all cases, the actual data of the incoming message will start at an offset
-        // of 40 bytes into the buffer(s) in the scatter list.
-
-        let ctx = unsafe { *self.qp }.context;
-        let ops = &mut unsafe { *ctx }.ops;
-        let errno = unsafe {
-            ops.post_recv.as_mut().unwrap()(self.qp, &mut wr as *mut _, &mut bad_wr as *mut _)
-        };
-        if errno != 0 {
-            Err(io::Error::from_raw_os_error(errno))
-        } else {
-            Ok(())
+        if let Some(imm) = imm_data {
+            wr.__bindgen_anon_1.imm_data = imm;
+            wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM;
         }
+
+        unsafe { self.post_send_wrs(slice::from_mut(&mut wr)) }.map_err(|(_, e)| e)
     }
 
     #[inline]
@@ -1980,14 +5734,63 @@ impl QueuePair {
         remote: RemoteMemorySlice,
         wr_id: u64,
         imm_data: Option<u32>,
-    ) -> io::Result<()> {
+    ) -> Result<(), Error> {
+        let send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED;
+        unsafe { self.post_write_with_flags(local, remote, wr_id, imm_data, send_flags) }
+            .map(|_| ())
+    }
+
+    /// Like `post_write`, but with the given `send_flags`, as for `post_send_with_flags`.
+    ///
+    /// # Safety
+    ///
+    /// See `post_send_with_flags`.
+    ///
+    /// # Errors
+    ///
+    /// See `post_send_with_flags`.
+    #[inline]
+    pub unsafe fn post_write_with_flags(
+        &mut self,
+        local: &[LocalMemorySlice],
+        remote: RemoteMemorySlice,
+        wr_id: u64,
+        imm_data: Option<u32>,
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
         let opcode = if imm_data.is_some() {
             ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM
         } else {
             ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE
         };
 
-        self._post_one_sided(local, remote, wr_id, opcode, imm_data)
+        self._post_one_sided(local, remote, wr_id, opcode, imm_data, send_flags)
+    }
+
+    /// Writes `data` inline to `remote`, without a local `MemoryRegion`.
+    ///
+    /// As for `post_send_inline`, `data` is copied while posting, and can be reused as soon as
+    /// this returns. Apart from that, this behaves like `post_write`.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::InlineTooLarge`: `data` is larger than `QueuePair::max_inline_data`.
+    ///  - All errors of `post_send`.
+    pub fn post_write_inline(
+        &mut self,
+        data: &[u8],
+        remote: RemoteMemorySlice,
+        wr_id: u64,
+        imm_data: Option<u32>,
+    ) -> Result<(), Error> {
+        let local = self.inline_slice(data)?;
+        let send_flags =
+            ffi::ibv_send_flags::IBV_SEND_SIGNALED | ffi::ibv_send_flags::IBV_SEND_INLINE;
+        // SAFETY: the device does not access `data` after posting, since it is sent inline.
+        unsafe {
+            self.post_write_with_flags(slice::from_ref(&local), remote, wr_id, imm_data, send_flags)
+        }
+        .map(|_| ())
     }
 
     #[inline]
@@ -1998,9 +5801,33 @@ impl QueuePair {
         local: &[LocalMemorySlice],
         remote: RemoteMemorySlice,
         wr_id: u64,
-    ) -> io::Result<()> {
+    ) -> Result<(), Error> {
+        let send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED;
+        unsafe { self.post_read_with_flags(local, remote, wr_id, send_flags) }.map(|_| ())
+    }
+
+    /// Like `post_read`, but with the given `send_flags`, as for `post_send_with_flags`.
+    ///
+    /// An unsignaled read has only landed in `local` once `QueuePair::is_send_complete` returns
+    /// `true` for it. `IBV_SEND_INLINE` does not apply to reads.
+    ///
+    /// # Safety
+    ///
+    /// See `post_send_with_flags`.
+    ///
+    /// # Errors
+    ///
+    /// See `post_send_with_flags`.
+    #[inline]
+    pub unsafe fn post_read_with_flags(
+        &self,
+        local: &[LocalMemorySlice],
+        remote: RemoteMemorySlice,
+        wr_id: u64,
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
         let opcode = ffi::ibv_wr_opcode::IBV_WR_RDMA_READ;
-        self._post_one_sided(local, remote, wr_id, opcode, None)
+        self._post_one_sided(local, remote, wr_id, opcode, None, send_flags)
     }
 
     // internal function to do one sided communication
@@ -2011,7 +5838,8 @@ impl QueuePair {
         wr_id: u64,
         opcode: ffi::ibv_wr_opcode,
         imm_data: Option<u32>,
-    ) -> io::Result<()> {
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
         let anon_1 = if let Some(imm_data) = imm_data {
             ffi::ibv_send_wr__bindgen_ty_1 {
                 imm_data: imm_data.to_be(),
@@ -2026,7 +5854,7 @@ impl QueuePair {
             sg_list: local.as_ptr() as *mut ffi::ibv_sge,
             num_sge: local.len() as i32,
             opcode,
-            send_flags: ffi::ibv_send_flags::IBV_SEND_SIGNALED.0,
+            send_flags: send_flags.0,
             wr: ffi::ibv_send_wr__bindgen_ty_2 {
                 rdma: ffi::ibv_send_wr__bindgen_ty_2__bindgen_ty_1 {
                     remote_addr: remote.addr,
@@ -2037,30 +5865,412 @@ impl QueuePair {
             __bindgen_anon_1: anon_1,
             __bindgen_anon_2: Default::default(),
         };
-        let mut bad_wr: *mut ffi::ibv_send_wr = ptr::null::<ffi::ibv_send_wr>() as *mut _;
 
+        unsafe { self.post_send_wrs(slice::from_mut(&mut wr)) }.map_err(|(_, e)| e)
+    }
+
+    /// Posts `wrs` to the Send Queue as a single linked list, which `wrs` must already form.
+    ///
+    /// Reserves CQ credits for the requests that can generate a completion, and assigns sequence
+    /// numbers to all of them. Returns the sequence number of the first request, or the number of
+    /// requests that were posted along with the error.
+    unsafe fn post_send_wrs(&self, wrs: &mut [ffi::ibv_send_wr]) -> Result<u64, (usize, Error)> {
+        if !self.sends.tracking && !wrs.iter().all(|wr| self.is_signaled(wr)) {
+            // nothing would tell when the memory of the unsignaled requests can be reused
+            let error = Error::verb("ibv_post_send", nix::libc::EINVAL).with_qp(self.qp_num());
+            return Err((0, error));
+        }
+        let reserved = wrs.iter().filter(|wr| self.reserves_entry(wr)).count();
+        self.cq
+            .0
+            .acquire_credits(&self.debits.0, reserved)
+            .map_err(|e| (0, e))?;
+        unsafe { self.post_reserved_send_wrs(wrs) }
+    }
+
+    /// Posts `wrs` as `post_send_wrs` does, once their CQ credits were reserved.
+    unsafe fn post_reserved_send_wrs(
+        &self,
+        wrs: &mut [ffi::ibv_send_wr],
+    ) -> Result<u64, (usize, Error)> {
+        // the lock is held while posting, so that sequence numbers follow the order of the queue
+        let mut pending = self.sends.tracking.then(|| self.sends.pending());
+        let mut bad_wr: *mut ffi::ibv_send_wr = ptr::null_mut();
         let ctx = unsafe { *self.qp }.context;
         let ops = &mut unsafe { *ctx }.ops;
         let errno = unsafe {
-            ops.post_send.as_mut().unwrap()(self.qp, &mut wr as *mut _, &mut bad_wr as *mut _)
+            ops.post_send.as_mut().unwrap()(self.qp, wrs.as_mut_ptr(), &mut bad_wr as *mut _)
         };
+        let posted = match errno {
+            0 => wrs.len(),
+            _ if bad_wr.is_null() => 0,
+            _ => batch::index_of(wrs, bad_wr),
+        };
+        let first = self
+            .sends
+            .next_seq
+            .fetch_add(posted as u64, Ordering::Relaxed);
+        if let Some(pending) = &mut pending {
+            pending.extend(
+                wrs[..posted]
+                    .iter()
+                    .map(|wr| (wr.wr_id, self.is_signaled(wr))),
+            );
+        }
+        drop(pending);
+
         if errno != 0 {
-            Err(io::Error::from_raw_os_error(errno))
-        } else {
-            Ok(())
+            let unposted = wrs[posted..]
+                .iter()
+                .filter(|wr| self.reserves_entry(wr))
+                .count();
+            self.cq.0.release_credits(&self.debits.0, unposted);
+            let error = Error::verb("ibv_post_send", errno).with_qp(self.qp_num());
+            return Err((posted, error));
+        }
+        Ok(first)
+    }
+
+    /// Returns whether `wr` generates a work completion if it succeeds.
+    fn is_signaled(&self, wr: &ffi::ibv_send_wr) -> bool {
+        self.sq_sig_all || wr.send_flags & ffi::ibv_send_flags::IBV_SEND_SIGNALED.0 != 0
+    }
+
+    /// Returns whether `wr` reserves an entry in the send CQ, if it has credit accounting.
+    ///
+    /// Tracked unsignaled requests do, since they still get a completion if they fail or are
+    /// flushed.
+    fn reserves_entry(&self, wr: &ffi::ibv_send_wr) -> bool {
+        self.sends.tracking || self.is_signaled(wr)
+    }
+}
+
+impl QueuePair {
+    /// Atomically compares the 8 bytes at `remote` with `compare`, and replaces them with `swap` if
+    /// they are equal.
+    ///
+    /// The original value at `remote` is written to `local` either way, so the swap succeeded if
+    /// that value equals `compare`. Some devices, e.g. those of the ConnectX family, write it in
+    /// big-endian byte order. The remote `QueuePair` must have granted
+    /// `IBV_ACCESS_REMOTE_ATOMIC`, and the connection needs `QueuePairBuilder::set_max_rd_atomic`
+    /// and `QueuePairBuilder::set_max_dest_rd_atomic` of at least 1.
+    ///
+    /// # Safety
+    ///
+    /// The memory region of `local` can only be safely reused or dropped after the request is
+    /// fully executed and a work completion has been retrieved from the corresponding completion
+    /// queue.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `local` or `remote` is not exactly 8 bytes long, or not 8-byte aligned.
+    ///  - `ENOMEM`: Send Queue is full or not enough resources to complete this operation.
+    ///  - `Error::CompletionQueueFull`: The send CQ has credit accounting enabled, and has no room
+    ///    for the completion of this request.
+    pub unsafe fn post_compare_and_swap(
+        &self,
+        local: &LocalMemorySlice,
+        remote: RemoteMemorySlice,
+        compare: u64,
+        swap: u64,
+        wr_id: u64,
+    ) -> Result<(), Error> {
+        let send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED;
+        unsafe {
+            self.post_compare_and_swap_with_flags(local, remote, compare, swap, wr_id, send_flags)
+        }
+        .map(|_| ())
+    }
+
+    /// Like `post_compare_and_swap`, but with the given `send_flags`, as for
+    /// `post_send_with_flags`.
+    ///
+    /// An unsignaled atomic has only written the original value to `local` once
+    /// `QueuePair::is_send_complete` returns `true` for it. `IBV_SEND_INLINE` does not apply to
+    /// atomics.
+    ///
+    /// # Safety
+    ///
+    /// See `post_send_with_flags`.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `local` or `remote` is not exactly 8 bytes long, or not 8-byte aligned.
+    ///  - All errors of `post_send_with_flags`.
+    pub unsafe fn post_compare_and_swap_with_flags(
+        &self,
+        local: &LocalMemorySlice,
+        remote: RemoteMemorySlice,
+        compare: u64,
+        swap: u64,
+        wr_id: u64,
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
+        let opcode = ffi::ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP;
+        unsafe { self.post_atomic(local, remote, wr_id, opcode, compare, swap, send_flags) }
+    }
+
+    /// Atomically adds `add` to the 8 bytes at `remote`, wrapping around on overflow.
+    ///
+    /// The original value at `remote` is written to `local`, with the same caveats as for
+    /// `post_compare_and_swap`.
+    ///
+    /// # Safety
+    ///
+    /// The memory region of `local` can only be safely reused or dropped after the request is
+    /// fully executed and a work completion has been retrieved from the corresponding completion
+    /// queue.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `local` or `remote` is not exactly 8 bytes long, or not 8-byte aligned.
+    ///  - `ENOMEM`: Send Queue is full or not enough resources to complete this operation.
+    ///  - `Error::CompletionQueueFull`: The send CQ has credit accounting enabled, and has no room
+    ///    for the completion of this request.
+    pub unsafe fn post_fetch_and_add(
+        &self,
+        local: &LocalMemorySlice,
+        remote: RemoteMemorySlice,
+        add: u64,
+        wr_id: u64,
+    ) -> Result<(), Error> {
+        let send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED;
+        unsafe { self.post_fetch_and_add_with_flags(local, remote, add, wr_id, send_flags) }
+            .map(|_| ())
+    }
+
+    /// Like `post_fetch_and_add`, but with the given `send_flags`, as for `post_send_with_flags`.
+    ///
+    /// See `post_compare_and_swap_with_flags` for unsignaled atomics.
+    ///
+    /// # Safety
+    ///
+    /// See `post_send_with_flags`.
+    ///
+    /// # Errors
+    ///
+    ///  - `EINVAL`: `local` or `remote` is not exactly 8 bytes long, or not 8-byte aligned.
+    ///  - All errors of `post_send_with_flags`.
+    pub unsafe fn post_fetch_and_add_with_flags(
+        &self,
+        local: &LocalMemorySlice,
+        remote: RemoteMemorySlice,
+        add: u64,
+        wr_id: u64,
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
+        let opcode = ffi::ibv_wr_opcode::IBV_WR_ATOMIC_FETCH_AND_ADD;
+        unsafe { self.post_atomic(local, remote, wr_id, opcode, add, 0, send_flags) }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn post_atomic(
+        &self,
+        local: &LocalMemorySlice,
+        remote: RemoteMemorySlice,
+        wr_id: u64,
+        opcode: ffi::ibv_wr_opcode,
+        compare_add: u64,
+        swap: u64,
+        send_flags: ibv_send_flags,
+    ) -> Result<u64, Error> {
+        // atomics always operate on a single, naturally aligned 64 bit word
+        let is_word = |addr: u64, len: usize| len == 8 && addr & 7 == 0;
+        if !is_word(local.addr(), local.len()) || !is_word(remote.addr(), remote.len()) {
+            return Err(Error::verb("ibv_post_send", nix::libc::EINVAL).with_qp(self.qp_num()));
+        }
+
+        let mut wr = ffi::ibv_send_wr {
+            wr_id,
+            next: ptr::null_mut(),
+            sg_list: local as *const LocalMemorySlice as *mut ffi::ibv_sge,
+            num_sge: 1,
+            opcode,
+            send_flags: send_flags.0,
+            wr: ffi::ibv_send_wr__bindgen_ty_2 {
+                atomic: ffi::ibv_send_wr__bindgen_ty_2__bindgen_ty_2 {
+                    remote_addr: remote.addr,
+                    compare_add,
+                    swap,
+                    rkey: remote.rkey,
+                },
+            },
+            qp_type: Default::default(),
+            __bindgen_anon_1: Default::default(),
+            __bindgen_anon_2: Default::default(),
+        };
+        unsafe { self.post_send_wrs(slice::from_mut(&mut wr)) }.map_err(|(_, e)| e)
+    }
+
+    /// Posts all requests of `batch` to the Send Queue with a single `ibv_post_send` call.
+    ///
+    /// This saves notifying the device once per request. The requests are executed in the order
+    /// they were added to the batch. `batch` is left unchanged, and can be cleared and reused once
+    /// the call returns.
+    ///
+    /// Returns the sequence number of the first request, as for `post_send_with_flags`; the
+    /// others follow consecutively.
+    ///
+    /// # Safety
+    ///
+    /// The memory of each request can only be safely reused or dropped after its work completion
+    /// has been retrieved from the corresponding completion queue, as for `post_send`, or once
+    /// it is known to be complete if it is unsignaled, as for `post_send_with_flags`.
+    ///
+    /// # Errors
+    ///
+    /// The `BatchError` reports the index of the first request that was rejected, along with:
+    ///
+    ///  - `EINVAL`: Invalid value provided in the Work Request, or the batch has unsignaled
+    ///    requests, but the `QueuePair` was not built with `QueuePairBuilder::set_send_tracking`.
+    ///  - `ENOMEM`: Send Queue is full or not enough resources to complete this operation.
+    ///  - `EFAULT`: Invalid value provided in `QueuePair`.
+    ///  - `Error::CompletionQueueFull`: The send CQ has credit accounting enabled, and has no room
+    ///    for the completions of the whole batch. No request was posted.
+    pub unsafe fn post_send_batch(&self, batch: &mut SendBatch<'_>) -> Result<u64, BatchError> {
+        if batch.is_empty() {
+            return Ok(self.sends.next_seq.load(Ordering::Relaxed));
         }
+        unsafe { self.post_send_wrs(batch.link()) }
+            .map_err(|(index, error)| BatchError { index, error })
     }
 }
 
-impl Drop for QueuePair {
+impl<S> Drop for QueuePair<S> {
     fn drop(&mut self) {
-        // TODO: ibv_destroy_qp() fails if the QP is attached to a multicast group.
-        let errno = unsafe { ffi::ibv_destroy_qp(self.qp) };
-        if errno != 0 {
-            let e = io::Error::from_raw_os_error(errno);
-            panic!("destroy_qp failed: {e}");
+        if let Err(e) = self.destroy() {
+            log::error!("{e}; leaking the queue pair");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn credits_are_reserved_until_released() {
+        let credits = Credits::new(4);
+        assert!(credits.acquire(3));
+        assert!(!credits.acquire(2));
+        assert_eq!(credits.available(), 1);
+        assert!(credits.acquire(1));
+        assert!(!credits.acquire(1));
+
+        assert_eq!(credits.release(2), 2);
+        assert_eq!(credits.available(), 2);
+        assert!(credits.acquire(0));
+        assert!(!credits.acquire(3));
+    }
+
+    #[test]
+    fn unreserved_completions_release_no_credits() {
+        let credits = Credits::new(4);
+        // e.g. receives posted to a `SharedReceiveQueue`
+        assert_eq!(credits.release(3), 0);
+        assert_eq!(credits.available(), 4);
+
+        assert!(credits.acquire(2));
+        assert_eq!(credits.release(5), 2);
+        assert_eq!(credits.available(), 4);
+        assert_eq!(credits.release(1), 0);
+        assert_eq!(credits.available(), 4);
+        assert!(!credits.acquire(5));
+    }
+
+    #[test]
+    fn successful_completions_retire_silent_unsignaled_sends() {
+        let sends = SendTracker::new(1, true);
+        sends.next_seq.store(5, Ordering::Relaxed);
+        // the unsignaled requests reuse the `wr_id` of the signaled ones
+        sends
+            .pending()
+            .extend([(7, false), (7, true), (9, false), (9, false), (9, true)]);
+
+        assert_eq!(sends.retire(7, true), 2);
+        assert_eq!(sends.completed.load(Ordering::Relaxed), 2);
+        // not the oldest signaled request
+        assert_eq!(sends.retire(3, true), 0);
+        assert_eq!(sends.retire(9, true), 3);
+        assert_eq!(sends.completed.load(Ordering::Relaxed), 5);
+        assert_eq!(sends.retire(9, true), 0);
+    }
+
+    #[test]
+    fn failed_completions_retire_one_send_each() {
+        let sends = SendTracker::new(1, true);
+        sends.next_seq.store(5, Ordering::Relaxed);
+        sends
+            .pending()
+            .extend([(7, false), (8, false), (7, true), (7, false), (9, true)]);
+
+        // the request with `wr_id` 8 failed, the one before it completed silently
+        assert_eq!(sends.retire(8, false), 2);
+        // the rest is flushed, one completion each, despite the same `wr_id`s
+        assert_eq!(sends.retire(7, false), 1);
+        assert_eq!(sends.retire(7, false), 1);
+        assert_eq!(sends.completed.load(Ordering::Relaxed), 4);
+        // a completion for a request that is not pending retires nothing
+        assert_eq!(sends.retire(3, false), 0);
+        assert_eq!(sends.retire(9, false), 1);
+        assert_eq!(sends.completed.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn credits_follow_resize() {
+        let credits = Credits::new(4);
+        assert!(credits.acquire(3));
+        credits.resize(-2);
+        assert_eq!(credits.available(), 0);
+        assert!(!credits.acquire(1));
+        assert_eq!(credits.release(2), 2);
+        assert_eq!(credits.available(), 1);
+        credits.resize(4);
+        assert_eq!(credits.available(), 5);
+    }
+
+    /// A `Context` whose device was already closed, so that tearing it down calls no verbs.
+    fn closed_context() -> Context {
+        Context {
+            inner: Arc::new(ContextInner {
+                ctx: ptr::null_mut(),
+                live: ResourceCounters::default(),
+                report_leaks: true,
+            }),
         }
     }
+
+    #[test]
+    fn closing_a_context_in_use_hands_it_back() {
+        let ctx = closed_context();
+        // e.g. held by `AsyncEvents`
+        let events = ctx.inner.clone();
+        assert!(ctx.live_resources().is_empty());
+
+        let e = ctx.close().unwrap_err();
+        assert!(matches!(e.error(), Error::InUse));
+        let ctx = e.into_inner();
+        drop(events);
+        assert!(ctx.close().is_ok());
+    }
+
+    #[test]
+    fn closing_a_pd_in_use_hands_it_back() {
+        let ctx = closed_context();
+        let pd = ProtectionDomain {
+            inner: Arc::new(ProtectionDomainInner {
+                ctx: ctx.inner.clone(),
+                pd: ptr::null_mut(),
+            }),
+        };
+        let other = pd.clone();
+        let e = pd.close().unwrap_err();
+        assert!(matches!(e.error(), Error::InUse));
+        let pd = e.into_inner();
+        drop(other);
+        assert!(pd.close().is_ok());
+        assert!(ctx.close().is_ok());
+    }
 }
 
 #[cfg(all(test, feature = "serde"))]
@@ -2072,6 +6282,7 @@ mod test_serde {
             num: 72,
             lid: 9,
             gid: Some(Default::default()),
+            psn: 0x12_3456,
         };
 
         let mut qpe = qpe_default;
@@ -0,0 +1,55 @@
+//! The states of a `QueuePair` that are tracked in its type.
+//!
+//! A `QueuePair` moves through `IBV_QPS_INIT` and `IBV_QPS_RTR` before it reaches `IBV_QPS_RTS`,
+//! and only supports some operations in each of them. `PreparedQueuePair::to_init`,
+//! `QueuePair::to_rtr` and `QueuePair::to_rts` take these steps one at a time, and each returns a
+//! `QueuePair` typed with the state it reached. `PreparedQueuePair::handshake` takes all steps at
+//! once. If a step fails, the `QueuePair` is returned with the error, still in its former state.
+//!
+//! `QueuePair::drain` and `QueuePair::reset` leave the connected states, and
+//! `QueuePair::reconnect` returns from `Reset` to `Rts`.
+
+/// The `QueuePair` was reset (`IBV_QPS_RESET`) by `QueuePair::reset`, and holds no work requests.
+///
+/// A newly built `QueuePair` is in this state too, but is handed out as a `PreparedQueuePair`.
+pub enum Reset {}
+
+/// The `QueuePair` is initialized (`IBV_QPS_INIT`), and associated with its port.
+///
+/// Receive requests can be posted, but are not processed until the `QueuePair` is ready to
+/// receive.
+pub enum Init {}
+
+/// The `QueuePair` is ready to receive (`IBV_QPS_RTR`), and connected to its remote `QueuePair`.
+///
+/// Posting receive requests in this state, before the remote side starts sending, avoids RNR
+/// retries at connection time.
+pub enum Rtr {}
+
+/// The `QueuePair` is ready to send (`IBV_QPS_RTS`), and supports all operations.
+///
+/// This is the default state of `QueuePair`.
+pub enum Rts {}
+
+/// The `QueuePair` was moved to the error state (`IBV_QPS_ERR`) by `QueuePair::drain`, which
+/// flushes its outstanding work requests.
+///
+/// Only `QueuePair::reset` leads back out of it.
+pub enum Drained {}
+
+/// The states in which receive requests can be posted: `Init`, `Rtr` and `Rts`.
+///
+/// This trait is sealed, and cannot be implemented outside of this crate.
+pub trait Receiving: sealed::Sealed {}
+
+impl Receiving for Init {}
+impl Receiving for Rtr {}
+impl Receiving for Rts {}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::Init {}
+    impl Sealed for super::Rtr {}
+    impl Sealed for super::Rts {}
+}
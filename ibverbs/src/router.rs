@@ -0,0 +1,532 @@
+//! Routing of work completions back to the requests that caused them.
+
+use crate::{CompletionQueue, Error};
+use ffi::{ibv_wc, ibv_wc_status};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use std::{error, fmt, io};
+
+/// The number of work completions that are polled from the CQ at once.
+const BATCH_SIZE: usize = 16;
+
+/// The id of the next `CompletionRouter`, which tells the tokens of different routers apart.
+static NEXT_ROUTER_ID: AtomicU64 = AtomicU64::new(0);
+
+type Callback<T> = Box<dyn FnOnce(RoutedCompletion<T>) + Send>;
+
+enum Slot<T> {
+    Pending(T),
+    Callback(T, Callback<T>),
+    Completed(RoutedCompletion<T>),
+}
+
+struct State<T> {
+    next_wr_id: u64,
+    slots: HashMap<u64, Slot<T>>,
+    /// whether a thread is currently polling the CQ, either blocked in `wait` or in `progress`
+    polling: bool,
+}
+
+impl<T> State<T> {
+    fn new() -> Self {
+        State {
+            next_wr_id: 0,
+            slots: HashMap::new(),
+            polling: false,
+        }
+    }
+
+    /// Adds a pending request, and returns its `wr_id`.
+    fn register(&mut self, state: T) -> u64 {
+        let wr_id = self.next_wr_id;
+        self.next_wr_id = self.next_wr_id.wrapping_add(1);
+        self.slots.insert(wr_id, Slot::Pending(state));
+        wr_id
+    }
+
+    /// Removes the request, and returns its state.
+    fn cancel(&mut self, wr_id: u64) -> T {
+        match self.slots.remove(&wr_id) {
+            Some(Slot::Pending(state)) => state,
+            Some(Slot::Completed(completion)) => completion.state,
+            Some(Slot::Callback(..)) | None => {
+                unreachable!("request tokens are only handed out for pending requests")
+            }
+        }
+    }
+
+    /// Removes the request and returns its completion, if it completed.
+    fn take(&mut self, wr_id: u64) -> Option<RoutedCompletion<T>> {
+        match self.slots.remove(&wr_id) {
+            Some(Slot::Completed(completion)) => Some(completion),
+            Some(slot) => {
+                self.slots.insert(wr_id, slot);
+                None
+            }
+            None => unreachable!("request tokens are only handed out for pending requests"),
+        }
+    }
+
+    /// Resolves the requests of `completions`, and returns the callbacks to run.
+    fn route(&mut self, completions: &[ibv_wc]) -> Vec<(Callback<T>, RoutedCompletion<T>)> {
+        let mut callbacks = Vec::new();
+        for wc in completions {
+            let wr_id = wc.wr_id();
+            match self.slots.remove(&wr_id) {
+                Some(Slot::Pending(state)) => {
+                    let completion = RoutedCompletion { wc: *wc, state };
+                    self.slots.insert(wr_id, Slot::Completed(completion));
+                }
+                Some(Slot::Callback(state, callback)) => {
+                    callbacks.push((callback, RoutedCompletion { wc: *wc, state }));
+                }
+                Some(slot @ Slot::Completed(_)) => {
+                    self.slots.insert(wr_id, slot);
+                    log::warn!("dropping duplicate work completion for wr_id {wr_id}");
+                }
+                None => {
+                    log::warn!("dropping work completion for unknown wr_id {wr_id}");
+                }
+            }
+        }
+        callbacks
+    }
+}
+
+/// Routes the work completions of a `CompletionQueue` back to the requests that caused them.
+///
+/// Before posting a work request, register it with `CompletionRouter::register`, attaching any
+/// state that has to be kept alive until the request completes, such as its `MemoryRegion`. Post
+/// the request with the `wr_id` of the returned `RequestToken`. Once the work completion for that
+/// `wr_id` is polled from the CQ, the token resolves to a `RoutedCompletion` that carries the
+/// work completion and the attached state, whether the request succeeded or not.
+///
+/// The CQ is polled by `CompletionRouter::progress`, `CompletionRouter::wait`, and by nothing
+/// else. All work requests whose completions go to the CQ must be registered with the router,
+/// since completions with unknown `wr_id`s are dropped.
+///
+/// Dropping the router drops the state of all requests that have not completed yet, even though
+/// the device may still access their memory.
+pub struct CompletionRouter<T> {
+    id: u64,
+    cq: CompletionQueue,
+    state: Mutex<State<T>>,
+    routed: Condvar,
+}
+
+/// A handle to a work request registered with a `CompletionRouter`.
+///
+/// Dropping a token that was neither resolved nor given to `CompletionRouter::on_complete` keeps
+/// the state of its request in the router until the router is dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct RequestToken<T> {
+    /// the id of the router that issued the token
+    router: u64,
+    wr_id: u64,
+    _state: PhantomData<fn() -> T>,
+}
+
+impl<T> RequestToken<T> {
+    /// Returns the `wr_id` that the work request must be posted with.
+    pub fn wr_id(&self) -> u64 {
+        self.wr_id
+    }
+}
+
+/// The work completion of a request registered with a `CompletionRouter`.
+#[derive(Debug)]
+pub struct RoutedCompletion<T> {
+    /// The work completion polled from the CQ.
+    pub wc: ibv_wc,
+    /// The state that was attached to the request by `CompletionRouter::register`.
+    pub state: T,
+}
+
+impl<T> RoutedCompletion<T> {
+    /// Returns the status of the work completion.
+    pub fn status(&self) -> ibv_wc_status {
+        self.wc
+            .error()
+            .map_or(ibv_wc_status::IBV_WC_SUCCESS, |(status, _)| status)
+    }
+
+    /// Returns `true` if the work request completed successfully.
+    pub fn is_success(&self) -> bool {
+        self.wc.is_valid()
+    }
+}
+
+/// The error returned by the methods of `CompletionRouter` that take a `RequestToken`.
+///
+/// It hands the token back, since the request is still registered with the router that issued
+/// it.
+pub enum TokenError<T> {
+    /// The request has not completed yet. Only returned by `CompletionRouter::try_take`.
+    Pending(RequestToken<T>),
+    /// The call failed.
+    Failed {
+        /// The token that was passed in.
+        token: RequestToken<T>,
+        /// The reason the call failed, e.g. `Error::TimedOut`, or `Error::ForeignRequestToken`
+        /// if the token was issued by another router.
+        error: Error,
+    },
+}
+
+impl<T> TokenError<T> {
+    /// Returns the token that was passed in.
+    pub fn into_token(self) -> RequestToken<T> {
+        match self {
+            TokenError::Pending(token) | TokenError::Failed { token, .. } => token,
+        }
+    }
+
+    /// Returns the reason the call failed, or `None` if the request is still pending.
+    pub fn error(&self) -> Option<&Error> {
+        match self {
+            TokenError::Pending(_) => None,
+            TokenError::Failed { error, .. } => Some(error),
+        }
+    }
+
+    fn token(&self) -> &RequestToken<T> {
+        match self {
+            TokenError::Pending(token) | TokenError::Failed { token, .. } => token,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TokenError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("TokenError");
+        s.field("wr_id", &self.token().wr_id);
+        match self.error() {
+            Some(error) => s.field("error", error),
+            None => s.field("pending", &true),
+        };
+        s.finish()
+    }
+}
+
+impl<T> fmt::Display for TokenError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let wr_id = self.token().wr_id;
+        match self.error() {
+            Some(error) => write!(f, "request with wr_id {wr_id}: {error}"),
+            None => write!(f, "request with wr_id {wr_id} has not completed yet"),
+        }
+    }
+}
+
+impl<T> error::Error for TokenError<T> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.error().map(|error| error as _)
+    }
+}
+
+/// Converts a pending request into an error of kind `io::ErrorKind::WouldBlock`.
+impl<T> From<TokenError<T>> for Error {
+    fn from(e: TokenError<T>) -> Self {
+        match e {
+            TokenError::Pending(_) => Error::Io(io::ErrorKind::WouldBlock.into()),
+            TokenError::Failed { error, .. } => error,
+        }
+    }
+}
+
+impl<T> CompletionRouter<T> {
+    /// Creates a router for the work completions of `cq`.
+    pub fn new(cq: CompletionQueue) -> Self {
+        CompletionRouter {
+            id: NEXT_ROUTER_ID.fetch_add(1, Ordering::Relaxed),
+            cq,
+            state: Mutex::new(State::new()),
+            routed: Condvar::new(),
+        }
+    }
+
+    /// Returns the routed `CompletionQueue`.
+    pub fn cq(&self) -> &CompletionQueue {
+        &self.cq
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        // the lock is never held while running user code, so a poisoned state is still consistent
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hands `token` back in an error if it was not issued by this router.
+    fn check(&self, token: RequestToken<T>) -> Result<RequestToken<T>, TokenError<T>> {
+        if token.router != self.id {
+            let error = Error::ForeignRequestToken;
+            return Err(TokenError::Failed { token, error });
+        }
+        Ok(token)
+    }
+
+    /// Registers a work request that is about to be posted, and attaches `state` to it.
+    ///
+    /// The request must be posted with the `wr_id` of the returned token.
+    pub fn register(&self, state: T) -> RequestToken<T> {
+        RequestToken {
+            router: self.id,
+            wr_id: self.lock().register(state),
+            _state: PhantomData,
+        }
+    }
+
+    /// Unregisters a request, and returns its state.
+    ///
+    /// This is meant for requests that could not be posted. If the request did complete, its work
+    /// completion is discarded.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::ForeignRequestToken`: The token was issued by another router.
+    pub fn cancel(&self, token: RequestToken<T>) -> Result<T, TokenError<T>> {
+        let token = self.check(token)?;
+        Ok(self.lock().cancel(token.wr_id))
+    }
+
+    /// Polls the CQ once without blocking, and routes the work completions found.
+    ///
+    /// Returns the number of work completions that were routed. Callbacks registered with
+    /// `CompletionRouter::on_complete` are run on the calling thread.
+    ///
+    /// If another thread is polling the CQ at the same time, e.g. while blocked in
+    /// `CompletionRouter::wait`, this returns 0 right away, and leaves routing to that thread.
+    /// Taking completions from under a blocked thread could leave it waiting for a completion
+    /// event that never comes.
+    ///
+    /// # Errors
+    ///
+    ///  - System errors: From the underlying `poll` call.
+    pub fn progress(&self) -> Result<usize, Error> {
+        {
+            let mut s = self.lock();
+            if s.polling {
+                return Ok(0);
+            }
+            s.polling = true;
+        }
+        let mut completions = [ibv_wc::default(); BATCH_SIZE];
+        let result = self.cq.poll(&mut completions).map(|completions| {
+            self.route(completions);
+            completions.len()
+        });
+        self.lock().polling = false;
+        // let the waiters take over polling the CQ
+        self.routed.notify_all();
+        result
+    }
+
+    /// Returns the completion of the request, if its work completion was already routed.
+    ///
+    /// This does not poll the CQ; use `CompletionRouter::progress` for that.
+    ///
+    /// # Errors
+    ///
+    /// The token is handed back in the `TokenError`:
+    ///
+    ///  - `TokenError::Pending`: The request has not completed yet.
+    ///  - `Error::ForeignRequestToken`: The token was issued by another router.
+    pub fn try_take(&self, token: RequestToken<T>) -> Result<RoutedCompletion<T>, TokenError<T>> {
+        let token = self.check(token)?;
+        match self.lock().take(token.wr_id) {
+            Some(completion) => Ok(completion),
+            None => Err(TokenError::Pending(token)),
+        }
+    }
+
+    /// Waits until the request completes, and returns its completion.
+    ///
+    /// While waiting, one of the waiting threads blocks on the CQ with `CompletionQueue::wait`,
+    /// and routes the work completions of all requests. The CQ therefore needs a completion
+    /// channel.
+    ///
+    /// # Errors
+    ///
+    /// The token is handed back in the `TokenError`, together with:
+    ///
+    ///  - `Error::TimedOut`: If the timeout expires before the request completes.
+    ///  - `Error::ForeignRequestToken`: The token was issued by another router.
+    ///  - `Error::NoCompletionChannel`: If the CQ was created without a completion channel.
+    ///  - System errors: From the underlying `CompletionQueue::wait` call.
+    pub fn wait(
+        &self,
+        token: RequestToken<T>,
+        timeout: Option<Duration>,
+    ) -> Result<RoutedCompletion<T>, TokenError<T>> {
+        let token = self.check(token)?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut s = self.lock();
+        loop {
+            if let Some(completion) = s.take(token.wr_id) {
+                return Ok(completion);
+            }
+
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining == Some(Duration::ZERO) {
+                let error = Error::TimedOut;
+                return Err(TokenError::Failed { token, error });
+            }
+
+            if s.polling {
+                // another thread is polling the CQ, and will wake us once it routed something
+                s = match remaining {
+                    Some(remaining) => {
+                        self.routed
+                            .wait_timeout(s, remaining)
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .0
+                    }
+                    None => self.routed.wait(s).unwrap_or_else(PoisonError::into_inner),
+                };
+                continue;
+            }
+
+            s.polling = true;
+            drop(s);
+            let mut completions = [ibv_wc::default(); BATCH_SIZE];
+            let result = self
+                .cq
+                .wait(&mut completions, remaining)
+                .map(|completions| self.route(completions));
+            s = self.lock();
+            s.polling = false;
+            // let one of the other waiters take over waiting on the CQ
+            self.routed.notify_all();
+            if let Err(error) = result {
+                return Err(TokenError::Failed { token, error });
+            }
+        }
+    }
+
+    /// Registers `callback` to be run with the completion of the request.
+    ///
+    /// The callback is run by the thread that routes the work completion, i.e. in
+    /// `CompletionRouter::progress` or `CompletionRouter::wait`. If the request already completed,
+    /// it is run right away.
+    ///
+    /// # Errors
+    ///
+    ///  - `Error::ForeignRequestToken`: The token was issued by another router. The callback is
+    ///    dropped without being run.
+    pub fn on_complete(
+        &self,
+        token: RequestToken<T>,
+        callback: impl FnOnce(RoutedCompletion<T>) + Send + 'static,
+    ) -> Result<(), TokenError<T>> {
+        let token = self.check(token)?;
+        let mut s = self.lock();
+        match s.slots.remove(&token.wr_id) {
+            Some(Slot::Pending(state)) => {
+                s.slots
+                    .insert(token.wr_id, Slot::Callback(state, Box::new(callback)));
+            }
+            Some(Slot::Completed(completion)) => {
+                drop(s);
+                callback(completion);
+            }
+            Some(Slot::Callback(..)) | None => {
+                unreachable!("request tokens are only handed out for pending requests")
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves the requests of `completions`, and runs their callbacks.
+    fn route(&self, completions: &[ibv_wc]) {
+        let callbacks = self.lock().route(completions);
+        if !completions.is_empty() {
+            self.routed.notify_all();
+        }
+
+        for (callback, completion) in callbacks {
+            callback(completion);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Fabricates a work completion for `wr_id`.
+    fn wc(wr_id: u64) -> ibv_wc {
+        let mut wc = ibv_wc::default();
+        // SAFETY: `ibv_wc` mirrors the C struct, which starts with the `wr_id`.
+        unsafe { (&mut wc as *mut ibv_wc).cast::<u64>().write(wr_id) };
+        wc
+    }
+
+    #[test]
+    fn completions_are_routed_by_wr_id() {
+        let mut s = State::new();
+        let a = s.register("a");
+        let b = s.register("b");
+        let c = s.register("c");
+        assert!(s.take(a).is_none());
+
+        // unknown and duplicate completions are dropped
+        assert!(s.route(&[wc(b), wc(42), wc(b)]).is_empty());
+        assert!(s.take(a).is_none());
+        let completion = s.take(b).unwrap();
+        assert_eq!(completion.state, "b");
+        assert_eq!(completion.wc.wr_id(), b);
+
+        // a completed request can still be cancelled
+        assert!(s.route(&[wc(c)]).is_empty());
+        assert_eq!(s.cancel(c), "c");
+        assert_eq!(s.cancel(a), "a");
+        assert!(s.slots.is_empty());
+    }
+
+    #[test]
+    fn pending_requests_are_not_errors() {
+        let token = |wr_id| RequestToken::<()> {
+            router: 0,
+            wr_id,
+            _state: PhantomData,
+        };
+        let pending = TokenError::Pending(token(1));
+        assert!(pending.error().is_none());
+        assert_eq!(
+            pending.to_string(),
+            "request with wr_id 1 has not completed yet"
+        );
+        assert_eq!(Error::from(pending).kind(), io::ErrorKind::WouldBlock);
+
+        let error = Error::TimedOut;
+        let timed_out = TokenError::Failed {
+            token: token(2),
+            error,
+        };
+        assert!(matches!(timed_out.error(), Some(Error::TimedOut)));
+        assert_eq!(timed_out.into_token().wr_id, 2);
+    }
+
+    #[test]
+    fn callbacks_are_returned_for_running() {
+        let mut s = State::new();
+        let a = s.register(1);
+        let state = match s.slots.remove(&a) {
+            Some(Slot::Pending(state)) => state,
+            _ => unreachable!(),
+        };
+        let callback: Callback<i32> = Box::new(|completion| assert_eq!(completion.state, 1));
+        s.slots.insert(a, Slot::Callback(state, callback));
+
+        let callbacks = s.route(&[wc(a)]);
+        assert_eq!(callbacks.len(), 1);
+        assert!(s.slots.is_empty());
+        for (callback, completion) in callbacks {
+            callback(completion);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Strategies for waiting on completions that trade latency against CPU usage.
+
+use std::time::{Duration, Instant};
+use std::{hint, thread};
+
+/// How long a phase of a `WaitStrategy` lasts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+    Iterations(u32),
+    Duration(Duration),
+}
+
+impl Limit {
+    /// Calls `step` until it returns `Some`, or the limit is reached.
+    fn run<T>(self, mut step: impl FnMut() -> Option<T>) -> Option<T> {
+        match self {
+            Limit::Iterations(n) => (0..n).find_map(|_| step()),
+            Limit::Duration(d) => {
+                let start = Instant::now();
+                loop {
+                    if let Some(t) = step() {
+                        return Some(t);
+                    }
+                    if start.elapsed() >= d {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The phase of a `WaitStrategy` that a wait ended in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WaitPhase {
+    /// The wait ended while busy-polling.
+    Spin,
+    /// The wait ended while polling between calls to `std::thread::yield_now`.
+    Yield,
+    /// The wait ended after blocking, e.g. on a `CompletionChannel`.
+    Block,
+    /// The wait ended while sleeping between polls, for a condition that no completion channel
+    /// reports. `CompletionQueue::wait_with` never ends in this phase.
+    Sleep,
+}
+
+/// Describes how to wait for completions: busy-poll first, then poll while yielding the CPU to
+/// other threads, and finally block.
+///
+/// Spinning gives the lowest latency, but occupies a CPU core. Blocking frees the core, but adds
+/// the latency of a completion event and a context switch. The `WaitPhase` reported by
+/// `CompletionQueue::wait_with` tells how long the phases should be for a given workload.
+///
+/// The default strategy neither spins nor yields, and blocks right away like
+/// `CompletionQueue::wait`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitStrategy {
+    spin: Limit,
+    yields: Limit,
+}
+
+impl Default for WaitStrategy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WaitStrategy {
+    /// Creates a strategy that blocks right away.
+    pub const fn new() -> Self {
+        WaitStrategy {
+            spin: Limit::Iterations(0),
+            yields: Limit::Iterations(0),
+        }
+    }
+
+    /// Busy-poll up to `iterations` times before yielding.
+    pub const fn spin_iterations(mut self, iterations: u32) -> Self {
+        self.spin = Limit::Iterations(iterations);
+        self
+    }
+
+    /// Busy-poll for up to `duration` before yielding.
+    pub const fn spin_for(mut self, duration: Duration) -> Self {
+        self.spin = Limit::Duration(duration);
+        self
+    }
+
+    /// Poll and yield up to `iterations` times before blocking.
+    pub const fn yield_iterations(mut self, iterations: u32) -> Self {
+        self.yields = Limit::Iterations(iterations);
+        self
+    }
+
+    /// Poll and yield for up to `duration` before blocking.
+    pub const fn yield_for(mut self, duration: Duration) -> Self {
+        self.yields = Limit::Duration(duration);
+        self
+    }
+
+    /// Runs the spin and the yield phase, calling `ready` until it returns `Some`.
+    ///
+    /// Returns what `ready` returned, and the phase it was returned in. Returns `None` if neither
+    /// phase succeeded, in which case the caller should block. This is the building block of
+    /// `CompletionQueue::wait_with`, and can be used to wait for other conditions the same way.
+    pub fn spin_then_yield<T>(
+        &self,
+        mut ready: impl FnMut() -> Option<T>,
+    ) -> Option<(T, WaitPhase)> {
+        if let Some(t) = self.spin.run(|| {
+            let t = ready();
+            if t.is_none() {
+                hint::spin_loop();
+            }
+            t
+        }) {
+            return Some((t, WaitPhase::Spin));
+        }
+        self.yields
+            .run(|| {
+                thread::yield_now();
+                ready()
+            })
+            .map(|t| (t, WaitPhase::Yield))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns a `ready` that succeeds on its `n`th call, and counts its calls.
+    fn ready_after(n: u32, calls: &mut u32) -> impl FnMut() -> Option<u32> + '_ {
+        move || {
+            *calls += 1;
+            (*calls >= n).then_some(*calls)
+        }
+    }
+
+    #[test]
+    fn iteration_limit_bounds_calls() {
+        let mut calls = 0;
+        assert_eq!(Limit::Iterations(3).run(ready_after(5, &mut calls)), None);
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        assert_eq!(
+            Limit::Iterations(3).run(ready_after(2, &mut calls)),
+            Some(2)
+        );
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn zero_limits_call_nothing_or_once() {
+        let mut calls = 0;
+        assert_eq!(Limit::Iterations(0).run(ready_after(1, &mut calls)), None);
+        assert_eq!(calls, 0);
+
+        // a duration is only checked after a call
+        let mut calls = 0;
+        assert_eq!(
+            Limit::Duration(Duration::ZERO).run(ready_after(2, &mut calls)),
+            None
+        );
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn duration_limit_runs_until_ready() {
+        let mut calls = 0;
+        let limit = Limit::Duration(Duration::from_secs(60));
+        assert_eq!(limit.run(ready_after(100, &mut calls)), Some(100));
+    }
+
+    #[test]
+    fn default_strategy_does_not_poll() {
+        let mut calls = 0;
+        let strategy = WaitStrategy::default();
+        assert_eq!(strategy.spin_then_yield(ready_after(1, &mut calls)), None);
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn phase_is_reported() {
+        let strategy = WaitStrategy::new().spin_iterations(2).yield_iterations(2);
+
+        let mut calls = 0;
+        let ready = ready_after(2, &mut calls);
+        assert_eq!(strategy.spin_then_yield(ready), Some((2, WaitPhase::Spin)));
+
+        let mut calls = 0;
+        let ready = ready_after(4, &mut calls);
+        assert_eq!(strategy.spin_then_yield(ready), Some((4, WaitPhase::Yield)));
+
+        let mut calls = 0;
+        assert_eq!(strategy.spin_then_yield(ready_after(5, &mut calls)), None);
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn yield_phase_follows_spin_duration() {
+        let strategy = WaitStrategy::new()
+            .spin_for(Duration::ZERO)
+            .yield_iterations(3);
+        let mut calls = 0;
+        let ready = ready_after(3, &mut calls);
+        assert_eq!(strategy.spin_then_yield(ready), Some((3, WaitPhase::Yield)));
+    }
+}